@@ -0,0 +1,328 @@
+//! GET responses kept in memory so repeat requests don't reach the local service
+//!
+//! Only responses the upstream marks cacheable with `max-age` or `s-maxage` are
+//! kept, and every visitor shares them, so anything private or tied to cookies
+//! or credentials goes straight through. Once full, the least recently used
+//! entry makes room.
+
+use hyper::body::Bytes;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Bodies are read whole before being cached, so bigger ones are streamed instead
+const MAX_BODY_LEN: u64 = 1024 * 1024;
+
+// Request headers that can change the response and so are part of the key, the
+// scheme the browser used can end up in absolute URLs and redirects
+const KEYED_HEADERS: [&str; 4] = ["host", "accept", "accept-encoding", "x-forwarded-proto"];
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    max_entries: usize,
+    max_ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    // Bumped on every hit, the entry with the lowest is evicted first
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored: Instant,
+    ttl: Duration,
+    used: u64,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize, max_ttl: Duration) -> Self {
+        ResponseCache {
+            max_entries,
+            max_ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// What a request is cached under, or None if it has to reach the upstream
+    pub fn key(method: &str, path: &str, headers: &[httparse::Header]) -> Option<String> {
+        if method != "GET" {
+            return None;
+        }
+        let mut key = path.to_string();
+        for header in headers {
+            let name = header.name.to_ascii_lowercase();
+            match name.as_str() {
+                "authorization" | "cookie" => return None,
+                "cache-control" | "pragma" => {
+                    let value = String::from_utf8_lossy(header.value).to_ascii_lowercase();
+                    if value.contains("no-cache") || value.contains("no-store") {
+                        return None;
+                    }
+                }
+                _ if KEYED_HEADERS.contains(&name.as_str()) => {
+                    key.push('\n');
+                    key.push_str(&name);
+                    key.push(':');
+                    key.push_str(&String::from_utf8_lossy(header.value));
+                }
+                _ => {}
+            }
+        }
+        Some(key)
+    }
+
+    /// A fresh copy of the response cached under `key`, with an Age header
+    pub fn get(&self, key: &str) -> Option<reqwest::Response> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+        let entry = entries.map.get_mut(key)?;
+        let age = entry.stored.elapsed();
+        if age >= entry.ttl {
+            entries.map.remove(key);
+            return None;
+        }
+        entry.used = clock;
+        let mut response = hyper::Response::builder().status(entry.status);
+        if let Some(headers) = response.headers_mut() {
+            headers.clone_from(&entry.headers);
+            headers.insert(header::AGE, HeaderValue::from(age.as_secs()));
+        }
+        Some(response.body(entry.body.clone()).unwrap().into())
+    }
+
+    /// Caches the response if it may be, reading its body to do so. What's
+    /// returned is the response to forward either way
+    pub async fn store(
+        &self,
+        key: String,
+        response: reqwest::Response,
+    ) -> reqwest::Result<reqwest::Response> {
+        let Some(ttl) = self.ttl(&response) else {
+            return Ok(response);
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.bytes().await?;
+        let mut forwarded = hyper::Response::builder().status(status);
+        if let Some(forwarded_headers) = forwarded.headers_mut() {
+            forwarded_headers.clone_from(&headers);
+        }
+        let forwarded = forwarded.body(body.clone()).unwrap().into();
+
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.map.contains_key(&key) && entries.map.len() >= self.max_entries {
+            entries
+                .map
+                .retain(|_, entry| entry.stored.elapsed() < entry.ttl);
+        }
+        if !entries.map.contains_key(&key) && entries.map.len() >= self.max_entries {
+            let oldest = entries
+                .map
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.map.remove(&oldest);
+            }
+        }
+        entries.clock += 1;
+        let used = entries.clock;
+        entries.map.insert(
+            key,
+            Entry {
+                status,
+                headers,
+                body,
+                stored: Instant::now(),
+                ttl,
+                used,
+            },
+        );
+        Ok(forwarded)
+    }
+
+    // How long the response may be kept, None if it can't be. Responses that
+    // set cookies or vary on headers outside the key are never kept
+    fn ttl(&self, response: &reqwest::Response) -> Option<Duration> {
+        let headers = response.headers();
+        if response.status() != StatusCode::OK
+            || response
+                .content_length()
+                .is_none_or(|len| len > MAX_BODY_LEN)
+            || headers.contains_key(header::SET_COOKIE)
+        {
+            return None;
+        }
+        let varies_outside_key = headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .any(|name| !KEYED_HEADERS.contains(&name.as_str()));
+        if varies_outside_key {
+            return None;
+        }
+        let mut max_age = None;
+        let mut shared_max_age = None;
+        let directives = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|directive| directive.trim().to_ascii_lowercase());
+        for directive in directives {
+            let (name, value) = directive.split_once('=').unwrap_or((&directive, ""));
+            let value = value.trim().trim_matches('"');
+            match name.trim() {
+                // Including the `no-cache="set-cookie"` forms
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = value.parse().ok(),
+                "s-maxage" => shared_max_age = value.parse().ok(),
+                _ => {}
+            }
+        }
+        // Every visitor shares the cache, so the shared lifetime wins
+        let ttl = Duration::from_secs(shared_max_age.or(max_age)?).min(self.max_ttl);
+        Some(ttl).filter(|ttl| !ttl.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)], body: &'static str) -> reqwest::Response {
+        let mut response = hyper::Response::builder();
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        response.body(Bytes::from(body)).unwrap().into()
+    }
+
+    fn ttl(headers: &[(&str, &str)]) -> Option<Duration> {
+        ResponseCache::new(8, Duration::from_secs(3600)).ttl(&response(headers, "hello"))
+    }
+
+    fn header<'a>(name: &'a str, value: &'a str) -> httparse::Header<'a> {
+        httparse::Header {
+            name,
+            value: value.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn keys_requests_by_path_and_keyed_headers() {
+        let key = ResponseCache::key(
+            "GET",
+            "/a?b",
+            &[header("Host", "foo.test"), header("User-Agent", "curl")],
+        );
+        assert_eq!(key.as_deref(), Some("/a?b\nhost:foo.test"));
+        assert_ne!(
+            key,
+            ResponseCache::key("GET", "/a?b", &[header("Host", "bar.test")])
+        );
+        let https = ResponseCache::key("GET", "/a", &[header("X-Forwarded-Proto", "https")]);
+        assert_eq!(https.as_deref(), Some("/a\nx-forwarded-proto:https"));
+        assert_ne!(
+            https,
+            ResponseCache::key("GET", "/a", &[header("X-Forwarded-Proto", "http")])
+        );
+        assert_eq!(ResponseCache::key("POST", "/a", &[]), None);
+    }
+
+    #[test]
+    fn bypasses_requests_with_credentials_or_no_cache() {
+        for bypassed in [
+            header("Authorization", "Bearer a"),
+            header("Cookie", "a=b"),
+            header("Cache-Control", "no-cache"),
+            header("Cache-Control", "max-age=0, no-store"),
+            header("Pragma", "no-cache"),
+        ] {
+            assert_eq!(ResponseCache::key("GET", "/", &[bypassed]), None);
+        }
+        assert!(ResponseCache::key("GET", "/", &[header("Cache-Control", "max-age=0")]).is_some());
+    }
+
+    #[test]
+    fn prefers_the_shared_max_age() {
+        assert_eq!(
+            ttl(&[("cache-control", "public, max-age=10")]),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            ttl(&[("cache-control", "max-age=10, s-maxage=20")]),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(ttl(&[]), None);
+        assert_eq!(ttl(&[("cache-control", "max-age=0")]), None);
+    }
+
+    #[test]
+    fn skips_responses_that_arent_shareable() {
+        for headers in [
+            [("cache-control", "private, max-age=10")],
+            [("cache-control", "no-store, max-age=10")],
+            [("cache-control", "no-cache=\"set-cookie\", max-age=10")],
+        ] {
+            assert_eq!(ttl(&headers), None);
+        }
+        let cacheable = ("cache-control", "max-age=10");
+        assert_eq!(ttl(&[cacheable, ("set-cookie", "a=b")]), None);
+        assert_eq!(ttl(&[cacheable, ("vary", "cookie")]), None);
+        assert_eq!(ttl(&[cacheable, ("vary", "*")]), None);
+        assert!(ttl(&[cacheable, ("vary", "Accept-Encoding")]).is_some());
+    }
+
+    #[tokio::test]
+    async fn serves_entries_until_they_expire() {
+        let cache = ResponseCache::new(8, Duration::from_millis(50));
+        let stored = cache
+            .store(
+                "/".to_string(),
+                response(&[("cache-control", "max-age=60")], "hello"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored.text().await.unwrap(), "hello");
+
+        let hit = cache.get("/").unwrap();
+        assert_eq!(hit.headers()[header::AGE], "0");
+        assert_eq!(hit.text().await.unwrap(), "hello");
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(cache.get("/").is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_the_least_recently_used_entry() {
+        let cache = ResponseCache::new(2, Duration::from_secs(60));
+        let cacheable = [("cache-control", "max-age=60")];
+        for key in ["/a", "/b"] {
+            cache
+                .store(key.to_string(), response(&cacheable, "hello"))
+                .await
+                .unwrap();
+        }
+        // `/a` was used more recently than `/b`
+        assert!(cache.get("/a").is_some());
+        cache
+            .store("/c".to_string(), response(&cacheable, "hello"))
+            .await
+            .unwrap();
+        assert!(cache.get("/a").is_some());
+        assert!(cache.get("/b").is_none());
+        assert!(cache.get("/c").is_some());
+    }
+}
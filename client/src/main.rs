@@ -1,115 +1,1932 @@
-use httparse;
-use reqwest;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpSocket;
+use cache::ResponseCache;
+use clap::Parser;
+use hyperlocal::UnixConnector;
+use log::warn;
+use protocol::{
+    dump, is_hop_by_hop, read_message_max, request_header_buffer, write_frame, write_message,
+    Message, DEFAULT_MAX_MESSAGE_LEN, INITIAL_WINDOW, MAX_BODY_CHUNK,
+};
+use reqwest::header::HeaderValue;
+use reqwest::{StatusCode, Url};
+use ring::hmac;
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use std::{fmt, io};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadHalf,
+};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::{self, ServerName};
+use tokio_rustls::TlsConnector;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+mod cache;
+
+// A request body as it streams in from the server, which only sends as much of
+// it as the client has room for. More room is granted as each chunk is taken, so
+// a slow upstream holds the body up on the server rather than it piling up here
+struct RequestBody {
+    chunks: UnboundedReceiver<io::Result<Vec<u8>>>,
+    // The request's id and where to send grants, none for a mirror's copy
+    grant: Option<(u32, UnboundedSender<Message>)>,
+}
+
+impl RequestBody {
+    async fn recv(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let chunk = self.chunks.recv().await;
+        if let Some(chunk) = &chunk {
+            taken(chunk, &self.grant);
+        }
+        chunk
+    }
+
+    fn into_stream(self) -> impl Stream<Item = io::Result<Vec<u8>>> {
+        let grant = self.grant;
+        UnboundedReceiverStream::new(self.chunks).map(move |chunk| {
+            taken(&chunk, &grant);
+            chunk
+        })
+    }
+}
+
+// Grants the server room for as much body as was just taken
+fn taken(chunk: &io::Result<Vec<u8>>, grant: &Option<(u32, UnboundedSender<Message>)>) {
+    if let (Ok(data), Some((id, writer_sender))) = (chunk, grant) {
+        let _ = writer_sender.send(Message::Window {
+            id: *id,
+            bytes: data.len() as u32,
+        });
+    }
+}
+
+// The primary stream to the server, plain TCP or TLS
+trait ServerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ServerStream for T {}
+
+type ServerReader = BufReader<ReadHalf<Box<dyn ServerStream>>>;
+
+// The server's default proxy listener port, for servers that don't say
+const DEFAULT_PROXY_PORT: u16 = 8080;
+
+// Delay before the first reconnect attempt, doubled after every failure
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// Idle time before the OS starts probing the connection to the server, so a
+// dead one ends in a reconnect
+const SERVER_TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+// Upstream connections are pooled and reused between requests, idle ones are
+// kept alive this long
+const UPSTREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+const UPSTREAM_TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Parser, Debug)]
+#[command(about = "tunnel-ly client")]
+struct Args {
+    /// Local service to expose, e.g. http://localhost:3000 or https://localhost:8443
+    #[arg(long, default_value = "http://localhost:8000", value_parser = parse_target)]
+    target: Url,
+    /// Send requests under a path prefix to another local service instead of
+    /// --target, e.g. `/api=http://localhost:3000`, can be repeated and the
+    /// longest matching prefix wins
+    #[arg(long, value_name = "PREFIX=TARGET", value_parser = parse_route)]
+    route: Vec<(String, Url)>,
+    /// Also send a copy of every request to this service, e.g. a new version under
+    /// test, its responses are thrown away
+    #[arg(long, value_name = "URL", value_parser = parse_target)]
+    mirror_target: Option<Url>,
+    /// Unix socket of a local service to expose instead of --target
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["target", "route"])]
+    target_unix: Option<PathBuf>,
+    /// Accept invalid or self-signed certificates from an https target
+    #[arg(long)]
+    insecure_upstream: bool,
+    /// Talk HTTP/2 to the local service without negotiating it first, for h2c
+    /// servers such as gRPC in development. Visitors and the tunnel itself still
+    /// use HTTP/1.1, so trailers are dropped and gRPC status only arrives when
+    /// the service also sends it in the response headers
+    #[arg(long)]
+    http2_prior_knowledge: bool,
+    /// Connect to this IP for a target or mirror host instead of looking it up,
+    /// e.g. `app:172.18.0.5`, still sending its name in the Host header and TLS,
+    /// can be repeated
+    #[arg(long, value_name = "HOST:IP", value_parser = parse_resolve)]
+    resolve: Vec<(String, IpAddr)>,
+    /// Host header sent upstream: `target` uses the target's host, or localhost
+    /// for a Unix socket, `preserve` keeps the public tunnel host and
+    /// `rewrite:<value>` sends a fixed value
+    #[arg(long, default_value = "target", value_parser = parse_host_header)]
+    host_header: HostHeader,
+    /// Point Location headers naming the local service, absolute or scheme-relative,
+    /// at the public tunnel host instead
+    #[arg(long)]
+    rewrite_redirects: bool,
+    /// Replace a Set-Cookie Domain naming the local service's host with the public
+    /// tunnel host
+    #[arg(long)]
+    rewrite_cookie_domain: bool,
+    /// Only forward requests with this method, e.g. GET, answering others with 405,
+    /// can be repeated
+    #[arg(long, value_name = "METHOD", value_parser = parse_method)]
+    allow_method: Vec<String>,
+    /// Only forward requests whose path matches this glob, e.g. `/api/*`, can be repeated
+    #[arg(long, value_name = "GLOB")]
+    allow_path: Vec<String>,
+    /// Answer requests whose path matches this glob with 403 instead of forwarding
+    /// them, even if allowed, can be repeated
+    #[arg(long, value_name = "GLOB")]
+    deny_path: Vec<String>,
+    /// The tunnel server's public listener, where tunnels are started, e.g.
+    /// https://tunnel.example or http://localhost:8000 for development
+    #[arg(long, default_value = "https://rachel.test", value_parser = parse_server)]
+    server: Url,
+    /// Subdomain to ask the server for instead of a generated one
+    #[arg(long)]
+    subdomain: Option<String>,
+    /// Join the --subdomain tunnel another client opened on a load balancing
+    /// server, with the token the server gave that client
+    #[arg(long, requires = "subdomain")]
+    join_token: Option<String>,
+    /// Token the server was started with, for servers that only open tunnels
+    /// for clients sending `Authorization: Bearer <token>`. Only sent to an
+    /// https --server unless --insecure-auth is given
+    #[arg(long)]
+    auth_token: Option<String>,
+    /// Send --auth-token to an http --server anyway, where anyone on the way can
+    /// read it
+    #[arg(long, requires = "auth_token")]
+    insecure_auth: bool,
+    /// Require visitors to log in with these credentials before anything is forwarded
+    #[arg(long, value_name = "USER:PASS", value_parser = parse_basic_auth)]
+    basic_auth: Option<String>,
+    /// Have the server redirect visitors to the same path under this URL, e.g. a
+    /// status page, instead of showing its offline page for the first while
+    /// (the server's --offline-grace) after the client disconnects
+    #[arg(long, value_name = "URL", value_parser = parse_target)]
+    fallback_url: Option<Url>,
+    /// Seconds to wait for the local service to start responding before answering
+    /// 504, 0 waits forever
+    #[arg(long, default_value_t = 30)]
+    upstream_timeout: u64,
+    /// Bytes buffered on each side of the connection to the server
+    #[arg(long, default_value_t = 8 * 1024)]
+    buffer_size: usize,
+    /// Answer requests whose head is longer than this many bytes with 431, and
+    /// abort ones with a longer body chunk, instead of reading them
+    #[arg(long, default_value_t = DEFAULT_MAX_MESSAGE_LEN)]
+    max_message_bytes: usize,
+    /// Print every message to and from the server, showing up to this many bytes
+    /// of each head or body chunk. Prints request and response contents, so only
+    /// use it for debugging
+    #[arg(long, value_name = "MAX_BYTES")]
+    dump_messages: Option<usize>,
+    /// Connect to the server's proxy port over TLS, trusting the CA certificates in
+    /// this PEM file, e.g. the system bundle at /etc/ssl/certs/ca-certificates.crt
+    /// or the one that signed the server's certificate
+    #[arg(long, value_name = "PATH")]
+    server_tls_ca: Option<PathBuf>,
+    /// Forward a single request, print the status it was answered with and exit
+    /// once the response is sent, e.g. for scripts. Requests arriving meanwhile
+    /// are answered with 503
+    #[arg(long)]
+    once: bool,
+    /// Keep up to this many GET responses in memory and answer repeats from them,
+    /// as long as the local service allows with Cache-Control max-age. Requests
+    /// with cookies or credentials always go through, 0 turns caching off
+    #[arg(long, default_value_t = 0)]
+    cache_entries: usize,
+    /// Longest a response is cached, in seconds, even if its max-age is longer
+    #[arg(long, default_value_t = 60)]
+    cache_ttl: u64,
+}
+
+fn parse_method(method: &str) -> Result<String, String> {
+    let method = method.to_ascii_uppercase();
+    hyper::Method::from_bytes(method.as_bytes())
+        .map(|_| method)
+        .map_err(|e| e.to_string())
+}
+
+fn parse_basic_auth(credentials: &str) -> Result<String, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(credentials.to_string()),
+        _ => Err("expected USER:PASS".to_string()),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum HostHeader {
+    Target,
+    Preserve,
+    Rewrite(HeaderValue),
+}
+
+fn parse_host_header(host_header: &str) -> Result<HostHeader, String> {
+    match host_header {
+        "target" => Ok(HostHeader::Target),
+        "preserve" => Ok(HostHeader::Preserve),
+        _ => match host_header.strip_prefix("rewrite:") {
+            Some(value) => HeaderValue::from_str(value)
+                .map(HostHeader::Rewrite)
+                .map_err(|e| format!("invalid host header value: {}", e)),
+            None => Err("expected target, preserve or rewrite:<value>".to_string()),
+        },
+    }
+}
+
+// Where and how requests coming through the tunnel are forwarded
+#[derive(Debug)]
+struct Upstream {
+    transport: Transport,
+    host_header: HostHeader,
+    paths: PathFilter,
+    // Methods requests may use, any if empty
+    methods: Vec<String>,
+    timeout: Option<Duration>,
+    rewrite_redirects: bool,
+    rewrite_cookie_domain: bool,
+    mirror: Option<Mirror>,
+    cache: Option<ResponseCache>,
+}
+
+// A second local service that gets a copy of every request, whose responses
+// are thrown away
+#[derive(Debug)]
+struct Mirror {
+    client: reqwest::Client,
+    target: Url,
+}
+
+impl Mirror {
+    // Sends a copy of `request` on its own task, failures are only logged
+    fn send(&self, request: &hyper::http::request::Builder, body: Option<RequestBody>) {
+        let (Some(method), Some(uri), Some(headers)) = (
+            request.method_ref(),
+            request.uri_ref(),
+            request.headers_ref(),
+        ) else {
+            return;
+        };
+        let mut headers = headers.clone();
+        // Filled in from the mirror's own URL
+        headers.remove(hyper::header::HOST);
+        let mut mirrored = self
+            .client
+            .request(method.clone(), join_target(&self.target, &uri.to_string()))
+            .headers(headers);
+        if let Some(body) = body {
+            mirrored = mirrored.body(reqwest::Body::wrap_stream(body.into_stream()));
+        }
+        tokio::spawn(async move {
+            match mirrored.send().await {
+                // Read to the end so the connection can be reused
+                Ok(response) => {
+                    let _ = response.bytes().await;
+                }
+                Err(e) => println!("Error: mirror request failed: {}", e),
+            }
+        });
+    }
+}
+
+// Copies a request body as it streams in, so the primary and the mirror each
+// get all of it. Room for more is granted as the primary takes its copy, the
+// mirror isn't waited on
+fn tee_body(mut body: RequestBody) -> (RequestBody, RequestBody) {
+    let (primary_sender, primary) = unbounded_channel();
+    let (mirror_sender, mirror) = unbounded_channel();
+    let primary = RequestBody {
+        chunks: primary,
+        grant: body.grant.take(),
+    };
+    let mirror = RequestBody {
+        chunks: mirror,
+        grant: None,
+    };
+    tokio::spawn(async move {
+        while let Some(chunk) = body.recv().await {
+            let copy = match &chunk {
+                Ok(data) => Ok(data.clone()),
+                Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+            };
+            let _ = mirror_sender.send(copy);
+            let _ = primary_sender.send(chunk);
+        }
+    });
+    (primary, mirror)
+}
+
+// Which request paths are exposed, a denied glob wins over an allowed one and
+// no allowed globs allows everything
+#[derive(Debug)]
+struct PathFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl PathFilter {
+    fn permits(&self, path: &str) -> bool {
+        if self.deny.iter().any(|glob| glob_match(glob, path)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|glob| glob_match(glob, path))
+    }
+}
+
+// Whether `text` matches `glob`, where `*` stands for any run of characters
+// including none and `/`
+fn glob_match(glob: &str, text: &str) -> bool {
+    let (glob, text) = (glob.as_bytes(), text.as_bytes());
+    let (mut g, mut t) = (0, 0);
+    // Where the last `*` was and the text position it's currently matched up to
+    let mut star = None;
+    while t < text.len() {
+        if g < glob.len() && glob[g] == b'*' {
+            star = Some((g, t));
+            g += 1;
+        } else if g < glob.len() && glob[g] == text[t] {
+            g += 1;
+            t += 1;
+        } else if let Some((star_g, star_t)) = star {
+            g = star_g + 1;
+            t = star_t + 1;
+            star = Some((star_g, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|&c| c == b'*')
+}
+
+#[derive(Debug)]
+enum Transport {
+    Tcp {
+        client: reqwest::Client,
+        targets: Targets,
+    },
+    Unix {
+        client: hyper::Client<UnixConnector>,
+        socket: PathBuf,
+    },
+}
+
+// The --target and any --route prefixes that go elsewhere, longest first
+#[derive(Debug)]
+struct Targets {
+    default: Url,
+    routes: Vec<(String, Url)>,
+}
+
+impl Targets {
+    fn new(default: Url, mut routes: Vec<(String, Url)>) -> Self {
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Targets { default, routes }
+    }
+
+    // A prefix only matches whole segments, `/api` takes `/api/users` but not
+    // `/apis`
+    fn pick(&self, path: &str) -> &Url {
+        let path = path.split('?').next().unwrap_or(path);
+        self.routes
+            .iter()
+            .find(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                    rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+                })
+            })
+            .map_or(&self.default, |(_, target)| target)
+    }
+}
+
+// How a local service is referred to in error responses
+fn service_name(url: &Url) -> String {
+    format!(
+        "the local service at {}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+impl Upstream {
+    // How the service a request for `path` goes to is referred to in error
+    // responses
+    fn name(&self, path: &str) -> String {
+        match &self.transport {
+            Transport::Tcp { targets, .. } => service_name(targets.pick(path)),
+            Transport::Unix { socket, .. } => {
+                format!("the local service's socket {}", socket.display())
+            }
+        }
+    }
+
+    // The URL a request for `path` is sent to, as the upstream would name itself
+    // in redirects
+    fn local_url(&self, path: &str) -> Url {
+        match &self.transport {
+            Transport::Tcp { targets, .. } => targets.pick(path).clone(),
+            Transport::Unix { .. } => Url::parse("http://localhost/").unwrap(),
+        }
+    }
+
+    // Points the response's redirects and cookies for the local service that
+    // answered `path` at where the browser reached the tunnel instead
+    fn rewrite_response(
+        &self,
+        headers: &mut reqwest::header::HeaderMap,
+        path: &str,
+        public: &PublicOrigin,
+    ) {
+        let local = self.local_url(path);
+        if self.rewrite_redirects {
+            let location = headers
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|location| rewrite_location(location, &local, public))
+                .and_then(|location| HeaderValue::from_str(&location).ok());
+            if let Some(location) = location {
+                headers.insert(reqwest::header::LOCATION, location);
+            }
+        }
+        if self.rewrite_cookie_domain {
+            let cookies: Vec<HeaderValue> = headers
+                .get_all(reqwest::header::SET_COOKIE)
+                .iter()
+                .map(|value| {
+                    value
+                        .to_str()
+                        .ok()
+                        .and_then(|cookie| {
+                            rewrite_cookie_domain(cookie, local.host_str()?, public.hostname())
+                        })
+                        .and_then(|cookie| HeaderValue::from_str(&cookie).ok())
+                        .unwrap_or_else(|| value.clone())
+                })
+                .collect();
+            headers.remove(reqwest::header::SET_COOKIE);
+            for cookie in cookies {
+                headers.append(reqwest::header::SET_COOKIE, cookie);
+            }
+        }
+    }
+
+    // Sends a request, giving up if the upstream takes longer than the timeout
+    // to start responding
+    async fn send(
+        &self,
+        request: hyper::http::request::Builder,
+        body: Option<RequestBody>,
+    ) -> Result<reqwest::Response, TunnelError> {
+        match self.timeout {
+            Some(limit) => {
+                let name = self.name(request.uri_ref().map_or("/", |uri| uri.path()));
+                timeout(limit, self.execute(request, body))
+                    .await
+                    .map_err(|_| TunnelError::Timeout(limit, name))?
+            }
+            None => self.execute(request, body).await,
+        }
+    }
+
+    // Sends a request whose URI is still just the path, responses over a Unix
+    // socket are wrapped up as reqwest ones so the rest of the client can treat
+    // both transports the same
+    async fn execute(
+        &self,
+        request: hyper::http::request::Builder,
+        body: Option<RequestBody>,
+    ) -> Result<reqwest::Response, TunnelError> {
+        let path = request
+            .uri_ref()
+            .map(|uri| uri.to_string())
+            .unwrap_or_default();
+        match &self.transport {
+            Transport::Tcp { client, targets } => {
+                let target = targets.pick(&path);
+                let body = match body {
+                    Some(body) => reqwest::Body::wrap_stream(body.into_stream()),
+                    None => reqwest::Body::from(Vec::new()),
+                };
+                let request = request
+                    .uri(join_target(target, &path).as_str())
+                    .body(body)
+                    .map_err(|e| TunnelError::Parse(e.to_string()))?;
+                let request = reqwest::Request::try_from(request).map_err(TunnelError::Upstream)?;
+                client.execute(request).await.map_err(TunnelError::Upstream)
+            }
+            Transport::Unix { client, socket } => {
+                let mut request = request.uri(hyperlocal::Uri::new(socket, &path));
+                // hyper would otherwise send the hex encoded socket path
+                if !request
+                    .headers_ref()
+                    .is_some_and(|headers| headers.contains_key(hyper::header::HOST))
+                {
+                    request = request.header(hyper::header::HOST, "localhost");
+                }
+                let body = match body {
+                    Some(body) => hyper::Body::wrap_stream(body.into_stream()),
+                    None => hyper::Body::empty(),
+                };
+                let request = request
+                    .body(body)
+                    .map_err(|e| TunnelError::Parse(e.to_string()))?;
+                let response = client.request(request).await.map_err(TunnelError::Unix)?;
+                let (parts, body) = response.into_parts();
+                Ok(hyper::Response::from_parts(parts, reqwest::Body::wrap_stream(body)).into())
+            }
+        }
+    }
+}
+
+// Where the browser reached the tunnel, as the server forwarded it
+#[derive(Debug)]
+struct PublicOrigin {
+    scheme: String,
+    host: String,
+}
+
+impl PublicOrigin {
+    // The host without its port, for cookie domains
+    fn hostname(&self) -> &str {
+        match self.host.find(']') {
+            Some(end) => &self.host[..=end],
+            None => self.host.split(':').next().unwrap_or(&self.host),
+        }
+    }
+}
+
+// `location` moved to the public origin if it's an absolute or scheme-relative
+// URL on the local service, None if it should be left alone
+fn rewrite_location(location: &str, local: &Url, public: &PublicOrigin) -> Option<String> {
+    let scheme_relative = location.starts_with("//");
+    let url = if scheme_relative {
+        Url::parse(&format!("{}:{}", local.scheme(), location)).ok()?
+    } else {
+        Url::parse(location).ok()?
+    };
+    if url.host_str() != local.host_str()
+        || url.port_or_known_default() != local.port_or_known_default()
+    {
+        return None;
+    }
+    let mut rest = url.path().to_string();
+    if let Some(query) = url.query() {
+        rest = format!("{}?{}", rest, query);
+    }
+    if let Some(fragment) = url.fragment() {
+        rest = format!("{}#{}", rest, fragment);
+    }
+    Some(if scheme_relative {
+        format!("//{}{}", public.host, rest)
+    } else {
+        format!("{}://{}{}", public.scheme, public.host, rest)
+    })
+}
+
+// `cookie` with a Domain attribute naming `local` replaced by `public`, None if
+// it has no such attribute
+fn rewrite_cookie_domain(cookie: &str, local: &str, public: &str) -> Option<String> {
+    let mut rewritten = false;
+    let attributes: Vec<String> = cookie
+        .split(';')
+        .map(|attribute| match attribute.trim().split_once('=') {
+            Some((name, domain))
+                if name.trim().eq_ignore_ascii_case("domain")
+                    && domain
+                        .trim()
+                        .trim_start_matches('.')
+                        .eq_ignore_ascii_case(local) =>
+            {
+                rewritten = true;
+                format!(" Domain={}", public)
+            }
+            _ => attribute.to_string(),
+        })
+        .collect();
+    rewritten.then(|| attributes.join(";"))
+}
+
+fn parse_route(route: &str) -> Result<(String, Url), String> {
+    let (prefix, target) = route
+        .split_once('=')
+        .ok_or_else(|| "expected PREFIX=TARGET".to_string())?;
+    if !prefix.starts_with('/') {
+        return Err("route prefix must start with /".to_string());
+    }
+    Ok((prefix.to_string(), parse_target(target)?))
+}
+
+fn parse_resolve(mapping: &str) -> Result<(String, IpAddr), String> {
+    let (host, ip) = mapping
+        .split_once(':')
+        .filter(|(host, _)| !host.is_empty())
+        .ok_or_else(|| "expected HOST:IP".to_string())?;
+    let ip = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|e| format!("invalid IP address: {}", e))?;
+    // Matched against URL hosts, which are always lowercase
+    Ok((host.to_ascii_lowercase(), ip))
+}
+
+fn parse_server(server: &str) -> Result<Url, String> {
+    let url = Url::parse(server).map_err(|e| format!("invalid server URL: {}", e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!(
+            "server must be http or https, not {}",
+            url.scheme()
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err("server must include a host".to_string());
+    }
+    Ok(url)
+}
+
+fn parse_target(target: &str) -> Result<Url, String> {
+    let url = Url::parse(target).map_err(|e| format!("invalid target URL: {}", e))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(format!(
+            "target must be http or https, not {}",
+            url.scheme()
+        ));
+    }
+    if url.host().is_none() {
+        return Err("target must include a host".to_string());
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err("target must not include a query or fragment".to_string());
+    }
+    Ok(url)
+}
+
+// Client for the local services requests are forwarded or mirrored to
+fn upstream_client(
+    insecure: bool,
+    http2_prior_knowledge: bool,
+    resolve: &[(String, IpAddr)],
+) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .danger_accept_invalid_certs(insecure)
+        .pool_idle_timeout(UPSTREAM_IDLE_TIMEOUT)
+        .tcp_keepalive(UPSTREAM_TCP_KEEPALIVE)
+        .connect_timeout(UPSTREAM_CONNECT_TIMEOUT);
+    if http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    // The port still comes from the URL, reqwest ignores this one
+    for (host, ip) in resolve {
+        builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+    }
+    builder.build()
+}
+
+// TLS settings for the primary stream, trusting only the given certificates
+fn server_tls_connector(ca: &PathBuf) -> io::Result<TlsConnector> {
+    let mut roots = rustls::RootCertStore::empty();
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(File::open(ca)?))?;
+    let (added, _) = roots.add_parsable_certificates(&certs);
+    if added == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no certificates found",
+        ));
+    }
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
 
 #[tokio::main]
 async fn main() {
-    let forwarding_port = "8000";
-    let forwarding_url = "http://localhost";
-    let server_proxy_port = "8080";
-    let server_http_port = "80";
-    let domain = "rachel.test";
+    // Warnings show by default, RUST_LOG can ask for more or less
+    let mut logger = pretty_env_logger::formatted_builder();
+    logger.filter_level(log::LevelFilter::Warn);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        logger.parse_filters(&filters);
+    }
+    logger.init();
+    let args = Args::parse();
+    if args.auth_token.is_some() && args.server.scheme() != "https" && !args.insecure_auth {
+        println!(
+            "Error: refusing to send --auth-token in the clear to {}, use an https --server or pass --insecure-auth",
+            args.server
+        );
+        std::process::exit(1);
+    }
+    let tls = args
+        .server_tls_ca
+        .map(|ca| match server_tls_connector(&ca) {
+            Ok(connector) => connector,
+            Err(e) => {
+                println!("Error: failed to load {}: {}", ca.display(), e);
+                std::process::exit(1);
+            }
+        });
+    let mirror = args.mirror_target.map(|target| {
+        match upstream_client(
+            args.insecure_upstream,
+            args.http2_prior_knowledge,
+            &args.resolve,
+        ) {
+            Ok(client) => Mirror { client, target },
+            Err(e) => {
+                println!("Error: failed to set up mirror client: {}", e);
+                std::process::exit(1);
+            }
+        }
+    });
+    let transport = match args.target_unix {
+        Some(socket) => Transport::Unix {
+            client: hyper::Client::builder()
+                .pool_idle_timeout(UPSTREAM_IDLE_TIMEOUT)
+                .http2_only(args.http2_prior_knowledge)
+                .build(UnixConnector),
+            socket,
+        },
+        None => match upstream_client(
+            args.insecure_upstream,
+            args.http2_prior_knowledge,
+            &args.resolve,
+        ) {
+            Ok(client) => Transport::Tcp {
+                client,
+                targets: Targets::new(args.target, args.route),
+            },
+            Err(e) => {
+                println!("Error: failed to set up upstream client: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let upstream = Arc::new(Upstream {
+        transport,
+        host_header: args.host_header,
+        paths: PathFilter {
+            allow: args.allow_path,
+            deny: args.deny_path,
+        },
+        methods: args.allow_method,
+        timeout: Some(Duration::from_secs(args.upstream_timeout)).filter(|t| !t.is_zero()),
+        rewrite_redirects: args.rewrite_redirects,
+        rewrite_cookie_domain: args.rewrite_cookie_domain,
+        mirror,
+        cache: Some(args.cache_entries)
+            .filter(|entries| *entries > 0)
+            .map(|entries| ResponseCache::new(entries, Duration::from_secs(args.cache_ttl))),
+    });
     let client = reqwest::Client::new();
-    let service_id = client
-        .post(&format!("http://{}:{}/start", domain, server_http_port))
-        .send()
-        .await
-        .unwrap()
-        .text()
+
+    let mut backoff = INITIAL_BACKOFF;
+    let mut reclaim = None;
+    loop {
+        match connect(
+            &client,
+            &args.server,
+            tls.as_ref(),
+            args.buffer_size,
+            args.dump_messages,
+            StartOptions {
+                subdomain: args.subdomain.as_deref(),
+                join_token: args.join_token.as_deref(),
+                auth_token: args.auth_token.as_deref(),
+                basic_auth: args.basic_auth.as_deref(),
+                fallback_url: args.fallback_url.as_ref().map(Url::as_str),
+            },
+            &mut reclaim,
+        )
         .await
-        .unwrap();
+        {
+            Ok((reader, writer_sender, writer)) => {
+                backoff = INITIAL_BACKOFF;
+                match serve(
+                    reader,
+                    writer_sender,
+                    &upstream,
+                    args.max_message_bytes,
+                    args.dump_messages,
+                    args.once,
+                )
+                .await
+                {
+                    // Closing the stream once the response is written is what
+                    // takes the tunnel down on the server
+                    Ok(()) => {
+                        let _ = writer.await;
+                        return;
+                    }
+                    Err(e) => println!("Error: lost connection to server: {}", e),
+                }
+            }
+            Err(e) => println!("Error: failed to connect to server: {}", e),
+        }
+        println!("Reconnecting in {}s", backoff.as_secs());
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
 
-    println!("body: {}", service_id);
+// What the client asks of the server when opening its tunnel
+#[derive(Debug, Clone, Copy)]
+struct StartOptions<'a> {
+    subdomain: Option<&'a str>,
+    join_token: Option<&'a str>,
+    auth_token: Option<&'a str>,
+    basic_auth: Option<&'a str>,
+    fallback_url: Option<&'a str>,
+}
 
-    let mut socket = TcpStream::connect(format!("{}:{}", domain, server_proxy_port))
-        .await
-        .unwrap();
-    socket.writable().await.unwrap();
-    socket
-        .write_all(format!("{}\0", service_id).as_bytes())
-        .await
-        .unwrap();
-    loop {
-        let mut bytes = vec![];
-        loop {
-            socket.readable().await.unwrap();
-            let mut buf: [u8; 1] = [0; 1];
-            let bytes_read = socket.read(&mut buf).await.unwrap();
-            if bytes_read == 0 {
-                continue;
+// A subdomain the server will hand back to whoever presents its token
+#[derive(Debug)]
+struct Reclaim {
+    service_id: String,
+    token: String,
+}
+
+// Registers a tunnel with the server, asking for the previous subdomain back if
+// the server gave out a reclaim token for it, and opens its primary stream
+async fn connect(
+    client: &reqwest::Client,
+    server: &Url,
+    tls: Option<&TlsConnector>,
+    buffer_size: usize,
+    dump_messages: Option<usize>,
+    options: StartOptions<'_>,
+    reclaim: &mut Option<Reclaim>,
+) -> Result<(ServerReader, UnboundedSender<Message>, JoinHandle<()>), TunnelError> {
+    let mut start = server.clone();
+    start.set_path("/start");
+    let mut request = client.post(start).header("Accept", "application/json");
+    if let Some(token) = options.auth_token {
+        request = request.bearer_auth(token);
+    }
+    if let Some(credentials) = options.basic_auth {
+        request = request.header("X-Basic-Auth", credentials);
+    }
+    if let Some(fallback_url) = options.fallback_url {
+        request = request.header("X-Fallback-Url", fallback_url);
+    }
+    if let Some(token) = options.join_token {
+        request = request.header("X-Join-Token", token);
+    }
+    if let Some(reclaim) = reclaim.as_ref() {
+        request = request
+            .header("X-Requested-Subdomain", &reclaim.service_id)
+            .header("X-Reclaim-Token", &reclaim.token);
+    } else if let Some(subdomain) = options.subdomain {
+        request = request.header("X-Requested-Subdomain", subdomain);
+    }
+    let response = request.send().await.map_err(TunnelError::Server)?;
+    // The hold expired and someone else took the subdomain, so the next
+    // attempt takes whatever the server generates
+    if response.status() == StatusCode::CONFLICT {
+        *reclaim = None;
+    }
+    let response = response.error_for_status().map_err(TunnelError::Server)?;
+    let token = response
+        .headers()
+        .get("X-Reclaim-Token")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    // Only handed out by load balancing servers
+    let join_token = response
+        .headers()
+        .get("X-Join-Token")
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| options.join_token.is_none())
+        .map(str::to_string);
+    let secret = response
+        .headers()
+        .get("X-Handshake-Secret")
+        .ok_or_else(|| TunnelError::Parse("no handshake secret in start response".to_string()))?
+        .as_bytes()
+        .to_vec();
+    let body = response.bytes().await.map_err(TunnelError::Server)?;
+    let (service_id, url, proxy) = parse_started(&body, server)?;
+    *reclaim = token.map(|token| Reclaim {
+        service_id: service_id.clone(),
+        token,
+    });
+
+    println!("Connected at {}", url);
+    if let Some(token) = join_token {
+        println!(
+            "Other clients can join with --subdomain {} --join-token {}",
+            service_id, token
+        );
+    }
+
+    let socket = TcpStream::connect(proxy).await?;
+    socket.set_nodelay(true)?;
+    SockRef::from(&socket)
+        .set_tcp_keepalive(&TcpKeepalive::new().with_time(SERVER_TCP_KEEPALIVE))?;
+    // The service id is sent inside TLS like everything after it
+    let mut stream: Box<dyn ServerStream> = match tls {
+        Some(tls) => {
+            let domain = server.host_str().unwrap_or_default();
+            let server_name = ServerName::try_from(domain)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let stream = tls
+                .connect(server_name, socket)
+                .await
+                .map_err(|e| io::Error::new(e.kind(), format!("TLS handshake failed: {}", e)))?;
+            Box::new(stream)
+        }
+        None => Box::new(socket),
+    };
+    // Signed so only whoever got the secret from the start request can claim
+    // the tunnel
+    let signature = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, &secret),
+        service_id.as_bytes(),
+    );
+    write_frame(&mut stream, service_id.as_bytes()).await?;
+    write_frame(&mut stream, signature.as_ref()).await?;
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = BufReader::with_capacity(buffer_size, reader);
+    let mut writer = BufWriter::with_capacity(buffer_size, writer);
+
+    let (writer_sender, mut writer_receiver) = unbounded_channel::<Message>();
+    // Ends once every sender is gone and what they sent is written
+    let writer = tokio::spawn(async move {
+        while let Some(message) = writer_receiver.recv().await {
+            if let Some(max_len) = dump_messages {
+                println!("> {}", dump(&message, max_len));
             }
-            if buf[0] == 0x00 {
-                // End of message signalled
-                break;
+            // Flushed once there's nothing else to send
+            let written = match write_message(&mut writer, &message).await {
+                Ok(()) if writer_receiver.is_empty() => writer.flush().await,
+                written => written,
+            };
+            if let Err(e) = written {
+                println!("Error: failed to write to server: {}", e);
+                return;
             }
-            bytes.push(buf[0]);
         }
-        let response = create_request(bytes, forwarding_url, forwarding_port).await;
-        let response = match response {
-            Ok(r) => r,
-            Err(e) => {
-                println!("Error: {}", e);
+        let _ = writer.shutdown().await;
+    });
+    Ok((reader, writer_sender, writer))
+}
+
+// The service id, public URL and proxy address from a start response. Servers
+// that don't send JSON answer with the bare service id, and the rest is worked
+// out from `server` and the default proxy port
+fn parse_started(body: &[u8], server: &Url) -> Result<(String, String, String), TunnelError> {
+    if let Ok(started) = serde_json::from_slice::<serde_json::Value>(body) {
+        let field = |name: &str| {
+            started[name]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| TunnelError::Parse(format!("no {} in start response", name)))
+        };
+        return Ok((field("service_id")?, field("url")?, field("proxy")?));
+    }
+    let service_id = std::str::from_utf8(body)
+        .map(str::trim)
+        .ok()
+        .filter(|id| !id.is_empty())
+        .ok_or_else(|| TunnelError::Parse("bad start response".to_string()))?;
+    let host = server.host_str().unwrap_or_default();
+    let port = server
+        .port()
+        .map(|port| format!(":{}", port))
+        .unwrap_or_default();
+    Ok((
+        service_id.to_string(),
+        format!("{}://{}.{}{}", server.scheme(), service_id, host, port),
+        format!("{}:{}", host, DEFAULT_PROXY_PORT),
+    ))
+}
+
+// Forwards requests from the primary stream until it fails, or with `once`
+// until the first one has been answered
+async fn serve(
+    mut reader: ServerReader,
+    writer_sender: UnboundedSender<Message>,
+    upstream: &Arc<Upstream>,
+    max_message_bytes: usize,
+    dump_messages: Option<usize>,
+    once: bool,
+) -> io::Result<()> {
+    // Request bodies still being streamed from the server
+    let mut bodies: HashMap<u32, UnboundedSender<io::Result<Vec<u8>>>> = HashMap::new();
+    // How much more of each response the server will take, gone once the
+    // response has been sent
+    let mut windows: HashMap<u32, Weak<Semaphore>> = HashMap::new();
+    // Taken by the first request when only one is forwarded
+    let (done_sender, mut done) = oneshot::channel::<StatusCode>();
+    let mut done_sender = once.then_some(done_sender);
+    let result = loop {
+        let read = tokio::select! {
+            read = read_message_max(&mut reader, max_message_bytes) => read,
+            status = &mut done, if once => {
+                if let Ok(status) = status {
+                    println!("Forwarded one request, answered with {}", status);
+                }
+                break Ok(());
+            }
+            // The writer gave up part way through a frame, which the server
+            // would otherwise wait on, so the connection is dropped and redone
+            _ = writer_sender.closed() => {
+                break Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "stopped writing to server",
+                ));
+            }
+        };
+        let message = match read {
+            Ok(Ok(message)) => message,
+            Ok(Err(oversized)) if oversized.head => {
+                println!(
+                    "Error: skipped {} byte request head over the limit",
+                    oversized.len
+                );
+                send_error(
+                    oversized.id,
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                    "the request's headers are too large for the tunnel",
+                    &writer_sender,
+                );
                 continue;
             }
+            // The rest of the body can't be trusted either
+            Ok(Err(oversized)) => {
+                println!(
+                    "Error: skipped {} byte request body chunk over the limit",
+                    oversized.len
+                );
+                Message::Abort { id: oversized.id }
+            }
+            Err(e) => break Err(e),
         };
-        let bytes = create_http_text(response).await;
-        socket
-            .write_all(format!("{}\0", bytes.len()).as_bytes())
-            .await
-            .unwrap();
-        socket.write_all(&bytes).await.unwrap();
-        socket.flush().await.unwrap();
+        if let Some(max_len) = dump_messages {
+            println!("< {}", dump(&message, max_len));
+        }
+        match message {
+            Message::Request { id, .. } if once && done_sender.is_none() => {
+                send_error(
+                    id,
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "the tunnel is only forwarding one request",
+                    &writer_sender,
+                );
+            }
+            Message::Request { id, data } => {
+                let (method, path) = request_line(&data);
+                let done_sender = done_sender.take();
+                let (body_sender, chunks) = unbounded_channel();
+                bodies.insert(id, body_sender);
+                let body_receiver = RequestBody {
+                    chunks,
+                    grant: Some((id, writer_sender.clone())),
+                };
+                let window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+                windows.retain(|_, window| window.strong_count() > 0);
+                windows.insert(id, Arc::downgrade(&window));
+                // Each request gets its own task so a slow upstream response doesn't
+                // hold up the rest of the tunnel
+                let writer_sender = writer_sender.clone();
+                let upstream = upstream.clone();
+                tokio::spawn(async move {
+                    let mut body = Some(body_receiver);
+                    let status = match create_request(data, &mut body, &upstream).await {
+                        // An upgrade request has no body, so its body frames are left
+                        // to carry the upgraded connection
+                        Ok(response) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                            match body {
+                                Some(body) => {
+                                    splice_upgrade(id, response, body, &window, &writer_sender)
+                                        .await
+                                }
+                                None => {
+                                    forward_response(id, response, &window, &writer_sender).await
+                                }
+                            }
+                            StatusCode::SWITCHING_PROTOCOLS
+                        }
+                        Ok(response) => {
+                            let status = response.status();
+                            forward_response(id, response, &window, &writer_sender).await;
+                            status
+                        }
+                        Err(e) => {
+                            warn!(
+                                "{} {} to {} failed: {}",
+                                method,
+                                path,
+                                upstream.name(&path),
+                                e.cause()
+                            );
+                            let (status, reason) = e.describe(&upstream);
+                            let allow = upstream.methods.join(", ");
+                            let headers: &[(&str, &str)] =
+                                if matches!(e, TunnelError::MethodNotAllowed(_)) {
+                                    &[("allow", &allow)]
+                                } else {
+                                    &[]
+                                };
+                            send_error_with_headers(id, status, &reason, headers, &writer_sender);
+                            status
+                        }
+                    };
+                    if let Some(done_sender) = done_sender {
+                        let _ = done_sender.send(status);
+                    }
+                });
+            }
+            Message::Body { id, data } => {
+                if let Some(body_sender) = bodies.get(&id) {
+                    let _ = body_sender.send(Ok(data));
+                }
+            }
+            Message::End { id } => {
+                bodies.remove(&id);
+            }
+            Message::Abort { id } => {
+                if let Some(body_sender) = bodies.remove(&id) {
+                    let _ = body_sender.send(Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        "request aborted by server",
+                    )));
+                }
+                // Stops the response, the server won't take any more of it
+                if let Some(window) = windows.remove(&id).and_then(|window| window.upgrade()) {
+                    window.close();
+                }
+            }
+            Message::Window { id, bytes } => {
+                if let Some(window) = windows.get(&id).and_then(Weak::upgrade) {
+                    window.add_permits(bytes as usize);
+                }
+            }
+            Message::Ping { id } => {
+                let _ = writer_sender.send(Message::Pong { id });
+            }
+            // In-flight requests keep going until the server closes the stream,
+            // then the usual reconnect kicks in
+            Message::Shutdown => println!("Server is shutting down"),
+            Message::Response { id, .. } | Message::Pong { id } => {
+                println!("Error: unexpected message from server: {}", id);
+            }
+        }
+    };
+    for (_, body_sender) in bodies {
+        let _ = body_sender.send(Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            "lost connection to server",
+        )));
+    }
+    for window in windows.values().filter_map(Weak::upgrade) {
+        window.close();
+    }
+    result
+}
+
+// Why connecting to the server failed, or why a request from it got no
+// response from the upstream
+#[derive(Debug)]
+enum TunnelError {
+    /// Something the server sent didn't make sense
+    Parse(String),
+    Forbidden(String),
+    MethodNotAllowed(String),
+    Upstream(reqwest::Error),
+    Unix(hyper::Error),
+    /// The upstream, named, didn't start responding in time
+    Timeout(Duration, String),
+    /// The server's answer to the start request
+    Server(reqwest::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelError::Parse(e) => write!(f, "malformed message from server: {}", e),
+            TunnelError::Forbidden(path) => write!(f, "path not exposed: {}", path),
+            TunnelError::MethodNotAllowed(method) => write!(f, "method not allowed: {}", method),
+            TunnelError::Upstream(e) => e.fmt(f),
+            TunnelError::Unix(e) => e.fmt(f),
+            TunnelError::Timeout(limit, _) => {
+                write!(f, "upstream did not respond within {}s", limit.as_secs())
+            }
+            TunnelError::Server(e) => e.fmt(f),
+            TunnelError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for TunnelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TunnelError::Upstream(e) | TunnelError::Server(e) => Some(e),
+            TunnelError::Unix(e) => Some(e),
+            TunnelError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TunnelError {
+    fn from(e: io::Error) -> Self {
+        TunnelError::Io(e)
+    }
+}
+
+impl TunnelError {
+    // The error and everything under it, cut short so an upstream that fails
+    // with a huge message doesn't flood the log
+    fn cause(&self) -> String {
+        let mut cause = self.to_string();
+        // The first source is the error this already displays
+        let mut source = self.source().and_then(Error::source);
+        while let Some(e) = source {
+            let _ = write!(cause, ": {}", e);
+            source = e.source();
+        }
+        truncate(cause, MAX_LOGGED_LEN)
+    }
+
+    // Status and explanation shown to whoever sent the request, naming the
+    // upstream when it's the one that failed
+    fn describe(&self, upstream: &Upstream) -> (StatusCode, String) {
+        let e: &(dyn Error + 'static) = match self {
+            TunnelError::Parse(_) => {
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    "the tunnel sent a malformed request".to_string(),
+                )
+            }
+            TunnelError::Forbidden(_) => {
+                return (
+                    StatusCode::FORBIDDEN,
+                    "this path is not exposed through the tunnel".to_string(),
+                )
+            }
+            TunnelError::MethodNotAllowed(_) => {
+                return (
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "this method is not allowed through the tunnel".to_string(),
+                )
+            }
+            TunnelError::Timeout(limit, name) => {
+                return (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    format!("{} did not respond within {}s", name, limit.as_secs()),
+                )
+            }
+            TunnelError::Upstream(e) | TunnelError::Server(e) => e,
+            TunnelError::Unix(e) => e,
+            TunnelError::Io(e) => e,
+        };
+        // reqwest errors say which target the request went to
+        let name = match self {
+            TunnelError::Upstream(e) => e.url().map_or_else(|| upstream.name("/"), service_name),
+            _ => upstream.name("/"),
+        };
+        if matches!(self, TunnelError::Upstream(e) if e.is_timeout()) {
+            return (
+                StatusCode::GATEWAY_TIMEOUT,
+                format!("timed out connecting to {}", name),
+            );
+        }
+        let mut source = e.source();
+        while let Some(cause) = source {
+            if let Some(io) = cause.downcast_ref::<io::Error>() {
+                match io.kind() {
+                    io::ErrorKind::ConnectionRefused => {
+                        return (
+                            StatusCode::BAD_GATEWAY,
+                            format!("{} refused the connection, is it running?", name),
+                        )
+                    }
+                    io::ErrorKind::NotFound => {
+                        return (
+                            StatusCode::BAD_GATEWAY,
+                            format!("{} does not exist, is it running?", name),
+                        )
+                    }
+                    io::ErrorKind::TimedOut => {
+                        return (
+                            StatusCode::GATEWAY_TIMEOUT,
+                            format!("timed out connecting to {}", name),
+                        )
+                    }
+                    _ => {}
+                }
+            }
+            // Neither hyper nor the TLS backend expose kinds for failed lookups
+            // or handshakes, only these messages
+            let message = cause.to_string();
+            if message.starts_with("dns error") {
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("could not resolve the host of {}", name),
+                );
+            }
+            let lowercase = message.to_lowercase();
+            if lowercase.contains("certificate") {
+                return (
+                    invalid_ssl_certificate(),
+                    format!("{} presented an invalid TLS certificate: {}", name, message),
+                );
+            }
+            if lowercase.contains("ssl")
+                || lowercase.contains("tls")
+                || lowercase.contains("handshake")
+            {
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("TLS handshake with {} failed: {}", name, message),
+                );
+            }
+            source = cause.source();
+        }
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("could not get a response from {}", name),
+        )
+    }
+}
+
+// Cloudflare's status for an origin whose certificate couldn't be validated,
+// which browsers show as is
+fn invalid_ssl_certificate() -> StatusCode {
+    StatusCode::from_u16(526).unwrap()
+}
+
+// Reason phrase for a status line, including the unofficial ones sent here
+fn reason_phrase(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        526 => "Invalid SSL Certificate",
+        _ => status.canonical_reason().unwrap_or(""),
     }
 }
 
 async fn create_request(
-    bytes: Vec<u8>,
-    forwarding_url: &str,
-    forwarding_port: &str,
-) -> Result<reqwest::Response, String> {
-    let mut headers = vec![httparse::EMPTY_HEADER; 64];
+    head: Vec<u8>,
+    body: &mut Option<RequestBody>,
+    upstream: &Upstream,
+) -> Result<reqwest::Response, TunnelError> {
+    let mut headers = request_header_buffer(&head);
     let mut req = httparse::Request::new(&mut headers);
-    let p = req.parse(&bytes).unwrap();
-    let pre_len = match p {
-        httparse::Status::Complete(len) => len,
-        httparse::Status::Partial => Err("Bad HTTP request".to_string())?,
+    match req
+        .parse(&head)
+        .map_err(|e| TunnelError::Parse(e.to_string()))?
+    {
+        httparse::Status::Complete(_) => {}
+        httparse::Status::Partial => Err(TunnelError::Parse("partial head".to_string()))?,
+    };
+    // Always there once parsing completes, but a bad frame shouldn't panic
+    let (Some(method), Some(path)) = (req.method, req.path) else {
+        return Err(TunnelError::Parse("missing method or path".to_string()));
     };
-    let body = bytes[pre_len..].to_vec();
+    let path = normalize_path(method, path).map_err(TunnelError::Parse)?;
+    let path = path.as_str();
+    // Checked before anything reaches the upstream
+    let route = path.split('?').next().unwrap_or(path);
+    if !upstream.paths.permits(route) {
+        return Err(TunnelError::Forbidden(route.to_string()));
+    }
+    if !upstream.methods.is_empty() && !upstream.methods.iter().any(|m| m == method) {
+        return Err(TunnelError::MethodNotAllowed(method.to_string()));
+    }
     let headers = req.headers.iter().filter(|h| **h != httparse::EMPTY_HEADER);
-    let mut request = reqwest::Client::new()
-        .request(
-            req.method.unwrap().parse().expect("Could not parse method"),
-            format!(
-                "{}:{}{}",
-                forwarding_url,
-                forwarding_port,
-                req.path.unwrap()
-            ),
-        )
-        .body(body);
+    let mut request = hyper::Request::builder()
+        .method(method)
+        .uri(path)
+        // Keep an HTTP/1.0 browser's connection-close semantics on the upstream side
+        .version(match req.version {
+            Some(0) => hyper::Version::HTTP_10,
+            _ => hyper::Version::HTTP_11,
+        });
+    // Without either of these headers the request has no body, and attaching an
+    // empty stream would make it go out chunked
+    let has_body = req.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("content-length")
+            || h.name.eq_ignore_ascii_case("transfer-encoding")
+    });
+    // An upgrade has to reach the upstream with its Connection and Upgrade headers
+    let upgrade = req
+        .headers
+        .iter()
+        .any(|h| h.name.eq_ignore_ascii_case("upgrade"));
+    // The X-Forwarded-* headers are the server's, so a visitor can't have them
+    // dropped by naming them in Connection
+    let connection_headers: Vec<String> = req
+        .headers
+        .iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !FORWARDED_HEADERS.contains(&token.as_str()))
+        .collect();
     for header in headers {
+        let name = header.name.to_ascii_lowercase();
+        let upgrade_header = upgrade && (name == "connection" || name == "upgrade");
+        if (is_hop_by_hop(&name) || connection_headers.contains(&name)) && !upgrade_header {
+            continue;
+        }
+        // Left out, Host is filled in from the target
+        if header.name.eq_ignore_ascii_case("host")
+            && !matches!(upstream.host_header, HostHeader::Preserve)
+        {
+            continue;
+        }
         request = request.header(header.name, header.value);
     }
-    request.send().await.map_err(|e| e.to_string())
+    if let HostHeader::Rewrite(host) = &upstream.host_header {
+        request = request.header(hyper::header::HOST, host.clone());
+    }
+    let public = public_origin(req.headers);
+    let mut body = body.take_if(|_| has_body);
+    // Upgraded connections aren't mirrored, their body frames can only go one way
+    if let Some(mirror) = upstream.mirror.as_ref().filter(|_| !upgrade) {
+        let copy = body.take().map(|original| {
+            let (primary, copy) = tee_body(original);
+            body = Some(primary);
+            copy
+        });
+        mirror.send(&request, copy);
+    }
+    // Looked up after mirroring so the mirror still sees every request. Responses
+    // are cached before they're rewritten for the browser's host
+    let cache = upstream
+        .cache
+        .as_ref()
+        .zip(ResponseCache::key(method, path, req.headers).filter(|_| !has_body && !upgrade));
+    let mut response = match &cache {
+        Some((cache, key)) => match cache.get(key) {
+            Some(cached) => cached,
+            None => {
+                let response = upstream.send(request, body).await?;
+                cache
+                    .store(key.clone(), response)
+                    .await
+                    .map_err(TunnelError::Upstream)?
+            }
+        },
+        None => upstream.send(request, body).await?,
+    };
+    if let Some(public) = public {
+        upstream.rewrite_response(response.headers_mut(), route, &public);
+    }
+    Ok(response)
+}
+
+// The request target as a path and query to send the upstream. It always starts
+// with a slash, dot segments are resolved so path filters see what the upstream
+// will, and anything a URI can't carry is percent-encoded while existing escapes
+// are kept. Absolute-form targets keep only their path and query, and `*` is
+// only allowed for OPTIONS. The upstream is reached through a URL, which can't
+// carry an asterisk-form target, so `OPTIONS *` is asked of `/` instead
+fn normalize_path(method: &str, path: &str) -> Result<String, String> {
+    if path.chars().any(|c| c.is_control() || c == ' ') {
+        return Err(format!("invalid request path: {}", path.escape_debug()));
+    }
+    let path = match path {
+        "*" if method == "OPTIONS" => "/",
+        "*" => return Err(format!("{} * is not a valid request", method)),
+        _ if path.starts_with("http://") || path.starts_with("https://") => {
+            let url = Url::parse(path).map_err(|e| format!("invalid request URL: {}", e))?;
+            return Ok(path_and_query(&url));
+        }
+        _ => path,
+    };
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let mut url = Url::parse("http://localhost/").unwrap();
+    url.set_path(path);
+    url.set_query(query);
+    Ok(path_and_query(&url))
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    }
+}
+
+// `target` with a normalized request path and query added to its own path
+fn join_target(target: &Url, path: &str) -> Url {
+    let (path, query) = match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    };
+    let mut url = target.clone();
+    url.set_path(&format!("{}{}", target.path().trim_end_matches('/'), path));
+    url.set_query(query);
+    url
+}
+
+// Added by the server to every request, HTTPS when it terminated TLS itself
+const FORWARDED_HEADERS: [&str; 3] = ["x-forwarded-for", "x-forwarded-host", "x-forwarded-proto"];
+
+// Longest error or path logged for a failed request
+const MAX_LOGGED_LEN: usize = 512;
+
+// A request head's method and path, for logging before it's parsed properly
+fn request_line(head: &[u8]) -> (String, String) {
+    let line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split(' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    (
+        truncate(method.to_string(), MAX_LOGGED_LEN),
+        truncate(path.to_string(), MAX_LOGGED_LEN),
+    )
+}
+
+// Cuts `text` down to `max_len` bytes on a character boundary, marking the cut
+fn truncate(mut text: String, max_len: usize) -> String {
+    if text.len() > max_len {
+        let mut end = max_len;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str("...");
+    }
+    text
+}
+
+// Read from the X-Forwarded-Host and X-Forwarded-Proto the server adds, falling
+// back to the Host header
+fn public_origin(headers: &[httparse::Header]) -> Option<PublicOrigin> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+    let host = header("x-forwarded-host").or_else(|| header("host"))?;
+    Some(PublicOrigin {
+        scheme: header("x-forwarded-proto").unwrap_or("http").to_string(),
+        host: host.to_string(),
+    })
+}
+
+// Sends the response head followed by its body in chunks as they arrive, as
+// fast as the server's `window` lets it
+async fn forward_response(
+    id: u32,
+    mut response: reqwest::Response,
+    window: &Semaphore,
+    writer_sender: &UnboundedSender<Message>,
+) {
+    let _ = writer_sender.send(Message::Response {
+        id,
+        data: create_http_head(&response),
+    });
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                if !send_body(id, &chunk, window, writer_sender).await {
+                    return;
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                println!("Error: {}", e);
+                let _ = writer_sender.send(Message::Abort { id });
+                return;
+            }
+        }
+    }
+    let _ = writer_sender.send(Message::End { id });
 }
 
-async fn create_http_text(req: reqwest::Response) -> Vec<u8> {
+// Sends the 101 head then carries the raw bytes of the upgraded connection over
+// the tunnel until the upstream closes it, bytes from the server arrive on `body`
+async fn splice_upgrade(
+    id: u32,
+    response: reqwest::Response,
+    mut body: RequestBody,
+    window: &Semaphore,
+    writer_sender: &UnboundedSender<Message>,
+) {
+    let _ = writer_sender.send(Message::Response {
+        id,
+        data: create_http_head(&response),
+    });
+    let upgraded = match response.upgrade().await {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            println!("Error: {}", e);
+            let _ = writer_sender.send(Message::Abort { id });
+            return;
+        }
+    };
+    let (mut reader, mut writer) = tokio::io::split(upgraded);
+    tokio::spawn(async move {
+        while let Some(Ok(chunk)) = body.recv().await {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+    let mut buf = vec![0; 8192];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => {
+                if !send_body(id, &buf[..len], window, writer_sender).await {
+                    return;
+                }
+            }
+            Err(e) => {
+                println!("Error: {}", e);
+                let _ = writer_sender.send(Message::Abort { id });
+                return;
+            }
+        }
+    }
+    let _ = writer_sender.send(Message::End { id });
+}
+
+// Sends `data` as body frames once the server has room for each, false if it
+// aborted the response instead
+async fn send_body(
+    id: u32,
+    data: &[u8],
+    window: &Semaphore,
+    writer_sender: &UnboundedSender<Message>,
+) -> bool {
+    for data in data.chunks(MAX_BODY_CHUNK) {
+        match window.acquire_many(data.len() as u32).await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return false,
+        }
+        let _ = writer_sender.send(Message::Body {
+            id,
+            data: data.to_vec(),
+        });
+    }
+    true
+}
+
+fn send_error(id: u32, status: StatusCode, reason: &str, writer_sender: &UnboundedSender<Message>) {
+    send_error_with_headers(id, status, reason, &[], writer_sender);
+}
+
+fn send_error_with_headers(
+    id: u32,
+    status: StatusCode,
+    reason: &str,
+    headers: &[(&str, &str)],
+    writer_sender: &UnboundedSender<Message>,
+) {
+    let status_line = format!("{} {}", status.as_u16(), reason_phrase(status));
+    let body = format!("{}: {}", status_line, reason);
+    let mut head = format!(
+        "HTTP/1.1 {}\r\ncontent-type: text/plain\r\ncontent-length: {}\r\n",
+        status_line,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    let _ = writer_sender.send(Message::Response {
+        id,
+        data: head.into_bytes(),
+    });
+    let _ = writer_sender.send(Message::Body {
+        id,
+        data: body.into_bytes(),
+    });
+    let _ = writer_sender.send(Message::End { id });
+}
+
+fn create_http_head(res: &reqwest::Response) -> Vec<u8> {
     let mut text = vec![];
     text.extend_from_slice(
         format!(
             "HTTP/1.1 {} {}\r\n",
-            req.status().as_u16(),
-            req.status().canonical_reason().unwrap()
+            res.status().as_u16(),
+            reason_phrase(res.status())
         )
         .as_bytes(),
     );
-    for (key, value) in req.headers() {
-        text.extend_from_slice(format!("{}: {}\r\n", key, value.to_str().unwrap()).as_bytes());
+    // Values go through as bytes, an upstream can send ones that aren't ASCII
+    for (key, value) in res.headers() {
+        text.extend_from_slice(key.as_str().as_bytes());
+        text.extend_from_slice(b": ");
+        text.extend_from_slice(value.as_bytes());
+        text.extend_from_slice(b"\r\n");
     }
     text.extend_from_slice(b"\r\n");
-    text.extend_from_slice(&req.bytes().await.unwrap());
     text
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("/api/*", "/api/users/1"));
+        assert!(glob_match("*.json", "/data/a.json"));
+        assert!(glob_match("/a*b*c", "/abbbc"));
+        assert!(glob_match("/*", "/"));
+        assert!(glob_match("**", ""));
+        assert!(glob_match("/exact", "/exact"));
+        assert!(!glob_match("/exact", "/exact/more"));
+        assert!(!glob_match("/api/*", "/apiary"));
+        assert!(!glob_match("*.json", "/a.json.bak"));
+    }
+
+    #[test]
+    fn picks_the_longest_matching_prefix() {
+        let targets = Targets::new(
+            url("http://localhost:8000"),
+            vec![
+                ("/api".to_string(), url("http://localhost:3000")),
+                ("/api/admin".to_string(), url("http://localhost:4000")),
+            ],
+        );
+        assert_eq!(targets.pick("/api/admin/users").port(), Some(4000));
+        assert_eq!(targets.pick("/api/admin").port(), Some(4000));
+        assert_eq!(targets.pick("/api/users?admin").port(), Some(3000));
+        assert_eq!(targets.pick("/api").port(), Some(3000));
+    }
+
+    #[test]
+    fn only_routes_whole_segments() {
+        let targets = Targets::new(
+            url("http://localhost:8000"),
+            vec![
+                ("/api".to_string(), url("http://localhost:3000")),
+                ("/static/".to_string(), url("http://localhost:5000")),
+            ],
+        );
+        assert_eq!(targets.pick("/apiary").port(), Some(8000));
+        assert_eq!(targets.pick("/api?x=1").port(), Some(3000));
+        assert_eq!(targets.pick("/static/app.js").port(), Some(5000));
+        assert_eq!(targets.pick("/staticky").port(), Some(8000));
+        assert_eq!(targets.pick("/").port(), Some(8000));
+    }
+
+    fn public() -> PublicOrigin {
+        PublicOrigin {
+            scheme: "https".to_string(),
+            host: "foo.tunnel.test".to_string(),
+        }
+    }
+
+    #[test]
+    fn moves_local_redirects_to_the_public_origin() {
+        let local = url("http://localhost:3000/");
+        assert_eq!(
+            rewrite_location("http://localhost:3000/login?next=/a#top", &local, &public())
+                .as_deref(),
+            Some("https://foo.tunnel.test/login?next=/a#top")
+        );
+        assert_eq!(
+            rewrite_location("//localhost:3000/login", &local, &public()).as_deref(),
+            Some("//foo.tunnel.test/login")
+        );
+        // Default ports compare equal to none at all
+        let local = url("http://localhost/");
+        assert_eq!(
+            rewrite_location("http://localhost:80/", &local, &public()).as_deref(),
+            Some("https://foo.tunnel.test/")
+        );
+    }
+
+    #[test]
+    fn leaves_other_redirects_alone() {
+        let local = url("http://localhost:3000/");
+        for location in [
+            "https://example.com/login",
+            "//example.com/login",
+            "http://localhost:4000/login",
+            "/login",
+            "login",
+        ] {
+            assert_eq!(rewrite_location(location, &local, &public()), None);
+        }
+    }
+
+    #[test]
+    fn rewrites_cookie_domains_naming_the_local_host() {
+        assert_eq!(
+            rewrite_cookie_domain(
+                "a=b; Domain=.LocalHost; Path=/",
+                "localhost",
+                "foo.tunnel.test"
+            )
+            .as_deref(),
+            Some("a=b; Domain=foo.tunnel.test; Path=/")
+        );
+        assert_eq!(
+            rewrite_cookie_domain("a=b; Domain=example.com", "localhost", "foo.tunnel.test"),
+            None
+        );
+        assert_eq!(
+            rewrite_cookie_domain("a=b; Path=/", "localhost", "foo.tunnel.test"),
+            None
+        );
+    }
+
+    #[test]
+    fn normalizes_empty_paths_to_the_root() {
+        assert_eq!(normalize_path("GET", "").unwrap(), "/");
+    }
+
+    #[test]
+    fn asks_options_star_of_the_root() {
+        assert_eq!(normalize_path("OPTIONS", "*").unwrap(), "/");
+        assert!(normalize_path("GET", "*").is_err());
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        assert!(normalize_path("GET", "/a\nb").is_err());
+        assert!(normalize_path("GET", "/a\0b").is_err());
+        assert!(normalize_path("GET", "/a b").is_err());
+    }
+
+    #[test]
+    fn resolves_dot_segments() {
+        assert_eq!(normalize_path("GET", "/a/../b/./c").unwrap(), "/b/c");
+        assert_eq!(normalize_path("GET", "/../../etc").unwrap(), "/etc");
+    }
+
+    #[test]
+    fn keeps_only_the_path_of_absolute_targets() {
+        assert_eq!(
+            normalize_path("GET", "http://evil.test/a?b=c").unwrap(),
+            "/a?b=c"
+        );
+    }
+
+    #[test]
+    fn reads_json_start_responses() {
+        let body =
+            br#"{"service_id":"foo","url":"https://foo.tunnel.test","proxy":"tunnel.test:9000"}"#;
+        let (service_id, public, proxy) = parse_started(body, &url("https://tunnel.test")).unwrap();
+        assert_eq!(service_id, "foo");
+        assert_eq!(public, "https://foo.tunnel.test");
+        assert_eq!(proxy, "tunnel.test:9000");
+        assert!(parse_started(br#"{"service_id":"foo"}"#, &url("https://tunnel.test")).is_err());
+    }
+
+    #[test]
+    fn falls_back_to_bare_service_ids() {
+        let (service_id, public, proxy) =
+            parse_started(b"foo", &url("http://tunnel.test:8000")).unwrap();
+        assert_eq!(service_id, "foo");
+        assert_eq!(public, "http://foo.tunnel.test:8000");
+        assert_eq!(proxy, "tunnel.test:8080");
+        assert!(parse_started(b"", &url("http://tunnel.test")).is_err());
+    }
+
+    #[test]
+    fn keeps_the_targets_host_for_scheme_relative_paths() {
+        let target = Url::parse("http://localhost:3000").unwrap();
+        let path = normalize_path("GET", "//evil.test/a").unwrap();
+        let url = join_target(&target, &path);
+        assert_eq!(url.host_str(), Some("localhost"));
+        assert_eq!(url.port(), Some(3000));
+        assert_eq!(url.path(), "//evil.test/a");
+    }
+
+    #[test]
+    fn joins_encoded_paths_and_queries_under_the_targets_path() {
+        let target = Url::parse("http://localhost:3000/api/").unwrap();
+        let path = normalize_path("GET", "/caf%C3%A9/\u{e9}?q=a%20b&r").unwrap();
+        assert_eq!(path, "/caf%C3%A9/%C3%A9?q=a%20b&r");
+        assert_eq!(
+            join_target(&target, &path).as_str(),
+            "http://localhost:3000/api/caf%C3%A9/%C3%A9?q=a%20b&r"
+        );
+    }
+}
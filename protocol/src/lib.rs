@@ -0,0 +1,337 @@
+//! Wire protocol shared by the tunnel-ly client and server
+//!
+//! Every message on the primary stream is a frame: a 4 byte big-endian length
+//! followed by exactly that many bytes of payload.
+
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    writer.write_all(&len.to_be_bytes()).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await
+}
+
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    read_frame_max(reader, u32::MAX as usize).await
+}
+
+/// Reads a frame, failing without allocating if it's longer than `max_len`
+pub async fn read_frame_max<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32().await? as usize;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes exceeds limit of {}", len, max_len),
+        ));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(payload)
+}
+
+const INITIAL_HEADERS: usize = 64;
+/// Upper bound on headers in a single request or response, anything larger
+/// fails to parse with `httparse::Error::TooManyHeaders`
+pub const MAX_HEADERS: usize = 1024;
+
+/// Returns a header buffer large enough to parse the HTTP request in `buf`,
+/// doubling from 64 entries up to `MAX_HEADERS`
+pub fn request_header_buffer(buf: &[u8]) -> Vec<httparse::Header<'_>> {
+    let mut headers = vec![httparse::EMPTY_HEADER; INITIAL_HEADERS];
+    while headers.len() < MAX_HEADERS {
+        match httparse::Request::new(&mut headers).parse(buf) {
+            Err(httparse::Error::TooManyHeaders) => {
+                headers = vec![httparse::EMPTY_HEADER; headers.len() * 2];
+            }
+            _ => break,
+        }
+    }
+    headers
+}
+
+/// Returns a header buffer large enough to parse the HTTP response in `buf`,
+/// doubling from 64 entries up to `MAX_HEADERS`
+pub fn response_header_buffer(buf: &[u8]) -> Vec<httparse::Header<'_>> {
+    let mut headers = vec![httparse::EMPTY_HEADER; INITIAL_HEADERS];
+    while headers.len() < MAX_HEADERS {
+        match httparse::Response::new(&mut headers).parse(buf) {
+            Err(httparse::Error::TooManyHeaders) => {
+                headers = vec![httparse::EMPTY_HEADER; headers.len() * 2];
+            }
+            _ => break,
+        }
+    }
+    headers
+}
+
+// Headers describing a single HTTP connection (RFC 7230 section 6.1)
+const HOP_BY_HOP: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Whether a header only applies to one connection, these aren't passed across
+/// the tunnel so each side's HTTP stack frames bodies and keeps connections
+/// alive on its own terms
+pub fn is_hop_by_hop(name: &str) -> bool {
+    HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
+}
+
+const REQUEST: u8 = 0;
+const RESPONSE: u8 = 1;
+const BODY: u8 = 2;
+const END: u8 = 3;
+const ABORT: u8 = 4;
+const PING: u8 = 5;
+const PONG: u8 = 6;
+const SHUTDOWN: u8 = 7;
+const WINDOW: u8 = 8;
+
+/// Body bytes either side may send for an id before the other grants it more
+/// with [`Message::Window`]
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// Longest body chunk sent in one message, a fraction of the window so the next
+/// one can be on its way while the last is taken
+pub const MAX_BODY_CHUNK: usize = INITIAL_WINDOW as usize / 4;
+
+/// A message multiplexed over the primary stream, tagged with the id of the
+/// request it belongs to so many requests can be in flight at once
+///
+/// A request or response is sent as its head followed by any number of `Body`
+/// chunks, then either `End` or `Abort`, so neither side has to buffer a whole
+/// payload. Bodies are flow controlled per id, so one that's read slowly only
+/// holds up itself
+#[derive(Debug)]
+pub enum Message {
+    /// Head of an HTTP request sent from the server to the client
+    Request {
+        id: u32,
+        data: Vec<u8>,
+    },
+    /// Head of an HTTP response sent from the client back to the server
+    Response {
+        id: u32,
+        data: Vec<u8>,
+    },
+    /// Chunk of the body following a request or response head
+    Body {
+        id: u32,
+        data: Vec<u8>,
+    },
+    /// The body for this id is complete
+    End {
+        id: u32,
+    },
+    /// The body for this id failed part way through and should be discarded
+    Abort {
+        id: u32,
+    },
+    /// Heartbeat from the server, answered with a `Pong` carrying the same id
+    Ping {
+        id: u32,
+    },
+    Pong {
+        id: u32,
+    },
+    /// The server is going away, sent once it stops taking new requests so the
+    /// client can finish what's in flight and reconnect later
+    Shutdown,
+    /// Lets the other side send `bytes` more of this id's body, sent as what
+    /// it already sent is passed on. Starts out at [`INITIAL_WINDOW`]
+    Window {
+        id: u32,
+        bytes: u32,
+    },
+}
+
+impl Message {
+    /// The bytes carried by this message, empty for anything but heads and bodies
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Message::Request { data, .. }
+            | Message::Response { data, .. }
+            | Message::Body { data, .. } => data,
+            Message::End { .. }
+            | Message::Abort { .. }
+            | Message::Ping { .. }
+            | Message::Pong { .. }
+            | Message::Shutdown
+            | Message::Window { .. } => &[],
+        }
+    }
+
+    fn decode(payload: Vec<u8>) -> io::Result<Message> {
+        if payload.len() < 5 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message shorter than its header",
+            ));
+        }
+        let id = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+        let data = payload[5..].to_vec();
+        match payload[0] {
+            REQUEST => Ok(Message::Request { id, data }),
+            RESPONSE => Ok(Message::Response { id, data }),
+            BODY => Ok(Message::Body { id, data }),
+            END => Ok(Message::End { id }),
+            ABORT => Ok(Message::Abort { id }),
+            PING => Ok(Message::Ping { id }),
+            PONG => Ok(Message::Pong { id }),
+            SHUTDOWN => Ok(Message::Shutdown),
+            WINDOW => match <[u8; 4]>::try_from(data) {
+                Ok(bytes) => Ok(Message::Window {
+                    id,
+                    bytes: u32::from_be_bytes(bytes),
+                }),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "window message without a 4 byte size",
+                )),
+            },
+            kind => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown message kind: {}", kind),
+            )),
+        }
+    }
+}
+
+/// Describes `message` for debugging, showing at most `max_len` bytes of its
+/// data. Heads are shown as text with anything unprintable escaped and bodies
+/// as a hex dump, so binary data can't garble the output
+pub fn dump(message: &Message, max_len: usize) -> String {
+    let (kind, id) = match message {
+        Message::Request { id, .. } => ("request", id),
+        Message::Response { id, .. } => ("response", id),
+        Message::Body { id, .. } => ("body", id),
+        Message::End { id } => ("end", id),
+        Message::Abort { id } => ("abort", id),
+        Message::Ping { id } => ("ping", id),
+        Message::Pong { id } => ("pong", id),
+        Message::Shutdown => return "shutdown".to_string(),
+        Message::Window { id, bytes } => return format!("window {} +{}", id, bytes),
+    };
+    let data = message.data();
+    let mut out = format!("{} {}", kind, id);
+    if data.is_empty() {
+        return out;
+    }
+    let shown = &data[..data.len().min(max_len)];
+    out.push_str(&format!(" ({} bytes)", data.len()));
+    if let Message::Body { .. } = message {
+        for (i, line) in shown.chunks(16).enumerate() {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = line
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            out.push_str(&format!(
+                "\n{:08x}  {:<47}  |{}|",
+                i * 16,
+                hex.join(" "),
+                text
+            ));
+        }
+    } else {
+        for line in shown.split_inclusive(|&byte| byte == b'\n') {
+            out.push_str(&format!("\n{}", line.escape_ascii()));
+        }
+    }
+    if shown.len() < data.len() {
+        out.push_str(&format!("\n... {} more bytes", data.len() - shown.len()));
+    }
+    out
+}
+
+/// Writes a message without flushing, so a buffered writer can take a run of
+/// them before it's flushed once. The header and payload are separate writes,
+/// so a writer that fails part way leaves the stream in the middle of a frame
+/// and it can't be used again
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> io::Result<()> {
+    let window;
+    let (kind, id, data): (u8, &u32, &[u8]) = match message {
+        Message::Request { id, data } => (REQUEST, id, data),
+        Message::Response { id, data } => (RESPONSE, id, data),
+        Message::Body { id, data } => (BODY, id, data),
+        Message::End { id } => (END, id, &[]),
+        Message::Abort { id } => (ABORT, id, &[]),
+        Message::Ping { id } => (PING, id, &[]),
+        Message::Pong { id } => (PONG, id, &[]),
+        Message::Shutdown => (SHUTDOWN, &0, &[]),
+        Message::Window { id, bytes } => {
+            window = bytes.to_be_bytes();
+            (WINDOW, id, &window)
+        }
+    };
+    let len = u32::try_from(5 + data.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large"))?;
+    let mut header = [0; 9];
+    header[..4].copy_from_slice(&len.to_be_bytes());
+    header[4] = kind;
+    header[5..].copy_from_slice(&id.to_be_bytes());
+    writer.write_all(&header).await?;
+    writer.write_all(data).await
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+    Message::decode(read_frame(reader).await?)
+}
+
+/// Limit on messages read with [`read_message_max`] unless another is given,
+/// well over the largest head or body chunk either side's HTTP stack produces
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+/// A message [`read_message_max`] skipped over rather than read into memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Oversized {
+    pub id: u32,
+    /// Whether it was a request or response head rather than a body chunk
+    pub head: bool,
+    pub len: usize,
+}
+
+/// Reads a message, skipping over one longer than `max_len` bytes without
+/// allocating for it so the stream stays usable
+pub async fn read_message_max<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> io::Result<Result<Message, Oversized>> {
+    let len = reader.read_u32().await? as usize;
+    if len <= max_len || len < 5 {
+        let mut payload = vec![0; len];
+        reader.read_exact(&mut payload).await?;
+        return Message::decode(payload).map(Ok);
+    }
+    let kind = reader.read_u8().await?;
+    let id = reader.read_u32().await?;
+    let skip = (len - 5) as u64;
+    if tokio::io::copy(&mut reader.take(skip), &mut tokio::io::sink()).await? < skip {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Err(Oversized {
+        id,
+        head: kind == REQUEST || kind == RESPONSE,
+        len,
+    }))
+}
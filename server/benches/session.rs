@@ -0,0 +1,174 @@
+//! Requests pushed through a tunnel's session to a client answering over an
+//! in-memory pipe, so only the server's own work is measured
+//!
+//! Run with `cargo bench -p server`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use protocol::{read_message, write_message, Message, INITIAL_WINDOW};
+use ring::hmac;
+use server::{PrimaryStream, ServiceManagerMessage, TunnelServer};
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::{duplex, split, DuplexStream};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{unbounded_channel, Sender, UnboundedSender};
+use tokio::sync::{oneshot, Semaphore};
+use tokio::time::sleep;
+
+const DOMAIN: &str = "bench.test";
+const SERVICE_ID: &str = "bench";
+
+// Response body sizes to measure
+const SIZES: [usize; 3] = [0, 16 * 1024, 1024 * 1024];
+
+// An address on localhost nothing is listening on yet
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+// Starts a server, opens a tunnel on it and hands its session one end of a
+// pipe, with a client on the other end answering every request with `body_len`
+// bytes
+async fn start_tunnel(body_len: usize) -> Sender<ServiceManagerMessage> {
+    let http_addr = free_addr();
+    let server = TunnelServer::builder()
+        .http_addr(http_addr)
+        .proxy_addr(free_addr())
+        .domain(DOMAIN)
+        .build();
+    let service_mgr = server.service_manager();
+    tokio::spawn(server.run());
+
+    let client = Client::new();
+    let secret = loop {
+        let start = Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/start", http_addr))
+            .header(hyper::header::HOST, DOMAIN)
+            .body(Body::from(SERVICE_ID))
+            .unwrap();
+        match client.request(start).await {
+            Ok(response) if response.status() == StatusCode::OK => {
+                break response.headers()["x-handshake-secret"].clone();
+            }
+            Ok(response) => panic!("failed to start tunnel: {}", response.status()),
+            // Still binding
+            Err(_) => sleep(Duration::from_millis(10)).await,
+        }
+    };
+
+    let (stream, client) = duplex(64 * 1024);
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let (verified_sender, verified) = oneshot::channel();
+    service_mgr
+        .send(ServiceManagerMessage::ForwardPrimaryStream {
+            service_id: SERVICE_ID.to_string(),
+            signature: hmac::sign(&key, SERVICE_ID.as_bytes()).as_ref().to_vec(),
+            stream: PrimaryStream::new(stream),
+            verified: verified_sender,
+        })
+        .await
+        .unwrap();
+    assert!(verified.await.unwrap());
+    tokio::spawn(answer_requests(client, body_len));
+    service_mgr
+}
+
+async fn answer_requests(client: DuplexStream, body_len: usize) {
+    let (mut reader, mut writer) = split(client);
+    let (writer_sender, mut writer_receiver) = unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(message) = writer_receiver.recv().await {
+            if write_message(&mut writer, &message).await.is_err() {
+                return;
+            }
+        }
+    });
+    let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body_len).into_bytes();
+    let body = Arc::new(vec![b'x'; body_len]);
+    // Each response's window, gone once its body's been sent
+    let mut windows: HashMap<u32, Weak<Semaphore>> = HashMap::new();
+    while let Ok(message) = read_message(&mut reader).await {
+        match message {
+            Message::Request { id, .. } => {
+                let window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+                windows.retain(|_, window| window.strong_count() > 0);
+                windows.insert(id, Arc::downgrade(&window));
+                let data = head.clone();
+                let _ = writer_sender.send(Message::Response { id, data });
+                tokio::spawn(respond(id, body.clone(), window, writer_sender.clone()));
+            }
+            Message::Window { id, bytes } => {
+                if let Some(window) = windows.get(&id).and_then(Weak::upgrade) {
+                    window.add_permits(bytes as usize);
+                }
+            }
+            Message::Ping { id } => {
+                let _ = writer_sender.send(Message::Pong { id });
+            }
+            _ => {}
+        }
+    }
+}
+
+// Chunked like a client reading from its upstream would
+async fn respond(
+    id: u32,
+    body: Arc<Vec<u8>>,
+    window: Arc<Semaphore>,
+    writer_sender: UnboundedSender<Message>,
+) {
+    for chunk in body.chunks(16 * 1024) {
+        window
+            .acquire_many(chunk.len() as u32)
+            .await
+            .unwrap()
+            .forget();
+        let data = chunk.to_vec();
+        let _ = writer_sender.send(Message::Body { id, data });
+    }
+    let _ = writer_sender.send(Message::End { id });
+}
+
+async fn send_request(service_mgr: &Sender<ServiceManagerMessage>) {
+    let (response_sender, mut response_receiver) = unbounded_channel();
+    let request = Request::builder()
+        .uri("/")
+        .header(hyper::header::HOST, format!("{}.{}", SERVICE_ID, DOMAIN))
+        .body(Body::empty())
+        .unwrap();
+    service_mgr
+        .send(ServiceManagerMessage::ForwardRequest {
+            request,
+            response_sender,
+        })
+        .await
+        .unwrap();
+    let response = response_receiver.recv().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+}
+
+fn session(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("session");
+    for body_len in SIZES {
+        let service_mgr = runtime.block_on(start_tunnel(body_len));
+        group.throughput(Throughput::Bytes(body_len as u64));
+        group.bench_with_input(
+            BenchmarkId::new("response", body_len),
+            &service_mgr,
+            |b, service_mgr| b.to_async(&runtime).iter(|| send_request(service_mgr)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, session);
+criterion_main!(benches);
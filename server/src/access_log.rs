@@ -0,0 +1,233 @@
+//! Access log lines for forwarded requests in the Common or Combined Log Format
+//!
+//! Each line starts with the tunnel's service id as a virtual host field, the
+//! way Apache's `vhost_combined` format does, followed by the usual fields.
+
+use hyper::header::{REFERER, USER_AGENT};
+use hyper::{Body, Request, StatusCode};
+use log::warn;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `host ident user [time] "request" status bytes`
+    Common,
+    /// Common plus the quoted Referer and User-Agent
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err("expected common or combined".to_string()),
+        }
+    }
+}
+
+pub(crate) struct AccessLog {
+    format: AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AccessLog {
+    pub fn new(out: Box<dyn Write + Send>, format: AccessLogFormat) -> Self {
+        AccessLog {
+            format,
+            out: Mutex::new(out),
+        }
+    }
+
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|_| out.flush()) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// What's known about a request for its access log line, which is written once
+/// this is dropped
+#[derive(Debug)]
+pub(crate) struct AccessEntry {
+    log: Arc<AccessLog>,
+    service_id: String,
+    remote: String,
+    time: SystemTime,
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// Until a response is recorded the request counts as failed
+    pub status: StatusCode,
+    pub bytes: u64,
+}
+
+impl AccessEntry {
+    pub fn new(log: Arc<AccessLog>, service_id: &str, req: &Request<Body>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        // The last X-Forwarded-For entry is the peer the server accepted
+        let remote = header(crate::X_FORWARDED_FOR)
+            .and_then(|forwarded| forwarded.rsplit(',').next().map(|ip| ip.trim().to_string()))
+            .unwrap_or_else(|| "-".to_string());
+        AccessEntry {
+            log,
+            service_id: service_id.to_string(),
+            remote,
+            time: SystemTime::now(),
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            referer: header(REFERER.as_str()),
+            user_agent: header(USER_AGENT.as_str()),
+            status: StatusCode::BAD_GATEWAY,
+            bytes: 0,
+        }
+    }
+
+    fn line(&self) -> String {
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let mut line = format!(
+            "{} {} - - [{}] \"{}\" {} {}",
+            self.service_id,
+            self.remote,
+            clf_time(self.time),
+            self.request_line,
+            self.status.as_u16(),
+            bytes
+        );
+        if self.log.format == AccessLogFormat::Combined {
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                quoted(self.referer.as_deref()),
+                quoted(self.user_agent.as_deref())
+            ));
+        }
+        line.push('\n');
+        line
+    }
+}
+
+impl Drop for AccessEntry {
+    fn drop(&mut self) {
+        self.log.write(&self.line());
+    }
+}
+
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(value) => value.replace('\\', "\\\\").replace('"', "\\\""),
+        None => "-".to_string(),
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// e.g. 10/Oct/2000:13:55:36 +0000, always in UTC
+fn clf_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+// Year, month and day of a count of days since 1970-01-01, from Howard
+// Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Collects everything written so tests can read the lines back
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn formats_clf_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_164_800);
+        assert_eq!(clf_time(leap_day), "29/Feb/2024:00:00:00 +0000");
+    }
+
+    #[test]
+    fn writes_combined_line_on_drop() {
+        let captured = Captured::default();
+        let log = Arc::new(AccessLog::new(
+            Box::new(captured.clone()),
+            AccessLogFormat::Combined,
+        ));
+        let req = Request::builder()
+            .uri("/index.html?q=1")
+            .header(crate::X_FORWARDED_FOR, "10.0.0.1, 192.0.2.7")
+            .header(USER_AGENT, "curl/8.0 \"test\"")
+            .body(Body::empty())
+            .unwrap();
+        let mut entry = AccessEntry::new(log, "foo", &req);
+        entry.time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        entry.status = StatusCode::OK;
+        entry.bytes = 2326;
+        drop(entry);
+        assert_eq!(
+            String::from_utf8(captured.0.lock().unwrap().clone()).unwrap(),
+            "foo 192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html?q=1 HTTP/1.1\" \
+             200 2326 \"-\" \"curl/8.0 \\\"test\\\"\"\n"
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Optional gzip/deflate compression of tunneled responses
+//!
+//! Bodies are compressed as they stream in, with a sync flush after every chunk
+//! so streamed responses still reach the browser as they're produced.
+
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use hyper::header::{HeaderMap, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::StatusCode;
+use std::io::{self, Write};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio::task;
+
+// Bodies known to be smaller than this aren't worth compressing
+const MIN_LENGTH: u64 = 256;
+
+// Content types that are already compressed
+const COMPRESSED_TYPES: [&str; 10] = [
+    "image/",
+    "video/",
+    "audio/",
+    "font/woff",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-7z-compressed",
+    "application/octet-stream",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Picks an encoding the request accepts, preferring gzip
+    pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+        let mut deflate = false;
+        for value in headers.get_all(ACCEPT_ENCODING) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for coding in value.split(',') {
+                let mut params = coding.split(';');
+                let name = params.next().unwrap_or_default().trim();
+                let refused = params.any(|param| {
+                    param
+                        .trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        == Some(0.0)
+                });
+                if refused {
+                    continue;
+                }
+                if name.eq_ignore_ascii_case("gzip") {
+                    return Some(Encoding::Gzip);
+                }
+                deflate |= name.eq_ignore_ascii_case("deflate");
+            }
+        }
+        deflate.then_some(Encoding::Deflate)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Whether a response with these headers should be compressed
+pub fn should_compress(status: StatusCode, headers: &HeaderMap) -> bool {
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || headers.contains_key(CONTENT_ENCODING)
+    {
+        return false;
+    }
+    let short = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .is_some_and(|len| len < MIN_LENGTH);
+    let compressed = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            let value = value.to_ascii_lowercase();
+            // SVG is text even though it's an image
+            !value.starts_with("image/svg+xml")
+                && COMPRESSED_TYPES.iter().any(|ty| value.starts_with(ty))
+        });
+    !short && !compressed
+}
+
+/// Returns a sender whose chunks are compressed before being passed on to
+/// `chunk_sender`, with the same `None` terminated protocol. Up to `capacity`
+/// chunks wait to be compressed while `chunk_sender` is full
+pub fn compressed(
+    encoding: Encoding,
+    chunk_sender: Sender<Option<Vec<u8>>>,
+    capacity: usize,
+) -> Sender<Option<Vec<u8>>> {
+    let (sender, mut receiver) = channel::<Option<Vec<u8>>>(capacity);
+    task::spawn(async move {
+        let mut encoder = Encoder::new(encoding);
+        // A closed channel without a `None` drops `chunk_sender`, aborting the body
+        while let Some(chunk) = receiver.recv().await {
+            let result = match chunk {
+                Some(chunk) => encoder.write(&chunk),
+                None => {
+                    if let Ok(rest) = encoder.finish() {
+                        let _ = chunk_sender.send(Some(rest)).await;
+                        let _ = chunk_sender.send(None).await;
+                    }
+                    return;
+                }
+            };
+            match result {
+                Ok(data) if data.is_empty() => {}
+                Ok(data) => {
+                    if chunk_sender.send(Some(data)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => return,
+            }
+        }
+    });
+    sender
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(vec![], Compression::default())),
+            Encoding::Deflate => Encoder::Deflate(ZlibEncoder::new(vec![], Compression::default())),
+        }
+    }
+
+    // Compresses and flushes `chunk`, returning the output produced so far
+    fn write(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.flush()?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use hyper::header::HeaderValue;
+    use std::io::Read;
+
+    #[test]
+    fn negotiates_accepted_encoding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Encoding::negotiate(&headers), None);
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("deflate, gzip;q=0.5"),
+        );
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Gzip));
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip;q=0, deflate"),
+        );
+        assert_eq!(Encoding::negotiate(&headers), Some(Encoding::Deflate));
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("br"));
+        assert_eq!(Encoding::negotiate(&headers), None);
+    }
+
+    #[test]
+    fn skips_compressed_content() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        assert!(should_compress(StatusCode::OK, &headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert!(!should_compress(StatusCode::OK, &headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/svg+xml"));
+        assert!(should_compress(StatusCode::OK, &headers));
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("br"));
+        assert!(!should_compress(StatusCode::OK, &headers));
+    }
+
+    #[tokio::test]
+    async fn compresses_streamed_chunks() {
+        let (chunk_sender, mut chunk_receiver) = channel(1);
+        let sender = compressed(Encoding::Gzip, chunk_sender, 1);
+        sender.send(Some(b"hello ".to_vec())).await.unwrap();
+        sender.send(Some(b"world".to_vec())).await.unwrap();
+        sender.send(None).await.unwrap();
+
+        let mut body = vec![];
+        while let Some(Some(chunk)) = chunk_receiver.recv().await {
+            body.extend(chunk);
+        }
+        let mut text = String::new();
+        GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "hello world");
+    }
+}
@@ -0,0 +1,272 @@
+//! Settings read from a `--config` file
+//!
+//! The file is TOML whose keys are the long flag names with underscores, e.g.
+//! `http_addr = "[::]:80"`, `compress = true` or
+//! `response_header = ["Via: tunnel-ly"]`. Flags given on the command line win
+//! over the file, except repeatable ones, whose values are added to the file's.
+
+use super::{parse_header, parse_header_value, Args};
+use crate::logging::LogFormat;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, ValueEnum};
+use serde::Deserialize;
+use server::{AccessLogFormat, IdScheme};
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// The flags a config file can set, values that need parsing like they would be
+/// on the command line are kept as strings until they're merged
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    http_addr: Option<SocketAddr>,
+    proxy_addr: Option<SocketAddr>,
+    domain: Option<String>,
+    request_timeout: Option<u64>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_timeout: Option<u64>,
+    compress: Option<bool>,
+    request_id_header: Option<bool>,
+    buffer_size: Option<usize>,
+    max_message_bytes: Option<usize>,
+    dump_messages: Option<usize>,
+    max_body_bytes: Option<u64>,
+    max_in_flight: Option<usize>,
+    queue_timeout: Option<u64>,
+    bandwidth_limit: Option<u64>,
+    idle_timeout: Option<u64>,
+    channel_capacity: Option<usize>,
+    id_scheme: Option<String>,
+    key_length: Option<usize>,
+    key_vowels: Option<String>,
+    key_consonants: Option<String>,
+    auth_token: Option<String>,
+    reserved_subdomains: Option<Vec<String>>,
+    max_tunnels: Option<usize>,
+    start_retry_after: Option<u64>,
+    wildcard_subdomains: Option<bool>,
+    reclaim_ttl: Option<u64>,
+    offline_grace: Option<u64>,
+    load_balance: Option<bool>,
+    start_rate_limit: Option<u32>,
+    accept_proxy_protocol: Option<bool>,
+    max_proxy_connections: Option<usize>,
+    allow_cidr: Vec<String>,
+    deny_cidr: Vec<String>,
+    response_header: Vec<String>,
+    force_response_headers: Option<bool>,
+    cors: Vec<String>,
+    cors_methods: Option<String>,
+    cors_headers: Option<String>,
+    error_pages: Option<PathBuf>,
+    landing_page: Option<PathBuf>,
+    offline_page: Option<PathBuf>,
+    access_log: Option<PathBuf>,
+    access_log_format: Option<String>,
+    log_format: Option<String>,
+    shutdown_grace_period: Option<u64>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    proxy_tls_cert: Option<PathBuf>,
+    proxy_tls_key: Option<PathBuf>,
+    check: Option<bool>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Sets each flag of `args` the file has a value for, unless `matches` shows
+    /// it was given on the command line
+    pub fn merge_into(self, args: &mut Args, matches: &ArgMatches) -> Result<(), String> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {
+                merge!($($field = self.$field),*)
+            };
+            ($($field:ident = $value:expr),* $(,)?) => {$(
+                if let Some(value) = $value {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value.into();
+                    }
+                }
+            )*};
+        }
+        merge!(
+            http_addr,
+            proxy_addr,
+            domain,
+            request_timeout,
+            heartbeat_interval,
+            heartbeat_timeout,
+            compress,
+            request_id_header,
+            buffer_size,
+            max_message_bytes,
+            dump_messages,
+            max_body_bytes,
+            max_in_flight,
+            queue_timeout,
+            bandwidth_limit,
+            idle_timeout,
+            channel_capacity,
+            key_length,
+            key_vowels,
+            key_consonants,
+            auth_token,
+            reserved_subdomains,
+            max_tunnels,
+            start_retry_after,
+            wildcard_subdomains,
+            reclaim_ttl,
+            offline_grace,
+            load_balance,
+            start_rate_limit,
+            accept_proxy_protocol,
+            max_proxy_connections,
+            force_response_headers,
+            error_pages,
+            landing_page,
+            offline_page,
+            access_log,
+            shutdown_grace_period,
+            tls_cert,
+            tls_key,
+            proxy_tls_cert,
+            proxy_tls_key,
+            check,
+        );
+
+        merge!(
+            id_scheme = parse("id_scheme", self.id_scheme, str::parse::<IdScheme>)?,
+            cors_methods = parse("cors_methods", self.cors_methods, parse_header_value)?,
+            cors_headers = parse("cors_headers", self.cors_headers, parse_header_value)?,
+            access_log_format = parse(
+                "access_log_format",
+                self.access_log_format,
+                str::parse::<AccessLogFormat>,
+            )?,
+            log_format = parse("log_format", self.log_format, |s| {
+                LogFormat::from_str(s, false)
+            })?,
+        );
+
+        // Lists from the file come first, the command line adds to them
+        let lists = (
+            parse_all("allow_cidr", self.allow_cidr, str::parse)?,
+            parse_all("deny_cidr", self.deny_cidr, str::parse)?,
+            parse_all("response_header", self.response_header, parse_header)?,
+        );
+        args.allow_cidr.splice(0..0, lists.0);
+        args.deny_cidr.splice(0..0, lists.1);
+        args.response_header.splice(0..0, lists.2);
+        args.cors.splice(0..0, self.cors);
+        Ok(())
+    }
+}
+
+// A string value parsed like the flag named `key` would be
+fn parse<T, E: Display>(
+    key: &str,
+    value: Option<String>,
+    parser: impl Fn(&str) -> Result<T, E>,
+) -> Result<Option<T>, String> {
+    value
+        .map(|value| parser(&value).map_err(|e| format!("invalid {}: {}", key, e)))
+        .transpose()
+}
+
+fn parse_all<T, E: Display>(
+    key: &str,
+    values: Vec<String>,
+    parser: impl Fn(&str) -> Result<T, E>,
+) -> Result<Vec<T>, String> {
+    values
+        .into_iter()
+        .map(|value| parser(&value).map_err(|e| format!("invalid {}: {}", key, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    fn merged(cli: &[&str], file: &str) -> Result<Args, String> {
+        let matches = Args::command().try_get_matches_from(cli).unwrap();
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        let config: Config = toml::from_str(file).map_err(|e| e.to_string())?;
+        config.merge_into(&mut args, &matches)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn fills_in_flags_missing_from_the_command_line() {
+        let file = r#"
+            # Public listener
+            http_addr = "[::]:80"
+            request_timeout = 10
+            compress = true
+            max_tunnels = 5
+            id_scheme = "uuid"
+            reserved_subdomains = ["admin"]
+            response_header = ["Via: tunnel-ly", 'X-Note: a # b']
+            auth_token = "say \"please\"" # quoted
+        "#;
+        let args = merged(&["server"], file).unwrap();
+        assert_eq!(args.http_addr, "[::]:80".parse().unwrap());
+        assert_eq!(args.request_timeout, 10);
+        assert!(args.compress);
+        assert_eq!(args.max_tunnels, Some(5));
+        assert_eq!(args.id_scheme, IdScheme::Uuid);
+        assert_eq!(args.reserved_subdomains, ["admin"]);
+        assert_eq!(args.response_header.len(), 2);
+        assert_eq!(args.response_header[1].1, "a # b");
+        assert_eq!(args.auth_token.as_deref(), Some("say \"please\""));
+        // Untouched by the file
+        assert_eq!(args.domain, "rachel.test");
+    }
+
+    #[test]
+    fn lets_the_command_line_win() {
+        let file = r#"
+            domain = "file.test"
+            request_timeout = 10
+            compress = false
+            allow_cidr = ["10.0.0.0/8"]
+        "#;
+        let cli = [
+            "server",
+            "--domain",
+            "cli.test",
+            "--compress",
+            "--allow-cidr",
+            "192.168.0.0/16",
+        ];
+        let args = merged(&cli, file).unwrap();
+        assert_eq!(args.domain, "cli.test");
+        assert_eq!(args.request_timeout, 10);
+        assert!(args.compress);
+        assert_eq!(
+            args.allow_cidr,
+            [
+                "10.0.0.0/8".parse().unwrap(),
+                "192.168.0.0/16".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        assert!(merged(&["server"], "[server]\ndomain = \"a.test\"").is_err());
+        assert!(merged(&["server"], "domain = rachel test").is_err());
+        assert!(merged(&["server"], "config = \"other.toml\"").is_err());
+        assert!(merged(&["server"], "request_timeout = \"soon\"").is_err());
+        assert!(merged(&["server"], "id_scheme = \"words\"").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Optional CORS headers on tunneled responses
+//!
+//! Browsers only let a page read a cross-origin response that names its origin
+//! in Access-Control-Allow-Origin. For origins on the allowlist a session answers
+//! preflight requests itself, and adds the headers to responses whose upstream
+//! didn't set its own.
+
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+/// Methods allowed unless others are given
+pub const DEFAULT_METHODS: &str = "GET, POST, PUT, PATCH, DELETE, OPTIONS";
+
+#[derive(Debug, Clone)]
+pub(crate) struct Cors {
+    /// Origins like `https://app.example.com`, or `*` for any
+    pub origins: Vec<String>,
+    pub methods: HeaderValue,
+    pub headers: HeaderValue,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            origins: vec![],
+            methods: HeaderValue::from_static(DEFAULT_METHODS),
+            headers: HeaderValue::from_static("*"),
+        }
+    }
+}
+
+impl Cors {
+    /// The Access-Control-Allow-Origin to answer a request with, or None if it
+    /// has no Origin or it isn't allowed
+    pub fn allow_origin(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(ORIGIN)?;
+        let allowed = self.origins.iter().any(|allowed| {
+            allowed == "*"
+                || origin
+                    .to_str()
+                    .is_ok_and(|origin| origin.eq_ignore_ascii_case(allowed.trim_end_matches('/')))
+        });
+        if !allowed {
+            return None;
+        }
+        // A wildcard can be answered the same for everyone and cached as such
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            Some(origin.clone())
+        }
+    }
+
+    pub fn is_preflight(req: &Request<Body>) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(ORIGIN)
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    pub fn preflight(&self, origin: HeaderValue) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        self.apply(response.headers_mut(), origin);
+        response
+    }
+
+    /// Adds the CORS headers, unless the upstream already answered with its own
+    pub fn apply(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        if headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return;
+        }
+        if origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers
+            .entry(ACCESS_CONTROL_ALLOW_METHODS)
+            .or_insert_with(|| self.methods.clone());
+        headers
+            .entry(ACCESS_CONTROL_ALLOW_HEADERS)
+            .or_insert_with(|| self.headers.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_origin(origin: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_str(origin).unwrap());
+        headers
+    }
+
+    #[test]
+    fn only_allows_listed_origins() {
+        let cors = Cors {
+            origins: vec!["https://app.example.com/".to_string()],
+            ..Cors::default()
+        };
+        assert_eq!(
+            cors.allow_origin(&with_origin("https://app.example.com"))
+                .unwrap(),
+            "https://app.example.com"
+        );
+        assert!(cors
+            .allow_origin(&with_origin("https://evil.example.com"))
+            .is_none());
+        assert!(cors.allow_origin(&HeaderMap::new()).is_none());
+
+        let any = Cors {
+            origins: vec!["*".to_string()],
+            ..Cors::default()
+        };
+        assert_eq!(
+            any.allow_origin(&with_origin("https://evil.example.com"))
+                .unwrap(),
+            "*"
+        );
+    }
+
+    #[test]
+    fn keeps_upstream_headers() {
+        let cors = Cors::default();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static("https://other.example.com"),
+        );
+        cors.apply(
+            &mut headers,
+            HeaderValue::from_static("https://app.example.com"),
+        );
+        assert_eq!(headers.len(), 1);
+        assert_eq!(
+            headers[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://other.example.com"
+        );
+    }
+}
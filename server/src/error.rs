@@ -0,0 +1,59 @@
+//! Errors [`TunnelServer`](crate::TunnelServer) returns to whoever embeds it
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum TunnelError {
+    /// A setting or file the server couldn't use, such as an empty key alphabet
+    /// or a certificate without a private key
+    Parse(String),
+    /// Reading a file or binding a listener failed
+    Io(io::Error),
+    /// In-flight requests outlasted the shutdown grace period
+    Timeout(Duration),
+}
+
+impl TunnelError {
+    // Says what was being done when the error happened
+    pub(crate) fn context(self, what: &str) -> Self {
+        match self {
+            TunnelError::Parse(message) => TunnelError::Parse(format!("{}: {}", what, message)),
+            TunnelError::Io(e) => {
+                TunnelError::Io(io::Error::new(e.kind(), format!("{}: {}", what, e)))
+            }
+            timeout => timeout,
+        }
+    }
+}
+
+impl fmt::Display for TunnelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelError::Parse(message) => f.write_str(message),
+            TunnelError::Io(e) => e.fmt(f),
+            TunnelError::Timeout(limit) => write!(
+                f,
+                "requests were still in flight after {}s",
+                limit.as_secs()
+            ),
+        }
+    }
+}
+
+impl Error for TunnelError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TunnelError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for TunnelError {
+    fn from(e: io::Error) -> Self {
+        TunnelError::Io(e)
+    }
+}
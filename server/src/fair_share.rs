@@ -0,0 +1,90 @@
+//! Per-tunnel shares of the service manager's queue
+//!
+//! Scheduling model: the service manager handles messages one at a time in the
+//! order they arrive, and routing a request only hands it to its tunnel's own
+//! session queue without waiting on the session, so once routed every tunnel's
+//! requests drain independently of the others. The one thing tunnels share is
+//! the manager's bounded queue. So that a flood to one tunnel can't fill it and
+//! leave every other tunnel answering 503, a tunnel may only have
+//! `capacity / (n + 1)` requests waiting in it, where `n` is the number of
+//! tunnels with requests waiting. That always leaves room for a tunnel with
+//! nothing queued yet, and evens out as more tunnels get busy.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub(crate) struct FairShare {
+    capacity: usize,
+    queued: Mutex<HashMap<String, usize>>,
+}
+
+impl FairShare {
+    pub fn new(capacity: usize) -> Self {
+        FairShare {
+            capacity,
+            queued: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a place in the queue for `service_id`, or None if it already has
+    /// its share
+    pub fn acquire(self: &Arc<Self>, service_id: &str) -> Option<QueuedSlot> {
+        let mut queued = self.queued.lock().unwrap();
+        let busy = queued.len() + usize::from(!queued.contains_key(service_id));
+        let share = (self.capacity / (busy + 1)).max(1);
+        let count = queued.entry(service_id.to_string()).or_default();
+        if *count >= share {
+            if *count == 0 {
+                queued.remove(service_id);
+            }
+            return None;
+        }
+        *count += 1;
+        Some(QueuedSlot {
+            share: self.clone(),
+            service_id: service_id.to_string(),
+        })
+    }
+}
+
+/// A request's place in the service manager's queue, given back when dropped
+#[derive(Debug)]
+pub(crate) struct QueuedSlot {
+    share: Arc<FairShare>,
+    service_id: String,
+}
+
+impl Drop for QueuedSlot {
+    fn drop(&mut self) {
+        let mut queued = self.share.queued.lock().unwrap();
+        if let Some(count) = queued.get_mut(&self.service_id) {
+            *count -= 1;
+            if *count == 0 {
+                queued.remove(&self.service_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_room_for_other_tunnels() {
+        let share = Arc::new(FairShare::new(12));
+        // Alone, a tunnel gets half the queue
+        let foo: Vec<_> = (0..6).map(|_| share.acquire("foo").unwrap()).collect();
+        assert!(share.acquire("foo").is_none());
+
+        // A newcomer still gets in, and past a third of the queue each gets no more
+        let bar: Vec<_> = (0..4).map(|_| share.acquire("bar").unwrap()).collect();
+        assert!(share.acquire("bar").is_none());
+        assert!(share.acquire("baz").is_some());
+
+        drop(foo);
+        drop(bar);
+        assert!(share.queued.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,6492 @@
+//! tunnel-ly server
+//!
+//! [`TunnelServer`] serves tunnels under a root domain. Embedders that want their
+//! own routing can drive the tunnels directly by sending
+//! [`ServiceManagerMessage`]s to [`TunnelServer::service_manager`].
+
+use access_log::{AccessEntry, AccessLog};
+use base64::engine::{general_purpose::STANDARD, Engine};
+use cidr::AccessList;
+use compress::Encoding;
+use cors::Cors;
+use fair_share::{FairShare, QueuedSlot};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::http::request;
+use hyper::server::conn::{AddrIncoming, AddrStream, Http};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Version};
+use log::{debug, error, info, trace, warn};
+use metrics::METRICS;
+use protocol::{
+    dump, is_hop_by_hop, read_frame_max, read_message_max, response_header_buffer, write_message,
+    Message, DEFAULT_MAX_MESSAGE_LEN, INITIAL_WINDOW, MAX_BODY_CHUNK,
+};
+use rand::prelude::*;
+use rate_limit::RateLimiter;
+use ring::hmac;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::collections::hash_map::Entry;
+use std::convert::Infallible;
+use std::fs::File;
+use std::future::{self, Future};
+use std::io::{BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedSender, WeakSender,
+};
+use tokio::sync::{broadcast, oneshot, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, interval_at, sleep, timeout, MissedTickBehavior};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tokio_rustls::{rustls, TlsAcceptor};
+use traffic::Traffic;
+
+pub use access_log::AccessLogFormat;
+pub use cidr::Cidr;
+pub use error::TunnelError;
+
+mod access_log;
+mod cidr;
+mod compress;
+mod cors;
+mod error;
+mod fair_share;
+mod metrics;
+mod proxy_protocol;
+mod rate_limit;
+mod traffic;
+
+/// A tunnel server, created with [`TunnelServer::builder`]
+pub struct TunnelServer {
+    http_addr: SocketAddr,
+    proxy_addr: SocketAddr,
+    tls: Option<(PathBuf, PathBuf)>,
+    proxy_tls: Option<(PathBuf, PathBuf)>,
+    shutdown_grace_period: Duration,
+    max_tunnels: Option<usize>,
+    wildcard_subdomains: bool,
+    reclaim_ttl: Option<Duration>,
+    offline_grace: Option<Duration>,
+    load_balance: bool,
+    proxy_access: AccessList,
+    proxy_protocol: bool,
+    max_proxy_connections: usize,
+    config: Arc<Config>,
+    service_mgr: Sender<ServiceManagerMessage>,
+    service_mgr_receiver: Receiver<ServiceManagerMessage>,
+}
+
+impl TunnelServer {
+    pub fn builder() -> TunnelServerBuilder {
+        TunnelServerBuilder::default()
+    }
+
+    /// Sender for the service manager, which keeps serving once [`run`] is polled
+    ///
+    /// [`run`]: TunnelServer::run
+    pub fn service_manager(&self) -> Sender<ServiceManagerMessage> {
+        self.service_mgr.clone()
+    }
+
+    /// Validates the settings and binds both listeners without serving, returning
+    /// what each check was and whether it passed
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn check(&self) -> Vec<(String, Result<(), TunnelError>)> {
+        let mut checks = vec![("key alphabet".to_string(), self.check_key())];
+        if self.tls.is_some() {
+            checks.push(("TLS certificate".to_string(), self.load_tls().map(|_| ())));
+        }
+        if self.proxy_tls.is_some() {
+            checks.push((
+                "proxy TLS certificate".to_string(),
+                self.load_proxy_tls().map(|_| ()),
+            ));
+        }
+        // Both stay bound until the end, so the same address given twice fails
+        let http_listener = bind(self.http_addr);
+        let proxy_listener = bind(self.proxy_addr);
+        checks.push((
+            format!("HTTP listener on {}", self.http_addr),
+            http_listener.map(|_| ()).map_err(TunnelError::Io),
+        ));
+        checks.push((
+            format!("proxy listener on {}", self.proxy_addr),
+            proxy_listener.map(|_| ()).map_err(TunnelError::Io),
+        ));
+        checks
+    }
+
+    fn check_key(&self) -> Result<(), TunnelError> {
+        if self.config.key.vowels.is_empty() || self.config.key.consonants.is_empty() {
+            return Err(TunnelError::Parse(
+                "key vowels and consonants must not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn load_tls(&self) -> Result<Option<TlsAcceptor>, TunnelError> {
+        self.tls
+            .as_ref()
+            .map(|(cert, key)| {
+                load_tls_acceptor(cert, key)
+                    .map_err(|e| e.context("failed to load TLS certificate"))
+            })
+            .transpose()
+    }
+
+    fn load_proxy_tls(&self) -> Result<Option<TlsAcceptor>, TunnelError> {
+        self.proxy_tls
+            .as_ref()
+            .map(|(cert, key)| {
+                load_tls_acceptor(cert, key)
+                    .map_err(|e| e.context("failed to load proxy TLS certificate"))
+            })
+            .transpose()
+    }
+
+    /// Binds both listeners and serves until the public listener fails
+    pub async fn run(self) -> Result<(), TunnelError> {
+        self.run_until(future::pending()).await
+    }
+
+    /// Like [`run`], but once `shutdown` completes stops accepting connections,
+    /// tells connected clients and waits out the grace period for in-flight
+    /// requests before returning, with [`TunnelError::Timeout`] if some are still
+    /// going once it's over
+    ///
+    /// [`run`]: TunnelServer::run
+    pub async fn run_until(self, shutdown: impl Future<Output = ()>) -> Result<(), TunnelError> {
+        self.check_key()?;
+        let tls_acceptor = self.load_tls()?;
+        let proxy_tls_acceptor = self.load_proxy_tls()?;
+        let http_listener = bind(self.http_addr)?;
+        let proxy_listener = bind(self.proxy_addr)?;
+
+        let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+        start_service_manager(
+            self.config.domain.clone(),
+            self.max_tunnels,
+            self.wildcard_subdomains,
+            self.reclaim_ttl,
+            self.offline_grace,
+            self.load_balance,
+            self.service_mgr_receiver,
+        );
+        spawn_socket_manager(
+            self.service_mgr.clone(),
+            proxy_listener,
+            self.proxy_access,
+            self.proxy_protocol,
+            self.max_proxy_connections,
+            proxy_tls_acceptor,
+            shutdown_receiver.clone(),
+        )
+        .await;
+        let mut request_mgr = spawn_request_manager(
+            http_listener,
+            self.service_mgr.clone(),
+            self.config,
+            tls_acceptor,
+            shutdown_receiver,
+        )
+        .await;
+        tokio::select! {
+            result = &mut request_mgr => return Ok(result.map_err(io::Error::from)??),
+            _ = shutdown => {}
+        }
+
+        info!(
+            "Shutting down, waiting up to {}s for in-flight requests",
+            self.shutdown_grace_period.as_secs()
+        );
+        let _ = shutdown_sender.send(true);
+        let _ = self.service_mgr.send(ServiceManagerMessage::Shutdown).await;
+        match timeout(self.shutdown_grace_period, request_mgr).await {
+            Ok(result) => Ok(result.map_err(io::Error::from)??),
+            Err(_) => Err(TunnelError::Timeout(self.shutdown_grace_period)),
+        }
+    }
+}
+
+// Resolves once the server starts shutting down
+async fn shutting_down(mut shutdown: watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
+
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    bind_socket(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to bind {}: {}", addr, e)))
+}
+
+// An unspecified IPv6 address like `[::]` takes IPv4 connections too, whatever
+// the OS defaults to
+fn bind_socket(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Settings for a [`TunnelServer`], every one defaults to the CLI's default
+pub struct TunnelServerBuilder {
+    http_addr: SocketAddr,
+    proxy_addr: SocketAddr,
+    domain: String,
+    session: SessionConfig,
+    auth_token: Option<String>,
+    key: KeyConfig,
+    tls: Option<(PathBuf, PathBuf)>,
+    proxy_tls: Option<(PathBuf, PathBuf)>,
+    shutdown_grace_period: Duration,
+    max_tunnels: Option<usize>,
+    wildcard_subdomains: bool,
+    reclaim_ttl: Option<Duration>,
+    offline_grace: Option<Duration>,
+    load_balance: bool,
+    start_rate_limit: Option<u32>,
+    start_retry_after: Duration,
+    proxy_access: AccessList,
+    proxy_protocol: bool,
+    max_proxy_connections: usize,
+    response_headers: Vec<(HeaderName, HeaderValue)>,
+    force_response_headers: bool,
+    error_pages: HashMap<StatusCode, String>,
+    landing_page: String,
+    offline_page: String,
+    reserved_subdomains: HashSet<String>,
+}
+
+impl Default for TunnelServerBuilder {
+    fn default() -> Self {
+        TunnelServerBuilder {
+            http_addr: ([127, 0, 0, 1], 80).into(),
+            proxy_addr: ([127, 0, 0, 1], 8080).into(),
+            domain: "rachel.test".to_string(),
+            session: SessionConfig {
+                request_timeout: Duration::from_secs(30),
+                heartbeat_interval: Duration::from_secs(15),
+                heartbeat_timeout: Duration::from_secs(30),
+                compress: false,
+                request_id_header: false,
+                buffer_size: 8 * 1024,
+                max_message_bytes: DEFAULT_MAX_MESSAGE_LEN,
+                max_body_bytes: None,
+                max_in_flight: None,
+                queue_timeout: Duration::from_secs(5),
+                access_log: None,
+                channel_capacity: 1024,
+                basic_auth: None,
+                cors: None,
+                bandwidth_limit: None,
+                dump_messages: None,
+                idle_timeout: None,
+                handshake_secret: String::new(),
+                fallback_url: None,
+                join_token: None,
+            },
+            auth_token: None,
+            key: KeyConfig {
+                scheme: IdScheme::Phonetic,
+                length: 10,
+                vowels: "aeiou".chars().collect(),
+                consonants: "bcdfghjklmnpqrstvwxyz".chars().collect(),
+            },
+            tls: None,
+            proxy_tls: None,
+            shutdown_grace_period: Duration::from_secs(30),
+            max_tunnels: None,
+            wildcard_subdomains: false,
+            reclaim_ttl: None,
+            offline_grace: Some(Duration::from_secs(60)),
+            load_balance: false,
+            start_rate_limit: None,
+            start_retry_after: Duration::from_secs(30),
+            proxy_access: AccessList::default(),
+            proxy_protocol: false,
+            max_proxy_connections: 1024,
+            response_headers: vec![],
+            force_response_headers: false,
+            error_pages: HashMap::new(),
+            landing_page: DEFAULT_LANDING_PAGE.to_string(),
+            offline_page: DEFAULT_OFFLINE_PAGE.to_string(),
+            reserved_subdomains: DEFAULT_RESERVED_SUBDOMAINS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl TunnelServerBuilder {
+    /// Address the public HTTP listener binds to
+    pub fn http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = addr;
+        self
+    }
+
+    /// Address the client proxy listener binds to
+    pub fn proxy_addr(mut self, addr: SocketAddr) -> Self {
+        self.proxy_addr = addr;
+        self
+    }
+
+    /// Root domain that tunnels are served under
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = domain.into();
+        self
+    }
+
+    /// How long to wait for a tunnel's client to start responding before returning 504
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.session.request_timeout = timeout;
+        self
+    }
+
+    /// How often each client is pinged over its primary stream
+    pub fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.session.heartbeat_interval = interval;
+        self
+    }
+
+    /// How long a client has to answer a ping before its tunnel is closed
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.session.heartbeat_timeout = timeout;
+        self
+    }
+
+    /// Compress responses for clients that accept gzip or deflate
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.session.compress = compress;
+        self
+    }
+
+    /// Echo each request's X-Request-Id on its response
+    pub fn request_id_header(mut self, request_id_header: bool) -> Self {
+        self.session.request_id_header = request_id_header;
+        self
+    }
+
+    /// Capacity of the read and write buffers on each client's primary stream
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.session.buffer_size = buffer_size;
+        self
+    }
+
+    /// Skip response heads and body chunks from clients longer than this instead of
+    /// reading them into memory, failing their request with 502
+    pub fn max_message_bytes(mut self, max: usize) -> Self {
+        self.session.max_message_bytes = max;
+        self
+    }
+
+    /// Answer requests with a body larger than this with 413, before forwarding
+    /// them if they declare their length and by aborting them otherwise
+    pub fn max_body_bytes(mut self, max: u64) -> Self {
+        self.session.max_body_bytes = Some(max);
+        self
+    }
+
+    /// Forward at most this many requests at once to each tunnel, more wait for
+    /// one to finish
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.session.max_in_flight = Some(max);
+        self
+    }
+
+    /// How long a request over [`max_in_flight`](Self::max_in_flight) waits
+    /// before it's answered with 503
+    pub fn queue_timeout(mut self, timeout: Duration) -> Self {
+        self.session.queue_timeout = timeout;
+        self
+    }
+
+    /// Write a line in `format` to `out` for every request forwarded to a tunnel
+    pub fn access_log(mut self, out: impl Write + Send + 'static, format: AccessLogFormat) -> Self {
+        self.session.access_log = Some(Arc::new(AccessLog::new(Box::new(out), format)));
+        self
+    }
+
+    /// Close a client's session once it's gone this long without a request,
+    /// unless one is still in flight, or without connecting its primary stream
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.session.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Messages the service manager and each session queue before requests to
+    /// them are answered with 503, and chunks each response body queues for a
+    /// slow browser before it's aborted, at least 1
+    pub fn channel_capacity(mut self, capacity: usize) -> Self {
+        self.session.channel_capacity = capacity.max(1);
+        self
+    }
+
+    /// Cap each tunnel client's request and response bodies at
+    /// `bytes_per_sec` in each direction, at least 1
+    pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.session.bandwidth_limit = Some(bytes_per_sec.max(1));
+        self
+    }
+
+    /// Log every message on clients' primary streams at trace level, with up
+    /// to `max_bytes` of each head or body chunk. This puts request and response
+    /// contents in the logs, so it's only meant for debugging
+    pub fn dump_messages(mut self, max_bytes: usize) -> Self {
+        self.session.dump_messages = Some(max_bytes);
+        self
+    }
+
+    /// How subdomains are generated for clients that don't request one
+    pub fn id_scheme(mut self, scheme: IdScheme) -> Self {
+        self.key.scheme = scheme;
+        self
+    }
+
+    /// Length of phonetic and base32 generated subdomains
+    pub fn key_length(mut self, length: usize) -> Self {
+        self.key.length = length;
+        self
+    }
+
+    /// Letters generated subdomains alternate between
+    pub fn key_alphabet(mut self, vowels: &str, consonants: &str) -> Self {
+        self.key.vowels = vowels.chars().collect();
+        self.key.consonants = consonants.chars().collect();
+        self
+    }
+
+    /// Require clients to send `Authorization: Bearer <token>` to open a tunnel
+    pub fn auth_token(mut self, token: impl Into<String>) -> Self {
+        self.auth_token = Some(token.into());
+        self
+    }
+
+    /// Serve HTTPS with a PEM certificate chain, which should cover `*.{domain}`,
+    /// and its PKCS#8 private key
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.tls = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Accept clients' primary streams over TLS with a PEM certificate chain,
+    /// which should cover the domain clients connect to, and its PKCS#8 private
+    /// key. The handshake and every message after it are encrypted, whether or
+    /// not the public listener uses TLS
+    pub fn proxy_tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.proxy_tls = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// How long [`TunnelServer::run_until`] waits for in-flight requests once
+    /// shutdown starts
+    pub fn shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = grace_period;
+        self
+    }
+
+    /// Answer `POST /start` with 503 while this many tunnels are open
+    pub fn max_tunnels(mut self, max_tunnels: usize) -> Self {
+        self.max_tunnels = Some(max_tunnels);
+        self
+    }
+
+    /// Retry-After sent when `POST /start` is refused with 503 because the
+    /// server is full or draining, rounded down to whole seconds
+    pub fn start_retry_after(mut self, retry_after: Duration) -> Self {
+        self.start_retry_after = retry_after;
+        self
+    }
+
+    /// Route `*.{service_id}.{domain}` to the `service_id` tunnel, with the full
+    /// Host still forwarded, instead of only exact `{service_id}.{domain}` hosts
+    pub fn wildcard_subdomains(mut self, wildcard: bool) -> Self {
+        self.wildcard_subdomains = wildcard;
+        self
+    }
+
+    /// Answer `POST /start` with a reclaim token in X-Reclaim-Token, which a
+    /// client can send back with its subdomain to get the same one again, and
+    /// hold a disconnected tunnel's subdomain for its token this long
+    pub fn reclaim_ttl(mut self, ttl: Duration) -> Self {
+        self.reclaim_ttl = Some(ttl);
+        self
+    }
+
+    /// Answer requests for a tunnel that closed less than this long ago with 503
+    /// and the offline page rather than 404, or redirect them if its client gave
+    /// a fallback URL. Zero always answers 404
+    pub fn offline_grace(mut self, grace: Duration) -> Self {
+        self.offline_grace = Some(grace).filter(|grace| !grace.is_zero());
+        self
+    }
+
+    /// Let a client that requests a subdomain already in use join its tunnel,
+    /// with requests spread round-robin over every client serving it, instead of
+    /// answering 409. The tunnel's first client is handed a token in
+    /// X-Join-Token, which the others have to send back to join
+    pub fn load_balance(mut self, load_balance: bool) -> Self {
+        self.load_balance = load_balance;
+        self
+    }
+
+    /// Subdomains clients may not request, replacing the default
+    /// [`DEFAULT_RESERVED_SUBDOMAINS`]
+    pub fn reserved_subdomains<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.reserved_subdomains = names
+            .into_iter()
+            .map(|name| name.into().to_lowercase())
+            .collect();
+        self
+    }
+
+    /// Answer `POST /start` with 429 once an IP opens more than this many
+    /// tunnels a minute
+    pub fn start_rate_limit(mut self, per_minute: u32) -> Self {
+        self.start_rate_limit = Some(per_minute);
+        self
+    }
+
+    /// Only accept client connections to the proxy listener from this network,
+    /// can be given more than once
+    pub fn allow_cidr(mut self, cidr: Cidr) -> Self {
+        self.proxy_access.allow.push(cidr);
+        self
+    }
+
+    /// Drop client connections to the proxy listener from this network, even if
+    /// it's allowed, can be given more than once
+    pub fn deny_cidr(mut self, cidr: Cidr) -> Self {
+        self.proxy_access.deny.push(cidr);
+        self
+    }
+
+    /// Expect a PROXY protocol v1 or v2 header from a load balancer at the start
+    /// of every connection to the proxy listener, and check the client address
+    /// it gives against the allowed and denied networks instead of the load
+    /// balancer's. Connections without one are dropped
+    pub fn accept_proxy_protocol(mut self, accept: bool) -> Self {
+        self.proxy_protocol = accept;
+        self
+    }
+
+    /// Handshake with at most this many proxy listener connections at once, and
+    /// leave further ones waiting to be accepted until one finishes, at least 1
+    pub fn max_proxy_connections(mut self, max: usize) -> Self {
+        self.max_proxy_connections = max.max(1);
+        self
+    }
+
+    /// Add a header to every tunneled response, unless the upstream already
+    /// set one with the same name
+    pub fn response_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.response_headers.push((name, value));
+        self
+    }
+
+    /// Replace upstream headers with the ones given to
+    /// [`response_header`](Self::response_header) instead of keeping them
+    pub fn force_response_headers(mut self, force: bool) -> Self {
+        self.force_response_headers = force;
+        self
+    }
+
+    /// Answer CORS preflight requests from `origin` and add Access-Control-Allow-*
+    /// headers to responses for it, unless the upstream set its own. `*` allows
+    /// any origin, can be given more than once
+    pub fn cors_origin(mut self, origin: impl Into<String>) -> Self {
+        self.session
+            .cors
+            .get_or_insert_with(Cors::default)
+            .origins
+            .push(origin.into());
+        self
+    }
+
+    /// Access-Control-Allow-Methods sent to allowed origins, by default
+    /// `GET, POST, PUT, PATCH, DELETE, OPTIONS`
+    pub fn cors_methods(mut self, methods: HeaderValue) -> Self {
+        self.session.cors.get_or_insert_with(Cors::default).methods = methods;
+        self
+    }
+
+    /// Access-Control-Allow-Headers sent to allowed origins, by default `*`
+    pub fn cors_headers(mut self, headers: HeaderValue) -> Self {
+        self.session.cors.get_or_insert_with(Cors::default).headers = headers;
+        self
+    }
+
+    /// Serve `html` instead of the plain text body when the server itself answers
+    /// a tunneled request with `status`, `{{host}}` in it is replaced with the
+    /// requested host
+    pub fn error_page(mut self, status: StatusCode, html: impl Into<String>) -> Self {
+        self.error_pages.insert(status, html.into());
+        self
+    }
+
+    /// HTML served for `GET /` on the root domain, `{{domain}}` in it is
+    /// replaced with the root domain
+    pub fn landing_page(mut self, html: impl Into<String>) -> Self {
+        self.landing_page = html.into();
+        self
+    }
+
+    /// HTML served with 503 for a tunnel that closed within the
+    /// [`offline_grace`](Self::offline_grace) window, `{{host}}` in it is replaced
+    /// with the requested host
+    pub fn offline_page(mut self, html: impl Into<String>) -> Self {
+        self.offline_page = html.into();
+        self
+    }
+
+    pub fn build(self) -> TunnelServer {
+        let (service_mgr, service_mgr_receiver) = channel(self.session.channel_capacity);
+        let fair_share = Arc::new(FairShare::new(self.session.channel_capacity));
+        TunnelServer {
+            http_addr: self.http_addr,
+            proxy_addr: self.proxy_addr,
+            tls: self.tls,
+            proxy_tls: self.proxy_tls,
+            shutdown_grace_period: self.shutdown_grace_period,
+            max_tunnels: self.max_tunnels,
+            wildcard_subdomains: self.wildcard_subdomains,
+            reclaim_ttl: self.reclaim_ttl,
+            offline_grace: self.offline_grace,
+            load_balance: self.load_balance,
+            proxy_access: self.proxy_access,
+            proxy_protocol: self.proxy_protocol,
+            max_proxy_connections: self.max_proxy_connections,
+            config: Arc::new(Config {
+                domain: self.domain,
+                http_port: self.http_addr.port(),
+                proxy_port: self.proxy_addr.port(),
+                session: self.session,
+                auth_token: self.auth_token,
+                key: self.key,
+                start_limiter: self.start_rate_limit.map(RateLimiter::new),
+                start_retry_after: self.start_retry_after,
+                response_headers: self.response_headers,
+                force_response_headers: self.force_response_headers,
+                error_pages: self.error_pages,
+                landing_page: self.landing_page,
+                offline_page: self.offline_page,
+                reserved_subdomains: self.reserved_subdomains,
+                reclaim_ttl: self.reclaim_ttl,
+                wildcard_subdomains: self.wildcard_subdomains,
+                load_balance: self.load_balance,
+                fair_share,
+            }),
+            service_mgr,
+            service_mgr_receiver,
+        }
+    }
+}
+
+// Settings shared by the request manager and the sessions it spawns
+#[derive(Debug)]
+struct Config {
+    domain: String,
+    // The public listener's own port, part of the tunnel URL in JSON `/start`
+    // responses unless it's the scheme's default
+    http_port: u16,
+    // The proxy listener's port, where JSON `/start` responses tell clients to
+    // open their primary stream
+    proxy_port: u16,
+    session: SessionConfig,
+    auth_token: Option<String>,
+    key: KeyConfig,
+    start_limiter: Option<RateLimiter>,
+    start_retry_after: Duration,
+    response_headers: Vec<(HeaderName, HeaderValue)>,
+    force_response_headers: bool,
+    error_pages: HashMap<StatusCode, String>,
+    landing_page: String,
+    offline_page: String,
+    reserved_subdomains: HashSet<String>,
+    reclaim_ttl: Option<Duration>,
+    wildcard_subdomains: bool,
+    load_balance: bool,
+    fair_share: Arc<FairShare>,
+}
+
+#[derive(Debug, Clone)]
+struct SessionConfig {
+    request_timeout: Duration,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    compress: bool,
+    request_id_header: bool,
+    buffer_size: usize,
+    max_message_bytes: usize,
+    max_body_bytes: Option<u64>,
+    max_in_flight: Option<usize>,
+    queue_timeout: Duration,
+    access_log: Option<Arc<AccessLog>>,
+    channel_capacity: usize,
+    /// Set per tunnel from its client's start request
+    basic_auth: Option<BasicAuth>,
+    cors: Option<Cors>,
+    bandwidth_limit: Option<u64>,
+    dump_messages: Option<usize>,
+    idle_timeout: Option<Duration>,
+    /// Set per session from its start request, its client signs the service id
+    /// with it to claim the session's primary stream
+    handshake_secret: String,
+    /// Set per tunnel from its client's start request, where visitors are
+    /// redirected while it's offline
+    fallback_url: Option<String>,
+    /// Set per session from its start request when load balancing, other
+    /// clients have to send the tunnel's to join it
+    join_token: Option<String>,
+}
+
+// Credentials a tunnel's visitors have to give before anything is forwarded
+#[derive(Debug, Clone)]
+struct BasicAuth {
+    user: String,
+    password: String,
+}
+
+impl BasicAuth {
+    // `user:password`, the password may contain colons
+    fn parse(credentials: &str) -> Option<Self> {
+        let (user, password) = credentials.split_once(':')?;
+        Some(BasicAuth {
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    fn allows(&self, headers: &HeaderMap) -> bool {
+        let decoded = headers
+            .get(hyper::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok());
+        let Some(decoded) = decoded else {
+            return false;
+        };
+        let Some(colon) = decoded.iter().position(|&b| b == b':') else {
+            return false;
+        };
+        let (user, password) = (&decoded[..colon], &decoded[colon + 1..]);
+        // Both are compared so timing doesn't reveal which one was wrong
+        constant_time_eq(user, self.user.as_bytes())
+            & constant_time_eq(password, self.password.as_bytes())
+    }
+}
+
+// Shape of the subdomains handed out when a client doesn't request one
+#[derive(Debug)]
+struct KeyConfig {
+    scheme: IdScheme,
+    length: usize,
+    vowels: Vec<char>,
+    consonants: Vec<char>,
+}
+
+/// How subdomains are generated for clients that don't request one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdScheme {
+    /// Alternating consonants and vowels, easy to read out but easy to guess
+    Phonetic,
+    /// A random version 4 UUID
+    Uuid,
+    /// Random lowercase base32 characters
+    Base32,
+}
+
+impl FromStr for IdScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "phonetic" => Ok(IdScheme::Phonetic),
+            "uuid" => Ok(IdScheme::Uuid),
+            "base32" => Ok(IdScheme::Base32),
+            _ => Err("expected phonetic, uuid or base32".to_string()),
+        }
+    }
+}
+
+/// Access-Control-Allow-Methods sent to allowed CORS origins by default
+pub const DEFAULT_CORS_METHODS: &str = cors::DEFAULT_METHODS;
+
+/// Served on the root domain unless another landing page is given
+pub const DEFAULT_LANDING_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>tunnel-ly</title>
+</head>
+<body>
+<h1>tunnel-ly</h1>
+<p>This server forwards requests for <code>*.{{domain}}</code> to services running behind
+the tunnel-ly client.</p>
+</body>
+</html>
+";
+
+/// Served for tunnels that just closed unless another offline page is given
+pub const DEFAULT_OFFLINE_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Tunnel offline</title>
+</head>
+<body>
+<h1>This tunnel is offline</h1>
+<p>The client serving <code>{{host}}</code> disconnected moments ago, it may be back
+shortly.</p>
+</body>
+</html>
+";
+
+// Seconds browsers are told to wait before retrying a tunnel that just closed
+const OFFLINE_RETRY_AFTER: &str = "5";
+
+/// Subdomains that can't be requested by default, so tunnels don't pass for the
+/// server's own endpoints
+pub const DEFAULT_RESERVED_SUBDOMAINS: [&str; 6] =
+    ["admin", "health", "metrics", "start", "status", "www"];
+
+// Times to reroll a generated service id that collides with a live tunnel
+const MAX_KEY_ATTEMPTS: u32 = 8;
+
+// Limits on the service id a client sends when opening its primary stream
+const MAX_HANDSHAKE_LEN: usize = 256;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Idle time before the OS starts probing a client's primary stream
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_REQUEST_ID: &str = "x-request-id";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_RECLAIM_TOKEN: &str = "x-reclaim-token";
+const X_JOIN_TOKEN: &str = "x-join-token";
+const X_BASIC_AUTH: &str = "x-basic-auth";
+const X_HANDSHAKE_SECRET: &str = "x-handshake-secret";
+const X_FALLBACK_URL: &str = "x-fallback-url";
+
+/// Requests handled by the service manager, which tracks every live tunnel
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum ServiceManagerMessage {
+    /// Routes a request to the tunnel named by its Host header, the response (or
+    /// an error response) is sent back on `response_sender`
+    ForwardRequest {
+        request: Request<Body>,
+        response_sender: UnboundedSender<Response<Body>>,
+    },
+    /// Reserves `service_id` for a session, along with `reclaim_token` if given
+    /// so it's held for the token once the session ends. `session` tells the
+    /// sessions of a load balanced tunnel apart
+    RegisterService {
+        service_id: String,
+        session: u64,
+        sender: Sender<ServiceSessionMessage>,
+        /// Bytes the session has moved, for the admin API
+        traffic: Arc<Traffic>,
+        /// What the session's client signs its handshake with
+        secret: String,
+        /// Where to redirect visitors once the tunnel is offline, the first
+        /// session's is kept for a load balanced tunnel
+        fallback: Option<String>,
+        reclaim_token: Option<String>,
+        /// What later sessions have to present to join a load balanced tunnel,
+        /// the first session's is kept
+        join_token: Option<String>,
+        registered: oneshot::Sender<Registration>,
+    },
+    /// Hands a client's primary stream to the session whose secret made
+    /// `signature`, the HMAC of the service id, if it's still waiting for one.
+    /// `verified` hears whether any of the tunnel's sessions' secrets did
+    ForwardPrimaryStream {
+        service_id: String,
+        signature: Vec<u8>,
+        stream: PrimaryStream,
+        verified: oneshot::Sender<bool>,
+    },
+    /// Unregisters a tunnel along with every session serving it
+    UnregisterService { service_id: String },
+    /// Takes one session out of a tunnel, which is unregistered once it has none
+    LeaveService { service_id: String, session: u64 },
+    Stats {
+        stats: oneshot::Sender<ServiceStats>,
+    },
+    /// Lets every connected client know the server is shutting down
+    Shutdown,
+    ListServices {
+        services: oneshot::Sender<Vec<ServiceInfo>>,
+    },
+    /// Unregisters a tunnel and closes its primary stream, `killed` is false if
+    /// there was no such tunnel
+    KillService {
+        service_id: String,
+        killed: oneshot::Sender<bool>,
+    },
+    /// Subscribes to an event for every tunnel registered or unregistered from
+    /// now on
+    SubscribeEvents {
+        events: oneshot::Sender<broadcast::Receiver<ServiceEvent>>,
+    },
+    /// Starts or stops turning away new sessions while open tunnels carry on,
+    /// replying with how many are still open
+    Drain {
+        draining: bool,
+        tunnels: oneshot::Sender<usize>,
+    },
+}
+
+/// A change in which tunnels are open
+#[derive(Debug, Clone)]
+pub struct ServiceEvent {
+    pub kind: ServiceEventKind,
+    pub service_id: String,
+    pub time: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceEventKind {
+    Registered,
+    Unregistered,
+}
+
+impl ServiceEvent {
+    fn new(kind: ServiceEventKind, service_id: &str) -> Self {
+        ServiceEvent {
+            kind,
+            service_id: service_id.to_string(),
+            time: SystemTime::now(),
+        }
+    }
+
+    // One server-sent event, with the details as JSON
+    fn to_sse(&self) -> String {
+        let kind = match self.kind {
+            ServiceEventKind::Registered => "registered",
+            ServiceEventKind::Unregistered => "unregistered",
+        };
+        let time = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        let data = serde_json::json!({
+            "type": kind,
+            "service_id": self.service_id,
+            "timestamp": time.as_secs(),
+        });
+        format!("event: {}\ndata: {}\n\n", kind, data)
+    }
+}
+
+// Events a subscriber can fall behind by before it misses some
+const EVENT_BUFFER: usize = 256;
+
+/// Reply to a `RegisterService`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Registration {
+    Registered,
+    /// Another session already has the service id, or it's held for a
+    /// disconnected one with a different reclaim token
+    Taken,
+    /// The server is at its tunnel limit
+    Full,
+    /// The server is draining and takes no new sessions
+    Draining,
+}
+
+// A registered tunnel as the service manager tracks it, served by one session
+// or, with load balancing, several
+#[derive(Debug)]
+struct Service {
+    sessions: Vec<Member>,
+    // Where the round-robin search for the next request's session starts
+    next: usize,
+    connected_at: SystemTime,
+    requests: u64,
+    fallback: Option<String>,
+    join_token: Option<String>,
+}
+
+#[derive(Debug)]
+struct Member {
+    session: u64,
+    sender: Sender<ServiceSessionMessage>,
+    traffic: Arc<Traffic>,
+    secret: String,
+    // Sessions only take requests once their primary stream has arrived
+    streaming: bool,
+}
+
+// What became of a request the service manager tried to hand to a session
+enum Forwarded {
+    Sent,
+    /// Every session's queue was full
+    Busy,
+    /// Every session had closed
+    Closed,
+}
+
+// What became of a primary stream the service manager tried to hand to a session
+enum Handoff {
+    Sent,
+    /// None of the sessions' secrets signed the handshake
+    Unsigned,
+    /// The session it was signed for already has a primary stream
+    Duplicate,
+    /// The session it was signed for had closed
+    Closed,
+}
+
+impl Service {
+    fn new(
+        session: u64,
+        sender: Sender<ServiceSessionMessage>,
+        traffic: Arc<Traffic>,
+        secret: String,
+        fallback: Option<String>,
+        join_token: Option<String>,
+    ) -> Self {
+        Service {
+            sessions: vec![Member {
+                session,
+                sender,
+                traffic,
+                secret,
+                streaming: false,
+            }],
+            next: 0,
+            connected_at: SystemTime::now(),
+            requests: 0,
+            fallback,
+            join_token,
+        }
+    }
+
+    // Gives the stream to the session whose secret signed its handshake, checked
+    // here so it can't go to a session that registered since
+    fn forward_stream(
+        &mut self,
+        service_id: &str,
+        signature: &[u8],
+        stream: PrimaryStream,
+    ) -> Handoff {
+        let signed = |member: &Member| verify_handshake(&member.secret, service_id, signature);
+        if !self.sessions.iter().any(signed) {
+            return Handoff::Unsigned;
+        }
+        match self
+            .sessions
+            .iter_mut()
+            .find(|member| !member.streaming && signed(member))
+        {
+            Some(member) if !member.sender.is_closed() => {
+                member.streaming = true;
+                if send_to_session(
+                    &member.sender,
+                    ServiceSessionMessage::RecvPrimaryStream(stream),
+                ) {
+                    Handoff::Sent
+                } else {
+                    Handoff::Closed
+                }
+            }
+            Some(_) => Handoff::Closed,
+            None => Handoff::Duplicate,
+        }
+    }
+
+    // Tries each session with a primary stream in turn, starting after the one
+    // that took the last request. Sessions that have closed are dropped
+    fn forward_request(
+        &mut self,
+        request: Request<Body>,
+        response_sender: UnboundedSender<Response<Body>>,
+    ) -> Forwarded {
+        let mut msg = ServiceSessionMessage::RecvRequest(request, response_sender);
+        // Until its stream arrives a lone session takes requests, and drops them
+        let any_streaming = self.sessions.iter().any(|member| member.streaming);
+        let mut busy = false;
+        for _ in 0..self.sessions.len() {
+            if self.sessions.is_empty() {
+                break;
+            }
+            let index = self.next % self.sessions.len();
+            self.next = self.next.wrapping_add(1);
+            if any_streaming && !self.sessions[index].streaming {
+                continue;
+            }
+            match self.sessions[index].sender.try_send(msg) {
+                Ok(()) => {
+                    self.requests += 1;
+                    return Forwarded::Sent;
+                }
+                Err(TrySendError::Full(returned)) => {
+                    busy = true;
+                    msg = returned;
+                }
+                Err(TrySendError::Closed(returned)) => {
+                    self.sessions.remove(index);
+                    msg = returned;
+                }
+            }
+        }
+        if busy {
+            Forwarded::Busy
+        } else {
+            Forwarded::Closed
+        }
+    }
+}
+
+// Bookkeeping for a tunnel that's gone, its subdomain is held for its reclaim
+// token if reconnects can reclaim them
+fn service_closed(
+    held: &mut HashMap<String, Hold>,
+    tombstones: &mut Option<Tombstones>,
+    events: &broadcast::Sender<ServiceEvent>,
+    service_id: &str,
+    fallback: Option<String>,
+    reclaim_ttl: Option<Duration>,
+) {
+    METRICS.tunnel_closed();
+    let _ = events.send(ServiceEvent::new(
+        ServiceEventKind::Unregistered,
+        service_id,
+    ));
+    if let (Some(hold), Some(ttl)) = (held.get_mut(service_id), reclaim_ttl) {
+        hold.expires = Some(Instant::now() + ttl);
+    }
+    if let Some(tombstones) = tombstones {
+        tombstones.bury(service_id, fallback);
+    }
+}
+
+// Tunnels that closed within the grace window, so requests for them can be told
+// the tunnel is offline rather than that it never existed
+#[derive(Debug)]
+struct Tombstones {
+    grace: Duration,
+    closed: HashMap<String, Tombstone>,
+}
+
+#[derive(Debug)]
+struct Tombstone {
+    closed: Instant,
+    fallback: Option<String>,
+}
+
+impl Tombstones {
+    fn new(grace: Duration) -> Self {
+        Tombstones {
+            grace,
+            closed: HashMap::new(),
+        }
+    }
+
+    fn bury(&mut self, service_id: &str, fallback: Option<String>) {
+        let grace = self.grace;
+        self.closed
+            .retain(|_, tombstone| tombstone.closed.elapsed() < grace);
+        self.closed.insert(
+            service_id.to_string(),
+            Tombstone {
+                closed: Instant::now(),
+                fallback,
+            },
+        );
+    }
+
+    fn recently_closed(&self, service_id: &str) -> Option<&Tombstone> {
+        self.closed
+            .get(service_id)
+            .filter(|tombstone| tombstone.closed.elapsed() < self.grace)
+    }
+}
+
+// Marks the server's answer for a tunnel that just closed, replaced by the
+// offline page
+#[derive(Debug, Clone, Copy)]
+struct TunnelOffline;
+
+// A service id kept for whoever has its reclaim token, until `expires` once its
+// tunnel is gone
+#[derive(Debug)]
+struct Hold {
+    token: String,
+    expires: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServiceInfo {
+    pub service_id: String,
+    /// When the tunnel was registered
+    pub connected_at: SystemTime,
+    /// Requests forwarded to the tunnel so far
+    pub requests: u64,
+    /// Clients serving the tunnel, more than one when load balanced
+    pub clients: usize,
+    /// Bytes read from the tunnel's clients so far
+    pub bytes_received: u64,
+    /// Bytes written to the tunnel's clients so far
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug)]
+pub struct ServiceStats {
+    /// Number of registered tunnels
+    pub services: usize,
+    /// Time since the service manager started
+    pub uptime: Duration,
+    /// Whether new sessions are being turned away
+    pub draining: bool,
+}
+
+/// Messages for a single tunnel's session
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant, clippy::enum_variant_names)]
+pub enum ServiceSessionMessage {
+    RecvPrimaryStream(PrimaryStream),
+    RecvRequest(Request<Body>, UnboundedSender<Response<Body>>),
+    RecvMessage(Message),
+    RequestTimeout(u32),
+    /// The request's body went over the configured maximum while streaming
+    RequestTooLarge(u32),
+    /// Nothing is reading the response's body anymore
+    ResponseDropped(u32),
+    Shutdown,
+    /// Closes the primary stream and ends the session
+    Close,
+}
+
+/// A client's connection to the proxy listener, over TLS when the server has a
+/// [`proxy_tls`](TunnelServerBuilder::proxy_tls) certificate. Anything that
+/// reads and writes bytes can carry one, like a [`tokio::io::duplex`] pipe
+pub struct PrimaryStream(Pin<Box<dyn Transport>>);
+
+// What a primary stream can be carried over
+trait Transport: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> Transport for T {}
+
+impl PrimaryStream {
+    pub fn new(stream: impl AsyncRead + AsyncWrite + Send + 'static) -> Self {
+        PrimaryStream(Box::pin(stream))
+    }
+}
+
+impl fmt::Debug for PrimaryStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrimaryStream").finish_non_exhaustive()
+    }
+}
+
+impl From<TcpStream> for PrimaryStream {
+    fn from(stream: TcpStream) -> Self {
+        PrimaryStream::new(stream)
+    }
+}
+
+impl AsyncRead for PrimaryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrimaryStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+async fn spawn_service_manager(domain: String) -> Sender<ServiceManagerMessage> {
+    let (sender, receiver) = channel(1024);
+    start_service_manager(domain, None, false, None, None, false, receiver);
+    sender
+}
+
+fn start_service_manager(
+    domain: String,
+    max_tunnels: Option<usize>,
+    wildcard_subdomains: bool,
+    reclaim_ttl: Option<Duration>,
+    offline_grace: Option<Duration>,
+    load_balance: bool,
+    mut receiver: Receiver<ServiceManagerMessage>,
+) {
+    debug!("Spawning service manager");
+
+    task::spawn(async move {
+        debug!("Service manager started");
+        let started = Instant::now();
+        let mut services: HashMap<String, Service> = HashMap::new();
+        let mut held: HashMap<String, Hold> = HashMap::new();
+        let mut tombstones = offline_grace.map(Tombstones::new);
+        let mut draining = false;
+        // Sending fails while nobody is subscribed, which is fine
+        let (mut events, _) = broadcast::channel(EVENT_BUFFER);
+        loop {
+            // Every sender is gone once the server has shut down
+            let msg = match receiver.recv().await {
+                Some(msg) => msg,
+                None => break,
+            };
+            trace!("Service manager received message: {:?}", msg);
+            match msg {
+                ServiceManagerMessage::RegisterService {
+                    service_id,
+                    session,
+                    sender,
+                    traffic,
+                    secret,
+                    fallback,
+                    reclaim_token,
+                    join_token,
+                    registered,
+                } => {
+                    if draining {
+                        warn!(
+                            "Service manager rejected service while draining: {}",
+                            service_id
+                        );
+                        let _ = registered.send(Registration::Draining);
+                        continue;
+                    }
+                    let now = Instant::now();
+                    held.retain(|_, hold| hold.expires.is_none_or(|expires| expires > now));
+                    let full = max_tunnels.is_some_and(|max| services.len() >= max);
+                    let reclaimed = held.get(&service_id).map(|hold| {
+                        reclaim_token.as_ref().is_some_and(|token| {
+                            constant_time_eq(token.as_bytes(), hold.token.as_bytes())
+                        })
+                    });
+                    // Only a client the tunnel's first one shared its token with
+                    // can join it
+                    let joins = |service: &Service| match (&service.join_token, &join_token) {
+                        (Some(expected), Some(token)) => {
+                            constant_time_eq(token.as_bytes(), expected.as_bytes())
+                        }
+                        _ => false,
+                    };
+                    match services.entry(service_id) {
+                        Entry::Occupied(mut entry) if load_balance && joins(entry.get()) => {
+                            debug!("Service manager added session to service: {}", entry.key());
+                            entry.get_mut().sessions.push(Member {
+                                session,
+                                sender,
+                                traffic,
+                                secret,
+                                streaming: false,
+                            });
+                            let _ = registered.send(Registration::Registered);
+                        }
+                        Entry::Occupied(entry) => {
+                            warn!(
+                                "Service manager rejected duplicate service: {}",
+                                entry.key()
+                            );
+                            let _ = registered.send(Registration::Taken);
+                        }
+                        Entry::Vacant(entry) if reclaimed == Some(false) => {
+                            warn!(
+                                "Service manager rejected service held for reconnect: {}",
+                                entry.key()
+                            );
+                            let _ = registered.send(Registration::Taken);
+                        }
+                        Entry::Vacant(entry) if full => {
+                            warn!(
+                                "Service manager rejected service at tunnel limit: {}",
+                                entry.key()
+                            );
+                            let _ = registered.send(Registration::Full);
+                        }
+                        Entry::Vacant(entry) => {
+                            if reclaimed == Some(true) {
+                                debug!("Service manager reclaimed service: {}", entry.key());
+                            } else {
+                                debug!("Service manager registered service: {}", entry.key());
+                            }
+                            if let Some(token) = reclaim_token.filter(|_| reclaim_ttl.is_some()) {
+                                held.insert(
+                                    entry.key().clone(),
+                                    Hold {
+                                        token,
+                                        expires: None,
+                                    },
+                                );
+                            }
+                            if let Some(tombstones) = &mut tombstones {
+                                tombstones.closed.remove(entry.key());
+                            }
+                            let _ = events
+                                .send(ServiceEvent::new(ServiceEventKind::Registered, entry.key()));
+                            entry.insert(Service::new(
+                                session, sender, traffic, secret, fallback, join_token,
+                            ));
+                            METRICS.tunnel_opened();
+                            let _ = registered.send(Registration::Registered);
+                        }
+                    }
+                }
+                ServiceManagerMessage::UnregisterService { service_id } => {
+                    debug!("Service manager unregistered service: {}", service_id);
+                    if let Some(service) = services.remove(&service_id) {
+                        service_closed(
+                            &mut held,
+                            &mut tombstones,
+                            &events,
+                            &service_id,
+                            service.fallback,
+                            reclaim_ttl,
+                        );
+                    }
+                }
+                ServiceManagerMessage::LeaveService {
+                    service_id,
+                    session,
+                } => {
+                    if let Some(service) = services.get_mut(&service_id) {
+                        service.sessions.retain(|member| member.session != session);
+                        if service.sessions.is_empty() {
+                            debug!("Service manager unregistered service: {}", service_id);
+                            let fallback = services.remove(&service_id).and_then(|s| s.fallback);
+                            service_closed(
+                                &mut held,
+                                &mut tombstones,
+                                &events,
+                                &service_id,
+                                fallback,
+                                reclaim_ttl,
+                            );
+                        } else {
+                            debug!(
+                                "Service manager removed session from service: {}",
+                                service_id
+                            );
+                        }
+                    }
+                }
+                ServiceManagerMessage::Stats { stats } => {
+                    let _ = stats.send(ServiceStats {
+                        services: services.len(),
+                        uptime: started.elapsed(),
+                        draining,
+                    });
+                }
+                ServiceManagerMessage::Drain {
+                    draining: drain,
+                    tunnels,
+                } => {
+                    if drain != draining {
+                        info!(
+                            "Service manager {} with {} services open",
+                            if drain {
+                                "draining"
+                            } else {
+                                "no longer draining"
+                            },
+                            services.len()
+                        );
+                    }
+                    draining = drain;
+                    let _ = tunnels.send(services.len());
+                }
+                ServiceManagerMessage::Shutdown => {
+                    debug!(
+                        "Service manager notifying {} services of shutdown",
+                        services.len()
+                    );
+                    for member in services.values().flat_map(|service| &service.sessions) {
+                        send_to_session(&member.sender, ServiceSessionMessage::Shutdown);
+                    }
+                    // Ends open event streams, which would otherwise hold up shutdown
+                    events = broadcast::channel(EVENT_BUFFER).0;
+                }
+                ServiceManagerMessage::ListServices { services: reply } => {
+                    let _ = reply.send(
+                        services
+                            .iter()
+                            .map(|(service_id, service)| {
+                                let (bytes_received, bytes_sent) = service
+                                    .sessions
+                                    .iter()
+                                    .map(|member| member.traffic.totals())
+                                    .fold((0, 0), |(received, sent), (r, s)| {
+                                        (received + r, sent + s)
+                                    });
+                                ServiceInfo {
+                                    service_id: service_id.clone(),
+                                    connected_at: service.connected_at,
+                                    requests: service.requests,
+                                    clients: service.sessions.len(),
+                                    bytes_received,
+                                    bytes_sent,
+                                }
+                            })
+                            .collect(),
+                    );
+                }
+                ServiceManagerMessage::KillService { service_id, killed } => {
+                    match services.remove(&service_id) {
+                        Some(service) => {
+                            debug!("Service manager killed service: {}", service_id);
+                            // A killed tunnel doesn't get to reconnect to its subdomain
+                            held.remove(&service_id);
+                            METRICS.tunnel_closed();
+                            let _ = events.send(ServiceEvent::new(
+                                ServiceEventKind::Unregistered,
+                                &service_id,
+                            ));
+                            for member in &service.sessions {
+                                send_to_session(&member.sender, ServiceSessionMessage::Close);
+                            }
+                            let _ = killed.send(true);
+                        }
+                        None => {
+                            warn!("Service manager could not find service: {}", service_id);
+                            let _ = killed.send(false);
+                        }
+                    }
+                }
+                ServiceManagerMessage::SubscribeEvents { events: reply } => {
+                    let _ = reply.send(events.subscribe());
+                }
+                // A stream nobody takes is dropped here, which closes it so its
+                // client sees it was turned away
+                ServiceManagerMessage::ForwardPrimaryStream {
+                    service_id,
+                    signature,
+                    stream,
+                    verified,
+                } => {
+                    let Some(service) = services.get_mut(&service_id) else {
+                        warn!(
+                            "Service manager could not find service for primary stream: {}",
+                            service_id
+                        );
+                        let _ = verified.send(false);
+                        continue;
+                    };
+                    let handoff = service.forward_stream(&service_id, &signature, stream);
+                    let _ = verified.send(!matches!(handoff, Handoff::Unsigned));
+                    match handoff {
+                        Handoff::Sent => debug!(
+                            "Service manager forwarded primary stream to service: {}",
+                            service_id
+                        ),
+                        Handoff::Unsigned => {}
+                        Handoff::Duplicate => warn!(
+                            "Service manager rejected another primary stream for service: {}",
+                            service_id
+                        ),
+                        Handoff::Closed => warn!(
+                            "Service manager has no live session for primary stream: {}",
+                            service_id
+                        ),
+                    }
+                }
+                ServiceManagerMessage::ForwardRequest {
+                    mut request,
+                    response_sender,
+                } => {
+                    // Out of the queue, so the tunnel's share has room again
+                    request.extensions_mut().remove::<QueuedSlot>();
+                    let request_id = ensure_request_id(request.headers_mut());
+                    let host = match request.headers().get(hyper::http::header::HOST) {
+                        Some(host) => host.to_str(),
+                        None => {
+                            warn!("Service manager could not find host header");
+                            let _ = response_sender
+                                .send(error_response(StatusCode::BAD_REQUEST, "400 Bad Request"));
+                            continue;
+                        }
+                    };
+                    let str_host = match host {
+                        Ok(host) => host,
+                        Err(e) => {
+                            warn!("Service manager could not parse host header: {}", e);
+                            let _ = response_sender
+                                .send(error_response(StatusCode::BAD_REQUEST, "400 Bad Request"));
+                            continue;
+                        }
+                    };
+                    let service_id = service_id_for_host(str_host, &domain, wildcard_subdomains);
+
+                    if let Some(service) = services.get_mut(service_id) {
+                        let service_id = service_id.to_string();
+                        // Unlike other messages, a request a session can't take
+                        // yet is turned away rather than queued without bound
+                        match service.forward_request(request, response_sender.clone()) {
+                            Forwarded::Sent => {
+                                debug!(
+                                    request_id = request_id.as_str();
+                                    "Service manager forwarded request to service: {}",
+                                    service_id
+                                );
+                            }
+                            Forwarded::Busy => {
+                                warn!(
+                                    request_id = request_id.as_str();
+                                    "Service manager found service's queue full: {}",
+                                    service_id
+                                );
+                                let _ = response_sender.send(error_response(
+                                    StatusCode::SERVICE_UNAVAILABLE,
+                                    "503 Tunnel Busy",
+                                ));
+                            }
+                            Forwarded::Closed => {
+                                warn!(
+                                    request_id = request_id.as_str();
+                                    "Service manager failed to forward request: session closed"
+                                );
+                                let _ = response_sender.send(error_response(
+                                    StatusCode::BAD_GATEWAY,
+                                    "502 Bad Gateway",
+                                ));
+                                if service.sessions.is_empty() {
+                                    let fallback =
+                                        services.remove(&service_id).and_then(|s| s.fallback);
+                                    service_closed(
+                                        &mut held,
+                                        &mut tombstones,
+                                        &events,
+                                        &service_id,
+                                        fallback,
+                                        reclaim_ttl,
+                                    );
+                                }
+                            }
+                        }
+                    } else if let Some(tombstone) = tombstones
+                        .as_ref()
+                        .and_then(|tombstones| tombstones.recently_closed(service_id))
+                    {
+                        debug!(
+                            request_id = request_id.as_str();
+                            "Service manager found service recently closed: {}",
+                            service_id
+                        );
+                        if let Some(fallback) = &tombstone.fallback {
+                            let _ = response_sender.send(fallback_redirect(fallback, &request));
+                            continue;
+                        }
+                        let mut response =
+                            error_response(StatusCode::SERVICE_UNAVAILABLE, "503 Tunnel Offline");
+                        response.headers_mut().insert(
+                            hyper::http::header::RETRY_AFTER,
+                            HeaderValue::from_static(OFFLINE_RETRY_AFTER),
+                        );
+                        response.extensions_mut().insert(TunnelOffline);
+                        let _ = response_sender.send(response);
+                    } else {
+                        warn!(
+                            request_id = request_id.as_str();
+                            "Service manager could not find service: {}",
+                            service_id
+                        );
+                        let _ = response_sender.send(error_response(
+                            StatusCode::NOT_FOUND,
+                            "404 Service Not Found",
+                        ));
+                    }
+                }
+            }
+        }
+        debug!("Service manager stopped");
+    });
+}
+
+// The manager never waits on a session, which could itself be waiting on the
+// manager, so a message a full session can't take yet is sent from a task.
+// False if the session has already closed
+fn send_to_session(sender: &Sender<ServiceSessionMessage>, msg: ServiceSessionMessage) -> bool {
+    match sender.try_send(msg) {
+        Ok(()) => true,
+        Err(TrySendError::Full(msg)) => {
+            let sender = sender.clone();
+            task::spawn(async move {
+                let _ = sender.send(msg).await;
+            });
+            true
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+fn load_tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, TunnelError> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key)?))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| TunnelError::Parse("no PKCS#8 private key found".to_string()))?;
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, rustls::PrivateKey(key))
+        .map_err(|e| TunnelError::Parse(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn spawn_request_manager(
+    listener: TcpListener,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: Arc<Config>,
+    tls_acceptor: Option<TlsAcceptor>,
+    shutdown: watch::Receiver<bool>,
+) -> task::JoinHandle<io::Result<()>> {
+    debug!("Spawning request manager");
+    task::spawn(async move {
+        debug!("Request manager started");
+        if let Some(tls_acceptor) = tls_acceptor {
+            serve_tls(listener, service_mgr, config, tls_acceptor, shutdown).await;
+            return Ok(());
+        }
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let service_mgr = service_mgr.clone();
+            let config = config.clone();
+            let remote_addr = conn.remote_addr();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    handle_incoming_request(
+                        req,
+                        service_mgr.clone(),
+                        config.clone(),
+                        remote_addr,
+                        "http",
+                    )
+                }))
+            }
+        });
+
+        let incoming = AddrIncoming::from_listener(listener).map_err(io::Error::other)?;
+        // And run until shutdown, letting open connections finish their requests
+        Server::builder(incoming)
+            .serve(make_service)
+            .with_graceful_shutdown(shutting_down(shutdown))
+            .await
+            .map_err(|e| io::Error::other(format!("server error: {}", e)))
+    })
+}
+
+// hyper's Server only accepts plain TCP, so TLS connections are accepted by hand
+// and each one is served on its own task
+async fn serve_tls(
+    listener: TcpListener,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: Arc<Config>,
+    tls_acceptor: TlsAcceptor,
+    shutdown: watch::Receiver<bool>,
+) {
+    let mut connections = JoinSet::new();
+    loop {
+        let (socket, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("Request manager failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            Some(_) = connections.join_next() => continue,
+            _ = shutting_down(shutdown.clone()) => break,
+        };
+        let tls_acceptor = tls_acceptor.clone();
+        let service_mgr = service_mgr.clone();
+        let config = config.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let stream = match tls_acceptor.accept(socket).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Request manager failed TLS handshake: {}", e);
+                    return;
+                }
+            };
+            let service = service_fn(move |req: Request<Body>| {
+                handle_incoming_request(
+                    req,
+                    service_mgr.clone(),
+                    config.clone(),
+                    remote_addr,
+                    "https",
+                )
+            });
+            let connection = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades();
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = &mut connection => result,
+                _ = shutting_down(shutdown) => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                debug!("Request manager connection error: {}", e);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+}
+
+// With the `tracing` feature each request gets a span, which names the tunnel
+// once it's known and the status once the response head is ready. A W3C
+// traceparent is passed on to the client with the other headers, so the upstream
+// continues the browser's trace
+async fn handle_incoming_request(
+    req: Request<Body>,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: Arc<Config>,
+    remote_addr: SocketAddr,
+    scheme: &'static str,
+) -> Result<Response<Body>, Infallible> {
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::Instrument;
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            path = req.uri().path(),
+            traceparent = req
+                .headers()
+                .get("traceparent")
+                .and_then(|value| value.to_str().ok()),
+            service_id = tracing::field::Empty,
+            status = tracing::field::Empty,
+        );
+        let response = route_incoming_request(req, service_mgr, config, remote_addr, scheme)
+            .instrument(span.clone())
+            .await;
+        if let Ok(response) = &response {
+            span.record("status", response.status().as_u16());
+        }
+        response
+    }
+    #[cfg(not(feature = "tracing"))]
+    route_incoming_request(req, service_mgr, config, remote_addr, scheme).await
+}
+
+async fn route_incoming_request(
+    mut req: Request<Body>,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: Arc<Config>,
+    remote_addr: SocketAddr,
+    scheme: &'static str,
+) -> Result<Response<Body>, Infallible> {
+    trace!("Request manager received request: {:?}", req);
+    let host = match req.headers().get(hyper::http::header::HOST) {
+        Some(host) => host.to_str(),
+        None => {
+            warn!("Request manager could not find host header");
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("400 Bad Request"))
+                .unwrap());
+        }
+    };
+    let host = match host {
+        Ok(host) => host.to_string(),
+        Err(e) => {
+            warn!("Request manager could not parse host header: {}", e);
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("400 Bad Request"))
+                .unwrap());
+        }
+    };
+    if host == config.domain {
+        handle_root_request(req, service_mgr, config, remote_addr, scheme).await
+    } else {
+        add_forwarded_headers(&mut req, remote_addr, scheme);
+        let service_id = service_id_for_host(&host, &config.domain, config.wildcard_subdomains);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("service_id", service_id);
+        match config.fair_share.acquire(service_id) {
+            Some(slot) => {
+                req.extensions_mut().insert(slot);
+            }
+            None => {
+                warn!(
+                    "Request manager found service's share of the queue full: {}",
+                    service_id
+                );
+                return Ok(error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "503 Tunnel Busy",
+                ));
+            }
+        }
+        let (sender, mut receiver) = unbounded_channel();
+        let forwarded = service_mgr.try_send(ServiceManagerMessage::ForwardRequest {
+            request: req,
+            response_sender: sender,
+        });
+        if let Err(e) = forwarded {
+            warn!("Request manager could not queue request: {}", e);
+            return Ok(error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "503 Server Busy",
+            ));
+        }
+        // A session that closes before its primary stream arrives drops requests
+        // without answering them
+        let mut response = receiver
+            .recv()
+            .await
+            .unwrap_or_else(|| error_response(StatusCode::BAD_GATEWAY, "502 Bad Gateway"));
+        if response.extensions().get::<TunnelOffline>().is_some() {
+            let retry_after = response
+                .headers_mut()
+                .remove(hyper::http::header::RETRY_AFTER);
+            response = error_page(response.status(), &config.offline_page, &host);
+            if let Some(retry_after) = retry_after {
+                response
+                    .headers_mut()
+                    .insert(hyper::http::header::RETRY_AFTER, retry_after);
+            }
+        } else if response.extensions().get::<ServerError>().is_some() {
+            if let Some(page) = config.error_pages.get(&response.status()) {
+                response = error_page(response.status(), page, &host);
+            }
+        }
+        METRICS.response(response.status());
+        add_response_headers(
+            response.headers_mut(),
+            &config.response_headers,
+            config.force_response_headers,
+        );
+        Ok(response)
+    }
+}
+
+// Marks a response the server made up itself rather than one relayed from a
+// tunnel, only these are replaced by the configured error pages
+#[derive(Debug, Clone, Copy)]
+struct ServerError;
+
+// Sends a visitor on to the same path under a tunnel's fallback URL
+fn fallback_redirect(fallback: &str, request: &Request<Body>) -> Response<Body> {
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    Response::builder()
+        .status(StatusCode::TEMPORARY_REDIRECT)
+        .header(
+            hyper::http::header::LOCATION,
+            format!("{}{}", fallback.trim_end_matches('/'), path),
+        )
+        .body(Body::from("307 Tunnel Offline"))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(status)
+        .body(Body::from(body))
+        .unwrap();
+    response.extensions_mut().insert(ServerError);
+    response
+}
+
+fn error_page(status: StatusCode, template: &str, host: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(
+            hyper::http::header::CONTENT_TYPE,
+            "text/html; charset=utf-8",
+        )
+        .body(Body::from(template.replace("{{host}}", &escape_html(host))))
+        .unwrap()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Tells the upstream app who the request really came from, appending to any
+// X-Forwarded-For left by proxies in front of us
+fn add_forwarded_headers(req: &mut Request<Body>, remote_addr: SocketAddr, scheme: &'static str) {
+    let headers = req.headers_mut();
+    let ip = remote_addr.ip().to_canonical().to_string();
+    let forwarded_for = match headers
+        .get(X_FORWARDED_FOR)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(existing) => format!("{}, {}", existing, ip),
+        None => ip,
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(scheme));
+    if let Some(host) = headers.get(hyper::http::header::HOST).cloned() {
+        headers.insert(X_FORWARDED_HOST, host);
+    }
+}
+
+// Adds the configured headers to a tunneled response, leaving any the upstream
+// already set alone unless `force`d
+fn add_response_headers(headers: &mut HeaderMap, extra: &[(HeaderName, HeaderValue)], force: bool) {
+    let skip: Vec<bool> = extra
+        .iter()
+        .map(|(name, _)| !force && headers.contains_key(name))
+        .collect();
+    if force {
+        for (name, _) in extra {
+            headers.remove(name);
+        }
+    }
+    for ((name, value), skip) in extra.iter().zip(skip) {
+        if !skip {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+}
+
+// Drops the upstream's connection headers, hyper picks framing and keep-alive
+// for the browser's connection itself
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let connection_headers: Vec<String> = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .collect();
+    for name in connection_headers {
+        headers.remove(name.as_str());
+    }
+    let hop_by_hop: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| is_hop_by_hop(name.as_str()))
+        .cloned()
+        .collect();
+    for name in hop_by_hop {
+        headers.remove(name);
+    }
+}
+
+// Whether a client asked for the JSON `/start` response
+fn accepts_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get_all(hyper::http::header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"))
+}
+
+// A refused `POST /start` that's worth retrying once tunnels close or the
+// server stops draining
+fn start_unavailable(config: &Config, message: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(
+            hyper::http::header::RETRY_AFTER,
+            config.start_retry_after.as_secs(),
+        )
+        .body(Body::from(message))
+        .unwrap()
+}
+
+async fn handle_root_request(
+    req: Request<Body>,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: Arc<Config>,
+    remote_addr: SocketAddr,
+    scheme: &'static str,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() == Method::POST && req.uri().path() == "/start" {
+        trace!("Request manager received start request: {:?}", req);
+        let json = accepts_json(&req);
+        if let Some(limiter) = &config.start_limiter {
+            if !limiter.allow(remote_addr.ip()) {
+                warn!(
+                    "Request manager rate limited start request from {}",
+                    remote_addr.ip()
+                );
+                return Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .body(Body::from("429 Too Many Requests"))
+                    .unwrap());
+            }
+        }
+        if let Some(token) = &config.auth_token {
+            if !is_authorized(&req, token) {
+                warn!("Request manager rejected unauthorized start request");
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Body::from("401 Unauthorized"))
+                    .unwrap());
+            }
+        }
+        // Only honoured, and handed out, while disconnected subdomains are held
+        let reclaim_token = config.reclaim_ttl.map(|_| {
+            req.headers()
+                .get(X_RECLAIM_TOKEN)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+                .unwrap_or_else(generate_token)
+        });
+        let mut session = config.session.clone();
+        session.handshake_secret = generate_handshake_secret();
+        // Becomes the tunnel's if this is the client opening it
+        if config.load_balance {
+            session.join_token = Some(
+                req.headers()
+                    .get(X_JOIN_TOKEN)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+                    .unwrap_or_else(generate_token),
+            );
+        }
+        if let Some(credentials) = req.headers().get(X_BASIC_AUTH) {
+            session.basic_auth = credentials.to_str().ok().and_then(BasicAuth::parse);
+            if session.basic_auth.is_none() {
+                warn!("Request manager rejected malformed basic auth credentials");
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("400 Invalid Basic Auth"))
+                    .unwrap());
+            }
+        }
+        if let Some(fallback) = req.headers().get(X_FALLBACK_URL) {
+            session.fallback_url = fallback
+                .to_str()
+                .ok()
+                .filter(|url| is_fallback_url(url))
+                .map(str::to_string);
+            if session.fallback_url.is_none() {
+                warn!("Request manager rejected invalid fallback URL");
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("400 Invalid Fallback URL"))
+                    .unwrap());
+            }
+        }
+        let requested = requested_subdomain(req).await;
+        if let Some(subdomain) = &requested {
+            if !is_dns_label(subdomain) {
+                warn!(
+                    "Request manager rejected invalid subdomain: {:?}",
+                    subdomain
+                );
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from("400 Invalid Subdomain"))
+                    .unwrap());
+            }
+            if config.reserved_subdomains.contains(subdomain) {
+                warn!("Request manager rejected reserved subdomain: {}", subdomain);
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::from(format!("403 Subdomain Reserved: {}", subdomain)))
+                    .unwrap());
+            }
+        }
+        let mut attempts = 0;
+        let service_id = loop {
+            let service_id = requested
+                .clone()
+                .unwrap_or_else(|| generate_service_id(&config.key));
+            match spawn_service_session(
+                service_id.clone(),
+                service_mgr.clone(),
+                session.clone(),
+                reclaim_token.clone(),
+            )
+            .await
+            {
+                Registration::Registered => break service_id,
+                Registration::Full => {
+                    return Ok(start_unavailable(&config, "503 Tunnel Limit Reached"));
+                }
+                Registration::Draining => {
+                    return Ok(start_unavailable(&config, "503 Server Draining"));
+                }
+                Registration::Taken => {}
+            }
+            // A requested subdomain is simply taken, a generated one collided and
+            // can be rerolled
+            if requested.is_some() {
+                return Ok(Response::builder()
+                    .status(StatusCode::CONFLICT)
+                    .body(Body::from(format!(
+                        "409 Subdomain Already In Use: {}",
+                        service_id
+                    )))
+                    .unwrap());
+            }
+            attempts += 1;
+            if attempts == MAX_KEY_ATTEMPTS {
+                error!(
+                    "Request manager could not generate a free service id in {} attempts",
+                    attempts
+                );
+                return Ok(start_unavailable(&config, "503 No Free Subdomain"));
+            }
+            debug!(
+                "Request manager regenerating colliding service id: {}",
+                service_id
+            );
+        };
+        trace!("Request manager spawned service session: {}", service_id);
+        let mut response =
+            Response::builder().header(X_HANDSHAKE_SECRET, &session.handshake_secret);
+        if let Some(token) = reclaim_token {
+            response = response.header(X_RECLAIM_TOKEN, token);
+        }
+        if let Some(token) = &session.join_token {
+            response = response.header(X_JOIN_TOKEN, token);
+        }
+        // Older clients read the bare service id and build the rest themselves
+        if !json {
+            return Ok(response.body(Body::from(service_id)).unwrap());
+        }
+        // The public listener serves HTTPS too once it has a certificate
+        let default_port = if scheme == "https" { 443 } else { 80 };
+        let port = match config.http_port {
+            port if port == default_port => String::new(),
+            port => format!(":{}", port),
+        };
+        let started = serde_json::json!({
+            "url": format!("{}://{}.{}{}", scheme, service_id, config.domain, port),
+            "proxy": format!("{}:{}", config.domain, config.proxy_port),
+            "service_id": service_id,
+        });
+        Ok(response
+            .header(hyper::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(started.to_string()))
+            .unwrap())
+    } else if req.uri().path().starts_with("/admin/") {
+        handle_admin_request(req, service_mgr, config).await
+    } else if req.method() == Method::GET && req.uri().path() == "/metrics" {
+        Ok(Response::builder()
+            .header(
+                hyper::http::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4",
+            )
+            .body(Body::from(METRICS.render()))
+            .unwrap())
+    } else if req.method() == Method::GET && req.uri().path() == "/health" {
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::Stats { stats: sender })
+            .await
+            .unwrap();
+        let stats = receiver.await.unwrap();
+        Ok(Response::builder()
+            .header(hyper::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+                "{{\"services\":{},\"uptime_secs\":{},\"draining\":{}}}",
+                stats.services,
+                stats.uptime.as_secs(),
+                stats.draining
+            )))
+            .unwrap())
+    } else if matches!(*req.method(), Method::GET | Method::HEAD) && req.uri().path() == "/" {
+        Ok(Response::builder()
+            .header(
+                hyper::http::header::CONTENT_TYPE,
+                "text/html; charset=utf-8",
+            )
+            .body(Body::from(
+                config
+                    .landing_page
+                    .replace("{{domain}}", &escape_html(&config.domain)),
+            ))
+            .unwrap())
+    } else {
+        Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("404 Not Found"))
+            .unwrap())
+    }
+}
+
+// Operator endpoints for inspecting and closing tunnels, only served when an auth
+// token is configured
+async fn handle_admin_request(
+    req: Request<Body>,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: Arc<Config>,
+) -> Result<Response<Body>, Infallible> {
+    let token = match &config.auth_token {
+        Some(token) => token,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::from("403 Admin API Requires An Auth Token"))
+                .unwrap())
+        }
+    };
+    if !is_authorized(&req, token) {
+        warn!("Request manager rejected unauthorized admin request");
+        return Ok(Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::from("401 Unauthorized"))
+            .unwrap());
+    }
+    let path = req.uri().path();
+    if req.method() == Method::GET && path == "/admin/tunnels" {
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::ListServices { services: sender })
+            .await
+            .unwrap();
+        let tunnels: Vec<_> = receiver
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|service| {
+                let connected_at = service
+                    .connected_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default();
+                serde_json::json!({
+                    "service_id": service.service_id,
+                    "connected_at": connected_at.as_secs(),
+                    "requests": service.requests,
+                    "clients": service.clients,
+                    "bytes_received": service.bytes_received,
+                    "bytes_sent": service.bytes_sent,
+                })
+            })
+            .collect();
+        Ok(Response::builder()
+            .header(hyper::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::Value::from(tunnels).to_string()))
+            .unwrap())
+    } else if req.method() == Method::GET && path == "/admin/events" {
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::SubscribeEvents { events: sender })
+            .await
+            .unwrap();
+        let events = receiver.await.unwrap();
+        Ok(Response::builder()
+            .header(hyper::http::header::CONTENT_TYPE, "text/event-stream")
+            .header(hyper::http::header::CACHE_CONTROL, "no-cache")
+            .body(event_stream(events))
+            .unwrap())
+    } else if req.method() == Method::POST && (path == "/admin/drain" || path == "/admin/undrain") {
+        let draining = path == "/admin/drain";
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::Drain {
+                draining,
+                tunnels: sender,
+            })
+            .await
+            .unwrap();
+        let tunnels = receiver.await.unwrap();
+        Ok(Response::builder()
+            .header(hyper::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                serde_json::json!({ "draining": draining, "tunnels": tunnels }).to_string(),
+            ))
+            .unwrap())
+    } else if let Some(service_id) = path
+        .strip_prefix("/admin/tunnels/")
+        .filter(|_| req.method() == Method::DELETE)
+    {
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::KillService {
+                service_id: service_id.to_string(),
+                killed: sender,
+            })
+            .await
+            .unwrap();
+        if receiver.await.unwrap() {
+            info!(
+                "Request manager closed service by admin request: {}",
+                service_id
+            );
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        } else {
+            Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("404 Service Not Found"))
+                .unwrap())
+        }
+    } else {
+        Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("404 Not Found"))
+            .unwrap())
+    }
+}
+
+// Streams events until the subscriber disconnects or the service manager stops
+fn event_stream(mut events: broadcast::Receiver<ServiceEvent>) -> Body {
+    let (mut sender, body) = Body::channel();
+    task::spawn(async move {
+        loop {
+            let chunk = match events.recv().await {
+                Ok(event) => event.to_sse(),
+                // Comments are ignored by clients, but say something was missed
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    format!(": missed {} events\n\n", missed)
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+    body
+}
+
+fn is_authorized(req: &Request<Body>, token: &str) -> bool {
+    let provided = req
+        .headers()
+        .get(hyper::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided) => constant_time_eq(provided.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+// Compares every byte regardless of where the first mismatch is, so response
+// timing doesn't reveal how much of a guessed token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// A subdomain can be requested with the X-Requested-Subdomain header or as the
+// request body, the header wins if both are present
+async fn requested_subdomain(req: Request<Body>) -> Option<String> {
+    if let Some(header) = req.headers().get("X-Requested-Subdomain") {
+        match header.to_str() {
+            Ok(subdomain) if !subdomain.trim().is_empty() => {
+                return Some(subdomain.trim().to_lowercase())
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Request manager could not parse requested subdomain: {}", e),
+        }
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Request manager failed to read start request body: {}", e);
+            return None;
+        }
+    };
+    let subdomain = String::from_utf8_lossy(&body).trim().to_lowercase();
+    if subdomain.is_empty() {
+        None
+    } else {
+        Some(subdomain)
+    }
+}
+
+// 128 random bits, hex encoded, for reclaim and join tokens
+fn generate_token() -> String {
+    format!("{:032x}", random::<u128>())
+}
+
+// 256 random bits, hex encoded, the key for an HMAC-SHA256 of the service id
+fn generate_handshake_secret() -> String {
+    format!("{:032x}{:032x}", random::<u128>(), random::<u128>())
+}
+
+// An absolute http or https URL, which the Location header can point at as is
+fn is_fallback_url(url: &str) -> bool {
+    url.parse::<hyper::Uri>().is_ok_and(|uri| {
+        matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some()
+    })
+}
+
+// 1 to 63 letters, digits and hyphens, not starting or ending with a hyphen
+fn is_dns_label(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+// Tells apart the sessions serving a load balanced tunnel
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(0);
+
+// Only starts the session if the service manager registered it
+async fn spawn_service_session(
+    service_id: String,
+    service_mgr: Sender<ServiceManagerMessage>,
+    config: SessionConfig,
+    reclaim_token: Option<String>,
+) -> Registration {
+    debug!(service_id = service_id.as_str(); "Spawning service session");
+    let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+    let (sender, mut receiver) = channel(config.channel_capacity);
+    let session_sender = sender.clone();
+    // Weak so pending timeouts don't keep a closed session alive
+    let timeout_sender = sender.downgrade();
+    let traffic = Arc::new(Traffic::new(config.bandwidth_limit));
+    let (registered_sender, registered_receiver) = oneshot::channel();
+    service_mgr
+        .send(ServiceManagerMessage::RegisterService {
+            service_id: service_id.clone(),
+            session,
+            sender,
+            traffic: traffic.clone(),
+            secret: config.handshake_secret.clone(),
+            fallback: config.fallback_url.clone(),
+            reclaim_token,
+            join_token: config.join_token.clone(),
+            registered: registered_sender,
+        })
+        .await
+        .unwrap();
+    let registration = registered_receiver.await.unwrap_or(Registration::Full);
+    if registration != Registration::Registered {
+        return registration;
+    }
+    trace!(service_id = service_id.as_str(); "Service session registered with service manager");
+    task::spawn(async move {
+        debug!(service_id = service_id.as_str(); "Service session started");
+        let stream = loop {
+            let msg = match config.idle_timeout {
+                Some(idle) => match timeout(idle, receiver.recv()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        info!(
+                            service_id = service_id.as_str();
+                            "Service session closing after {}s without a primary stream",
+                            idle.as_secs()
+                        );
+                        let _ = service_mgr
+                            .send(ServiceManagerMessage::LeaveService {
+                                service_id: service_id.clone(),
+                                session,
+                            })
+                            .await;
+                        return;
+                    }
+                },
+                None => receiver.recv().await,
+            };
+            // The service manager let go of the session before its stream arrived
+            let Some(msg) = msg else {
+                debug!(service_id = service_id.as_str(); "Service session closed");
+                return;
+            };
+            match msg {
+                ServiceSessionMessage::RecvPrimaryStream(stream) => {
+                    trace!(
+                        service_id = service_id.as_str();
+                        "Service session received primary stream"
+                    );
+                    break stream;
+                }
+                ServiceSessionMessage::RecvRequest(_, _) => {}
+                ServiceSessionMessage::RecvMessage(_) => {}
+                ServiceSessionMessage::RequestTimeout(_) => {}
+                ServiceSessionMessage::RequestTooLarge(_) => {}
+                ServiceSessionMessage::ResponseDropped(_) => {}
+                ServiceSessionMessage::Shutdown => {}
+                ServiceSessionMessage::Close => {
+                    debug!(service_id = service_id.as_str(); "Service session closed");
+                    return;
+                }
+            }
+        };
+        let (reader, writer) = tokio::io::split(stream);
+        // Frames are small and many, so reads and writes go through buffers
+        // rather than hitting the socket for every length prefix and payload
+        let mut reader = tokio::io::BufReader::with_capacity(config.buffer_size, reader);
+        let mut writer = BufWriter::with_capacity(config.buffer_size, writer);
+
+        // Bounded so request bodies are only read from browsers as fast as the
+        // primary stream takes them. Pings, aborts and window grants skip ahead
+        // on their own queue, so they're never stuck behind a busy stream
+        let (writer_sender, mut writer_receiver) = channel::<Message>(config.channel_capacity);
+        let (control_sender, mut control_receiver) = unbounded_channel::<Message>();
+        let writer_service_id = service_id.clone();
+        let writer_traffic = traffic.clone();
+        let writer_service_mgr = service_mgr.clone();
+        let writer_session_sender = timeout_sender.clone();
+        let dump_messages = config.dump_messages;
+        task::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    biased;
+                    Some(message) = control_receiver.recv() => message,
+                    message = writer_receiver.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                };
+                if let Some(max_len) = dump_messages {
+                    trace!(
+                        service_id = writer_service_id.as_str();
+                        "Service session sending {}",
+                        dump(&message, max_len)
+                    );
+                }
+                let len = message.data().len();
+                // Flushed once neither queue has anything else to go out, so a
+                // burst of messages reaches the socket together
+                let written = match write_message(&mut writer, &message).await {
+                    Ok(()) if control_receiver.is_empty() && writer_receiver.is_empty() => {
+                        writer.flush().await
+                    }
+                    written => written,
+                };
+                if let Err(e) = written {
+                    warn!(
+                        service_id = writer_service_id.as_str();
+                        "Service session failed to write to primary stream: {}",
+                        e
+                    );
+                    // Nothing more can reach the client, so the session leaves
+                    // its tunnel and closes, answering whatever it was still
+                    // waiting on with 502
+                    let _ = writer_service_mgr
+                        .send(ServiceManagerMessage::LeaveService {
+                            service_id: writer_service_id,
+                            session,
+                        })
+                        .await;
+                    if let Some(session_sender) = writer_session_sender.upgrade() {
+                        let _ = session_sender.send(ServiceSessionMessage::Close).await;
+                    }
+                    break;
+                }
+                METRICS.bytes_sent(len);
+                writer_traffic.sent(len);
+            }
+        });
+
+        let reader_service_id = service_id.clone();
+        let reader_service_mgr = service_mgr.clone();
+        let max_message_bytes = config.max_message_bytes;
+        let reader_traffic = traffic.clone();
+        let reader = task::spawn(async move {
+            loop {
+                let message = match read_message_max(&mut reader, max_message_bytes).await {
+                    Ok(Ok(message)) => message,
+                    // Handled like the client giving up on the response
+                    Ok(Err(oversized)) => {
+                        warn!(
+                            service_id = reader_service_id.as_str(),
+                            stream_id = oversized.id;
+                            "Service session skipped {} byte message from client over the limit",
+                            oversized.len
+                        );
+                        Message::Abort { id: oversized.id }
+                    }
+                    Err(e) => {
+                        warn!(
+                            service_id = reader_service_id.as_str();
+                            "Service session failed to read from primary stream: {}",
+                            e
+                        );
+                        // Once the manager drops its sender and this task exits, the
+                        // session loop sees its channel close and shuts down
+                        let _ = reader_service_mgr
+                            .send(ServiceManagerMessage::LeaveService {
+                                service_id: reader_service_id,
+                                session,
+                            })
+                            .await;
+                        break;
+                    }
+                };
+                if let Some(max_len) = dump_messages {
+                    trace!(
+                        service_id = reader_service_id.as_str();
+                        "Service session received {}",
+                        dump(&message, max_len)
+                    );
+                }
+                METRICS.bytes_received(message.data().len());
+                reader_traffic.received(message.data().len());
+                // Holding back response bodies over the bandwidth limit stops
+                // reading from the client until they'd fit
+                if let Message::Body { data, .. } = &message {
+                    reader_traffic.throttle(data.len()).await;
+                }
+                // Waiting here stops reading from the client while the
+                // session is behind
+                if session_sender
+                    .send(ServiceSessionMessage::RecvMessage(message))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        let mut next_id: u32 = 0;
+        // Requests still waiting on a response head from the client
+        let mut pending: HashMap<u32, UnboundedSender<Response<Body>>> = HashMap::new();
+        // Responses whose body is still being streamed back from the client
+        let mut streaming: HashMap<u32, Streaming> = HashMap::new();
+        // Encodings requests accepted, for responses to be compressed with
+        let mut encodings: HashMap<u32, Encoding> = HashMap::new();
+        // Requests that haven't finished yet, each holds its slot under the
+        // in-flight limit until it's removed
+        let mut in_flight: HashMap<u32, InFlight> = HashMap::new();
+        let in_flight_limit = config
+            .max_in_flight
+            .map(|max| Arc::new(Semaphore::new(max)));
+        // HEAD requests, whose responses declare a length without sending a
+        // body, until their end arrives
+        let mut heads: HashSet<u32> = HashSet::new();
+        // Bytes still owed by streaming responses that declared a Content-Length
+        let mut remaining: HashMap<u32, u64> = HashMap::new();
+        // Upgrade requests waiting to hear whether the client switched protocols
+        let mut upgrades: HashMap<u32, oneshot::Sender<Receiver<Option<Vec<u8>>>>> = HashMap::new();
+        let mut heartbeat = interval_at(
+            time::Instant::now() + config.heartbeat_interval,
+            config.heartbeat_interval,
+        );
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut next_ping: u32 = 0;
+        // The ping still waiting on a pong and when it was sent
+        let mut awaiting_pong: Option<(u32, Instant)> = None;
+        // Pushed back by every request
+        let idle = time::sleep(config.idle_timeout.unwrap_or_default());
+        tokio::pin!(idle);
+        loop {
+            let msg = tokio::select! {
+                msg = receiver.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = &mut idle, if config.idle_timeout.is_some() => {
+                    let timeout = config.idle_timeout.unwrap_or_default();
+                    if !in_flight.is_empty() {
+                        idle.as_mut().reset(time::Instant::now() + timeout);
+                        continue;
+                    }
+                    info!(
+                        service_id = service_id.as_str();
+                        "Service session closing after {}s without requests",
+                        timeout.as_secs()
+                    );
+                    let _ = service_mgr
+                        .send(ServiceManagerMessage::LeaveService {
+                            service_id: service_id.clone(),
+                            session,
+                        })
+                        .await;
+                    reader.abort();
+                    break;
+                }
+                _ = heartbeat.tick() => {
+                    match awaiting_pong {
+                        Some((_, sent)) if sent.elapsed() >= config.heartbeat_timeout => {
+                            warn!(
+                                service_id = service_id.as_str();
+                                "Service session closing unresponsive primary stream"
+                            );
+                            let _ = service_mgr
+                                .send(ServiceManagerMessage::LeaveService {
+                                    service_id: service_id.clone(),
+                                    session,
+                                })
+                                .await;
+                            reader.abort();
+                            break;
+                        }
+                        Some(_) => {}
+                        None => {
+                            let _ = control_sender.send(Message::Ping { id: next_ping });
+                            awaiting_pong = Some((next_ping, Instant::now()));
+                            next_ping = next_ping.wrapping_add(1);
+                        }
+                    }
+                    continue;
+                }
+            };
+            match msg {
+                // Dropping it closes the connection rather than leaving it open
+                // with nothing reading from it
+                ServiceSessionMessage::RecvPrimaryStream(_) => {
+                    warn!(
+                        service_id = service_id.as_str();
+                        "Service session rejected a second primary stream"
+                    );
+                }
+                ServiceSessionMessage::RecvRequest(mut req, response_sender) => {
+                    if let Some(timeout) = config.idle_timeout {
+                        idle.as_mut().reset(time::Instant::now() + timeout);
+                    }
+                    let id = next_id;
+                    next_id = next_id.wrapping_add(1);
+                    let request_id = req
+                        .headers()
+                        .get(X_REQUEST_ID)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or("-")
+                        .to_string();
+                    trace!(
+                        service_id = service_id.as_str(),
+                        request_id = request_id.as_str(),
+                        stream_id = id;
+                        "Service session received request from socket connection manager"
+                    );
+                    // The CORS origin this request's response is for, preflights are
+                    // answered here since browsers send them without credentials
+                    let cors_origin = config
+                        .cors
+                        .as_ref()
+                        .and_then(|cors| cors.allow_origin(req.headers()));
+                    if let (Some(cors), Some(origin)) = (&config.cors, &cors_origin) {
+                        if Cors::is_preflight(&req) {
+                            let _ = response_sender.send(cors.preflight(origin.clone()));
+                            continue;
+                        }
+                    }
+                    if let Some(auth) = &config.basic_auth {
+                        if !auth.allows(req.headers()) {
+                            debug!(
+                                service_id = service_id.as_str(),
+                                request_id = request_id.as_str(),
+                                stream_id = id;
+                                "Service session challenged request without valid credentials"
+                            );
+                            let mut response =
+                                error_response(StatusCode::UNAUTHORIZED, "401 Unauthorized");
+                            response.headers_mut().insert(
+                                hyper::http::header::WWW_AUTHENTICATE,
+                                HeaderValue::from_str(&format!("Basic realm=\"{}\"", service_id))
+                                    .unwrap(),
+                            );
+                            let _ = response_sender.send(response);
+                            continue;
+                        }
+                        // The credentials were for the tunnel, not the upstream
+                        req.headers_mut().remove(hyper::http::header::AUTHORIZATION);
+                    }
+                    // Bodies that declare their length can be turned away up front
+                    if config
+                        .max_body_bytes
+                        .is_some_and(|max| req.body().size_hint().lower() > max)
+                    {
+                        warn!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id.as_str(),
+                            stream_id = id;
+                            "Service session rejected request body over the size limit"
+                        );
+                        let _ = response_sender.send(error_response(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            "413 Payload Too Large",
+                        ));
+                        continue;
+                    }
+                    // Requests over the limit wait for a slot off the session task and
+                    // come back through here holding it
+                    let permit = match &in_flight_limit {
+                        Some(limit) => match req.extensions_mut().remove::<Admitted>() {
+                            Some(Admitted(permit)) => Some(permit),
+                            None => match limit.clone().try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    debug!(
+                                        service_id = service_id.as_str(),
+                                        request_id = request_id.as_str();
+                                        "Service session queueing request over the in-flight limit"
+                                    );
+                                    queue_request(
+                                        req,
+                                        response_sender,
+                                        limit.clone(),
+                                        config.queue_timeout,
+                                        timeout_sender.clone(),
+                                    );
+                                    continue;
+                                }
+                            },
+                        },
+                        None => None,
+                    };
+                    let window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+                    in_flight.insert(
+                        id,
+                        InFlight {
+                            request_id: request_id.clone(),
+                            window: window.clone(),
+                            access: config
+                                .access_log
+                                .as_ref()
+                                .map(|log| AccessEntry::new(log.clone(), &service_id, &req)),
+                            cors_origin,
+                            _permit: permit,
+                        },
+                    );
+                    METRICS.request_forwarded();
+                    pending.insert(id, response_sender);
+                    if req.method() == Method::HEAD {
+                        heads.insert(id);
+                    }
+                    // HEAD responses keep the length of the uncompressed body
+                    if config.compress && req.method() != Method::HEAD {
+                        if let Some(encoding) = Encoding::negotiate(req.headers()) {
+                            encodings.insert(id, encoding);
+                        }
+                    }
+                    let session_sender = timeout_sender.clone();
+                    let timeout_sender = timeout_sender.clone();
+                    task::spawn(async move {
+                        sleep(config.request_timeout).await;
+                        if let Some(sender) = timeout_sender.upgrade() {
+                            let _ = sender.send(ServiceSessionMessage::RequestTimeout(id)).await;
+                        }
+                    });
+                    let upgrade = if is_upgrade_request(&req) {
+                        let (sender, receiver) = oneshot::channel();
+                        upgrades.insert(id, sender);
+                        Some(receiver)
+                    } else {
+                        None
+                    };
+
+                    // Streaming the request body can take a while, so do it off the
+                    // session task to keep other requests flowing
+                    let writer_sender = writer_sender.clone();
+                    let service_id = service_id.clone();
+                    let traffic = traffic.clone();
+                    task::spawn(async move {
+                        forward_request(
+                            id,
+                            req,
+                            upgrade,
+                            config.max_body_bytes,
+                            &window,
+                            &traffic,
+                            &writer_sender,
+                            &session_sender,
+                        )
+                        .await;
+                        trace!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id.as_str(),
+                            stream_id = id;
+                            "Service session forwarded request to client"
+                        );
+                    });
+                }
+                ServiceSessionMessage::RecvMessage(Message::Response { id, data }) => 'block: {
+                    let response_sender = match pending.remove(&id) {
+                        Some(response_sender) => response_sender,
+                        None => {
+                            warn!(
+                                service_id = service_id.as_str(),
+                                request_id = request_id(&in_flight, id),
+                                stream_id = id;
+                                "Service session received response for unknown request"
+                            );
+                            break 'block;
+                        }
+                    };
+                    let upgrade = upgrades.remove(&id);
+                    let encoding = encodings.remove(&id);
+                    let head = heads.remove(&id);
+                    let mut headers = response_header_buffer(&data);
+                    let mut resp = httparse::Response::new(&mut headers);
+                    match resp.parse(&data) {
+                        Ok(httparse::Status::Complete(_)) => {}
+                        Err(e) => {
+                            warn!(
+                                service_id = service_id.as_str();
+                                "Service session failed to parse response from client: {}",
+                                e
+                            );
+                            let _ = control_sender.send(Message::Abort { id });
+                            let _ = response_sender
+                                .send(error_response(StatusCode::BAD_GATEWAY, "502 Bad Gateway"));
+                            finish_request(&mut in_flight, id, StatusCode::BAD_GATEWAY);
+                            break 'block;
+                        }
+                        Ok(httparse::Status::Partial) => {
+                            let _ = response_sender
+                                .send(error_response(StatusCode::BAD_REQUEST, "400 Bad Request"));
+                            finish_request(&mut in_flight, id, StatusCode::BAD_REQUEST);
+                            break 'block;
+                        }
+                    };
+                    let headers = resp
+                        .headers
+                        .iter()
+                        .filter(|h| **h != httparse::EMPTY_HEADER);
+                    // httparse takes any three digits, not all of them are a status
+                    let (Some(version), Some(Ok(status))) =
+                        (resp.version, resp.code.map(StatusCode::from_u16))
+                    else {
+                        warn!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session received invalid status line from client"
+                        );
+                        let _ = control_sender.send(Message::Abort { id });
+                        let _ = response_sender
+                            .send(error_response(StatusCode::BAD_GATEWAY, "502 Bad Gateway"));
+                        finish_request(&mut in_flight, id, StatusCode::BAD_GATEWAY);
+                        break 'block;
+                    };
+                    let version = match version {
+                        1 => Version::HTTP_11,
+                        _ => Version::HTTP_10,
+                    };
+                    let content_length = match declared_length(resp.headers) {
+                        Ok(content_length) => content_length,
+                        Err(()) => {
+                            warn!(
+                                service_id = service_id.as_str(),
+                                request_id = request_id(&in_flight, id),
+                                stream_id = id;
+                                "Service session received invalid content length from client"
+                            );
+                            let _ = control_sender.send(Message::Abort { id });
+                            let _ = response_sender
+                                .send(error_response(StatusCode::BAD_GATEWAY, "502 Bad Gateway"));
+                            finish_request(&mut in_flight, id, StatusCode::BAD_GATEWAY);
+                            break 'block;
+                        }
+                    };
+                    let mut r = Response::builder().version(version).status(status);
+                    for header in headers {
+                        r = r.header(header.name, header.value);
+                    }
+                    if status != StatusCode::SWITCHING_PROTOCOLS {
+                        strip_hop_by_hop(r.headers_mut().unwrap());
+                    }
+                    if config.request_id_header {
+                        if let Some(value) = in_flight
+                            .get(&id)
+                            .and_then(|request| HeaderValue::from_str(&request.request_id).ok())
+                        {
+                            r.headers_mut().unwrap().insert(X_REQUEST_ID, value);
+                        }
+                    }
+                    if let (Some(cors), Some(origin)) = (
+                        &config.cors,
+                        in_flight
+                            .get(&id)
+                            .and_then(|request| request.cors_origin.clone()),
+                    ) {
+                        cors.apply(r.headers_mut().unwrap(), origin);
+                    }
+                    trace!(
+                        service_id = service_id.as_str(),
+                        request_id = request_id(&in_flight, id),
+                        stream_id = id;
+                        "Service session received and parsed response from client"
+                    );
+                    // After a 101 the body frames carry the raw upgraded connection
+                    // instead of a response body
+                    let (body, chunk_sender) = match upgrade {
+                        Some(upgrade) if status == StatusCode::SWITCHING_PROTOCOLS => {
+                            let (chunk_sender, chunk_receiver) = channel(config.channel_capacity);
+                            let _ = upgrade.send(chunk_receiver);
+                            (Body::empty(), Some(chunk_sender))
+                        }
+                        // Its Content-Length is passed on, but any body the
+                        // upstream sent anyway is dropped as it arrives
+                        _ if head => (Body::empty(), None),
+                        _ => {
+                            let (body, chunk_sender) = streamed_body(config.channel_capacity);
+                            (body, Some(chunk_sender))
+                        }
+                    };
+                    let chunk_sender = match (encoding, chunk_sender) {
+                        (Some(encoding), Some(chunk_sender))
+                            if compress::should_compress(status, r.headers_ref().unwrap()) =>
+                        {
+                            let headers = r.headers_mut().unwrap();
+                            headers.remove(hyper::header::CONTENT_LENGTH);
+                            headers.insert(
+                                hyper::header::CONTENT_ENCODING,
+                                HeaderValue::from_static(encoding.as_str()),
+                            );
+                            headers.append(
+                                hyper::header::VARY,
+                                HeaderValue::from_static("accept-encoding"),
+                            );
+                            Some(compress::compressed(
+                                encoding,
+                                chunk_sender,
+                                config.channel_capacity,
+                            ))
+                        }
+                        (_, chunk_sender) => chunk_sender,
+                    };
+                    if let Some(request) = in_flight.get_mut(&id) {
+                        request.answered(status);
+                    }
+                    // The browser may have gone away while the client was responding
+                    if response_sender.send(r.body(body).unwrap()).is_err() {
+                        debug!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session dropped response for disconnected request"
+                        );
+                        let _ = control_sender.send(Message::Abort { id });
+                        in_flight.remove(&id);
+                        break 'block;
+                    }
+                    match chunk_sender {
+                        Some(chunk_sender) => {
+                            let relay = relay_body(
+                                id,
+                                chunk_sender,
+                                control_sender.clone(),
+                                timeout_sender.clone(),
+                            );
+                            streaming.insert(id, relay);
+                        }
+                        // Answered, all that's left is its end
+                        None => {
+                            heads.insert(id);
+                        }
+                    }
+                    // These responses never have a body, whatever length they declare
+                    let bodiless = head
+                        || status.is_informational()
+                        || status == StatusCode::NO_CONTENT
+                        || status == StatusCode::NOT_MODIFIED;
+                    if let Some(content_length) = content_length.filter(|_| !bodiless) {
+                        remaining.insert(id, content_length);
+                    }
+                }
+                ServiceSessionMessage::RecvMessage(Message::Body { id, data }) => {
+                    match streaming.get(&id) {
+                        Some(relay) => {
+                            if let Some(left) = remaining.get_mut(&id) {
+                                if data.len() as u64 > *left {
+                                    warn!(
+                                        service_id = service_id.as_str(),
+                                        request_id = request_id(&in_flight, id),
+                                        stream_id = id;
+                                        "Service session received more body than the content length"
+                                    );
+                                    // Dropping the chunk sender aborts the body
+                                    remaining.remove(&id);
+                                    in_flight.remove(&id);
+                                    streaming.remove(&id);
+                                    let _ = control_sender.send(Message::Abort { id });
+                                    continue;
+                                }
+                                *left -= data.len() as u64;
+                            }
+                            if let Some(request) = in_flight.get_mut(&id) {
+                                request.sent(data.len());
+                            }
+                            // Whatever the window allows is buffered until the browser
+                            // reads it, a client sending past it would be buffered
+                            // without limit
+                            let permit = relay.window.try_acquire_many(data.len() as u32);
+                            match permit.map(|permit| permit.forget()) {
+                                Ok(()) => {
+                                    let _ = relay.chunk_sender.send(Some(data));
+                                }
+                                Err(_) => {
+                                    warn!(
+                                        service_id = service_id.as_str(),
+                                        request_id = request_id(&in_flight, id),
+                                        stream_id = id;
+                                        "Service session received more body than the window allowed"
+                                    );
+                                    remaining.remove(&id);
+                                    in_flight.remove(&id);
+                                    streaming.remove(&id);
+                                    let _ = control_sender.send(Message::Abort { id });
+                                }
+                            }
+                        }
+                        // Late responses to timed out requests land here too
+                        None => trace!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session received body for unknown response"
+                        ),
+                    }
+                }
+                ServiceSessionMessage::RecvMessage(Message::End { id }) => {
+                    let left = remaining.remove(&id).unwrap_or(0);
+                    match streaming.remove(&id) {
+                        Some(_) if left > 0 => warn!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session received {} bytes less body than the content length",
+                            left
+                        ),
+                        None if heads.remove(&id) => {}
+                        Some(relay) => {
+                            let _ = relay.chunk_sender.send(None);
+                        }
+                        None => warn!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session received end for unknown response"
+                        ),
+                    }
+                    in_flight.remove(&id);
+                }
+                ServiceSessionMessage::RecvMessage(Message::Abort { id }) => {
+                    warn!(
+                        service_id = service_id.as_str(),
+                        request_id = request_id(&in_flight, id),
+                        stream_id = id;
+                        "Service session received aborted response from client"
+                    );
+                    in_flight.remove(&id);
+                    upgrades.remove(&id);
+                    encodings.remove(&id);
+                    heads.remove(&id);
+                    remaining.remove(&id);
+                    // Dropping the chunk sender aborts a body that's already streaming
+                    if streaming.remove(&id).is_none() {
+                        if let Some(response_sender) = pending.remove(&id) {
+                            let _ = response_sender
+                                .send(error_response(StatusCode::BAD_GATEWAY, "502 Bad Gateway"));
+                        }
+                    }
+                }
+                ServiceSessionMessage::RequestTimeout(id) => {
+                    // Only requests still waiting on a response head time out
+                    if let Some(response_sender) = pending.remove(&id) {
+                        warn!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session timed out waiting for response"
+                        );
+                        finish_request(&mut in_flight, id, StatusCode::GATEWAY_TIMEOUT);
+                        upgrades.remove(&id);
+                        encodings.remove(&id);
+                        heads.remove(&id);
+                        let _ = control_sender.send(Message::Abort { id });
+                        let _ = response_sender.send(error_response(
+                            StatusCode::GATEWAY_TIMEOUT,
+                            "504 Gateway Timeout",
+                        ));
+                    }
+                }
+                ServiceSessionMessage::RequestTooLarge(id) => {
+                    warn!(
+                        service_id = service_id.as_str(),
+                        request_id = request_id(&in_flight, id),
+                        stream_id = id;
+                        "Service session aborted request body over the size limit"
+                    );
+                    // The client may already be responding, in which case the body it
+                    // was sending gets cut off instead
+                    if let Some(response_sender) = pending.remove(&id) {
+                        finish_request(&mut in_flight, id, StatusCode::PAYLOAD_TOO_LARGE);
+                        upgrades.remove(&id);
+                        encodings.remove(&id);
+                        heads.remove(&id);
+                        let _ = response_sender.send(error_response(
+                            StatusCode::PAYLOAD_TOO_LARGE,
+                            "413 Payload Too Large",
+                        ));
+                    }
+                }
+                ServiceSessionMessage::ResponseDropped(id) => {
+                    // Already over if its end arrived first
+                    if streaming.remove(&id).is_some() {
+                        debug!(
+                            service_id = service_id.as_str(),
+                            request_id = request_id(&in_flight, id),
+                            stream_id = id;
+                            "Service session aborted response nothing was reading"
+                        );
+                        remaining.remove(&id);
+                        in_flight.remove(&id);
+                        let _ = control_sender.send(Message::Abort { id });
+                    }
+                }
+                ServiceSessionMessage::Shutdown => {
+                    let _ = control_sender.send(Message::Shutdown);
+                }
+                ServiceSessionMessage::Close => {
+                    reader.abort();
+                    break;
+                }
+                ServiceSessionMessage::RecvMessage(Message::Shutdown) => {
+                    warn!(
+                        service_id = service_id.as_str();
+                        "Service session received unexpected shutdown from client"
+                    );
+                }
+                ServiceSessionMessage::RecvMessage(Message::Window { id, bytes }) => {
+                    if let Some(request) = in_flight.get(&id) {
+                        request.window.add_permits(bytes as usize);
+                    }
+                }
+                ServiceSessionMessage::RecvMessage(Message::Pong { id }) => {
+                    if matches!(awaiting_pong, Some((ping, _)) if ping == id) {
+                        awaiting_pong = None;
+                    }
+                }
+                ServiceSessionMessage::RecvMessage(
+                    Message::Request { id, .. } | Message::Ping { id },
+                ) => {
+                    warn!(
+                        service_id = service_id.as_str(), stream_id = id;
+                        "Service session received unexpected request from client"
+                    );
+                }
+            }
+        }
+        debug!(service_id = service_id.as_str(); "Service session closed");
+        for (_, response_sender) in pending {
+            let _ =
+                response_sender.send(error_response(StatusCode::BAD_GATEWAY, "502 Bad Gateway"));
+        }
+    });
+    Registration::Registered
+}
+
+// A request a session is still working on
+#[derive(Debug)]
+struct InFlight {
+    /// Its X-Request-Id, for logging
+    request_id: String,
+    /// Body bytes the client has room for, its body is only read from the
+    /// browser as fast as the client grants more
+    window: Arc<Semaphore>,
+    /// Written to the access log once the request is removed
+    access: Option<AccessEntry>,
+    /// Access-Control-Allow-Origin for its response, if it came from an allowed origin
+    cors_origin: Option<HeaderValue>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl InFlight {
+    fn answered(&mut self, status: StatusCode) {
+        if let Some(access) = &mut self.access {
+            access.status = status;
+        }
+    }
+
+    fn sent(&mut self, bytes: usize) {
+        if let Some(access) = &mut self.access {
+            access.bytes += bytes as u64;
+        }
+    }
+}
+
+// Whatever is still sending the request's body stops once the session is done
+// with it
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.window.close();
+    }
+}
+
+// Records the status the session answered a request with and stops tracking it
+fn finish_request(in_flight: &mut HashMap<u32, InFlight>, id: u32, status: StatusCode) {
+    if let Some(mut request) = in_flight.remove(&id) {
+        request.answered(status);
+    }
+}
+
+// Slot under the in-flight limit carried by a request that had to queue for it
+struct Admitted(OwnedSemaphorePermit);
+
+// Waits up to `queue_timeout` for a slot under the session's in-flight limit,
+// then hands the request back to the session holding it or answers 503
+fn queue_request(
+    mut req: Request<Body>,
+    response_sender: UnboundedSender<Response<Body>>,
+    limit: Arc<Semaphore>,
+    queue_timeout: Duration,
+    session_sender: WeakSender<ServiceSessionMessage>,
+) {
+    task::spawn(async move {
+        match timeout(queue_timeout, limit.acquire_owned()).await {
+            Ok(Ok(permit)) => {
+                req.extensions_mut().insert(Admitted(permit));
+                if let Some(session_sender) = session_sender.upgrade() {
+                    let _ = session_sender
+                        .send(ServiceSessionMessage::RecvRequest(req, response_sender))
+                        .await;
+                }
+            }
+            _ => {
+                warn!("Service session timed out queueing request over the in-flight limit");
+                let _ = response_sender.send(error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "503 Tunnel Busy",
+                ));
+            }
+        }
+    });
+}
+
+// The X-Request-Id of a request in flight for log lines
+fn request_id(in_flight: &HashMap<u32, InFlight>, id: u32) -> &str {
+    in_flight
+        .get(&id)
+        .map_or("-", |request| request.request_id.as_str())
+}
+
+// The tunnel a Host is routed to, with `wildcard` only the label right before the
+// domain names it so the upstream can use subdomains of its own
+fn service_id_for_host<'a>(host: &'a str, domain: &str, wildcard: bool) -> &'a str {
+    match host.strip_suffix(&format!(".{}", domain)) {
+        Some(subdomain) if wildcard => subdomain.rsplit('.').next().unwrap_or(subdomain),
+        Some(subdomain) => subdomain,
+        None => host,
+    }
+}
+
+// Reuses the X-Request-Id a request came in with, or gives it a new one
+fn ensure_request_id(headers: &mut HeaderMap) -> String {
+    if let Some(request_id) = headers
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+    {
+        return request_id.to_string();
+    }
+    let request_id = format!("{:016x}", random::<u64>());
+    headers.insert(X_REQUEST_ID, HeaderValue::from_str(&request_id).unwrap());
+    request_id
+}
+
+// Returns a body fed by the chunks sent on the returned sender, it completes on
+// `None` and is aborted if the sender is dropped first. Up to `capacity` chunks
+// wait for the browser to read them
+fn streamed_body(capacity: usize) -> (Body, Sender<Option<Vec<u8>>>) {
+    let (mut body_sender, body) = Body::channel();
+    let (chunk_sender, mut chunk_receiver) = channel::<Option<Vec<u8>>>(capacity);
+    task::spawn(async move {
+        while let Some(chunk) = chunk_receiver.recv().await {
+            match chunk {
+                Some(chunk) => {
+                    if body_sender.send_data(chunk.into()).await.is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            }
+        }
+        body_sender.abort();
+    });
+    (body, chunk_sender)
+}
+
+// A response body the client is streaming, its chunks are relayed on as the
+// browser takes them
+struct Streaming {
+    chunk_sender: UnboundedSender<Option<Vec<u8>>>,
+    /// How much more the client may send before it's granted more
+    window: Arc<Semaphore>,
+}
+
+// Relays the chunks of response `id` on to `chunk_sender`, granting the client
+// another window of however much was taken each time. The session hears when
+// nothing is reading them anymore
+fn relay_body(
+    id: u32,
+    chunk_sender: Sender<Option<Vec<u8>>>,
+    control_sender: UnboundedSender<Message>,
+    session_sender: WeakSender<ServiceSessionMessage>,
+) -> Streaming {
+    let window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+    let (relay_sender, mut relay_receiver) = unbounded_channel::<Option<Vec<u8>>>();
+    let granted = window.clone();
+    task::spawn(async move {
+        while let Some(chunk) = relay_receiver.recv().await {
+            let bytes = chunk.as_ref().map(Vec::len);
+            if chunk_sender.send(chunk).await.is_err() {
+                if let Some(session_sender) = session_sender.upgrade() {
+                    let _ = session_sender
+                        .send(ServiceSessionMessage::ResponseDropped(id))
+                        .await;
+                }
+                return;
+            }
+            let Some(bytes) = bytes else {
+                return;
+            };
+            granted.add_permits(bytes);
+            let _ = control_sender.send(Message::Window {
+                id,
+                bytes: bytes as u32,
+            });
+        }
+    });
+    Streaming {
+        chunk_sender: relay_sender,
+        window,
+    }
+}
+
+fn is_upgrade_request(req: &Request<Body>) -> bool {
+    let connection_upgrade = req
+        .headers()
+        .get_all(hyper::http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    connection_upgrade && req.headers().contains_key(hyper::http::header::UPGRADE)
+}
+
+// Sends the request head followed by its body in chunks as they arrive. For an
+// upgrade request the body frames continue with the upgraded connection once
+// the client switches protocols, so `End` is only sent when that closes. A body
+// that outgrows `max_body_bytes` is aborted and the session told about it, and
+// chunks wait for room in the request's `window` and their turn under the
+// bandwidth limit
+#[allow(clippy::too_many_arguments)]
+async fn forward_request(
+    id: u32,
+    mut req: Request<Body>,
+    upgrade: Option<oneshot::Receiver<Receiver<Option<Vec<u8>>>>>,
+    max_body_bytes: Option<u64>,
+    window: &Semaphore,
+    traffic: &Traffic,
+    writer_sender: &Sender<Message>,
+    session_sender: &WeakSender<ServiceSessionMessage>,
+) {
+    let on_upgrade = upgrade.as_ref().map(|_| hyper::upgrade::on(&mut req));
+    // hyper answers `Expect: 100-continue` with 100 Continue once the body is first
+    // read below, so the browser starts sending it as soon as the request reaches
+    // the client. Passed on, the upstream would be waited on to say the same, and
+    // its interim response has nowhere to go
+    req.headers_mut().remove(hyper::header::EXPECT);
+    let (parts, mut body) = req.into_parts();
+    // The send fails once the session is gone, and with it any reason to go on
+    if writer_sender
+        .send(Message::Request {
+            id,
+            data: create_http_head(&parts),
+        })
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let mut sent: u64 = 0;
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => {
+                sent += chunk.len() as u64;
+                if max_body_bytes.is_some_and(|max| sent > max) {
+                    let _ = writer_sender.send(Message::Abort { id }).await;
+                    if let Some(session_sender) = session_sender.upgrade() {
+                        let _ = session_sender
+                            .send(ServiceSessionMessage::RequestTooLarge(id))
+                            .await;
+                    }
+                    return;
+                }
+                for data in chunk.chunks(MAX_BODY_CHUNK) {
+                    if !send_body(id, data, window, traffic, writer_sender).await {
+                        return;
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Service session failed to read request body: {}: {}", id, e);
+                let _ = writer_sender.send(Message::Abort { id }).await;
+                return;
+            }
+        }
+    }
+    if let (Some(on_upgrade), Some(upgrade)) = (on_upgrade, upgrade) {
+        // Fails if the response wasn't a 101, in which case the request is done
+        if let Ok(upgraded) = on_upgrade.await {
+            if let Ok(chunk_receiver) = upgrade.await {
+                splice_upgrade(id, upgraded, chunk_receiver, window, traffic, writer_sender).await;
+                return;
+            }
+        }
+    }
+    let _ = writer_sender.send(Message::End { id }).await;
+}
+
+// Sends a chunk of body once the client has room for it, which stops reading
+// from the browser until it does, and the bandwidth limit allows. False if the
+// session is done with the stream or gone
+async fn send_body(
+    id: u32,
+    data: &[u8],
+    window: &Semaphore,
+    traffic: &Traffic,
+    writer_sender: &Sender<Message>,
+) -> bool {
+    match window.acquire_many(data.len() as u32).await {
+        Ok(permit) => permit.forget(),
+        // The client may still be reading the body
+        Err(_) => {
+            let _ = writer_sender.send(Message::Abort { id }).await;
+            return false;
+        }
+    }
+    traffic.throttle(data.len()).await;
+    // Also waits while the primary stream is behind
+    writer_sender
+        .send(Message::Body {
+            id,
+            data: data.to_vec(),
+        })
+        .await
+        .is_ok()
+}
+
+// Carries the raw bytes of an upgraded connection over the tunnel until the
+// browser closes it, bytes from the client arrive on `chunk_receiver`
+async fn splice_upgrade(
+    id: u32,
+    upgraded: Upgraded,
+    mut chunk_receiver: Receiver<Option<Vec<u8>>>,
+    window: &Semaphore,
+    traffic: &Traffic,
+    writer_sender: &Sender<Message>,
+) {
+    let (mut reader, mut writer) = tokio::io::split(upgraded);
+    task::spawn(async move {
+        while let Some(Some(chunk)) = chunk_receiver.recv().await {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+    let mut buf = vec![0; 8192];
+    loop {
+        match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(len) => {
+                if !send_body(id, &buf[..len], window, traffic, writer_sender).await {
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!(
+                    "Service session failed to read upgraded connection: {}: {}",
+                    id, e
+                );
+                let _ = writer_sender.send(Message::Abort { id }).await;
+                return;
+            }
+        }
+    }
+    let _ = writer_sender.send(Message::End { id }).await;
+}
+
+fn create_http_head(parts: &request::Parts) -> Vec<u8> {
+    let mut text = vec![];
+    text.extend_from_slice(
+        format!(
+            "{} {} {}\r\n",
+            parts.method,
+            parts.uri,
+            http1_version(parts.version)
+        )
+        .as_bytes(),
+    );
+    // Values go through as bytes, browsers can send ones that aren't ASCII
+    for (key, value) in &parts.headers {
+        text.extend_from_slice(key.as_str().as_bytes());
+        text.extend_from_slice(b": ");
+        text.extend_from_slice(value.as_bytes());
+        text.extend_from_slice(b"\r\n");
+    }
+    text.extend_from_slice(&b"\r\n"[..]);
+    text
+}
+
+// The Content-Length a response head declares, an error if it's malformed or
+// repeated with different values
+fn declared_length(headers: &[httparse::Header]) -> Result<Option<u64>, ()> {
+    let mut length = None;
+    for header in headers {
+        if !header.name.eq_ignore_ascii_case("content-length") {
+            continue;
+        }
+        let value = std::str::from_utf8(header.value).map_err(|_| ())?;
+        for value in value.split(',') {
+            let value = value.trim().parse::<u64>().map_err(|_| ())?;
+            if length.is_some_and(|length| length != value) {
+                return Err(());
+            }
+            length = Some(value);
+        }
+    }
+    Ok(length)
+}
+
+// The client speaks HTTP/1 to the upstream, so newer requests go over as 1.1
+fn http1_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 | Version::HTTP_10 => "HTTP/1.0",
+        Version::HTTP_11 => "HTTP/1.1",
+        version => {
+            debug!("Service session sending {:?} request as HTTP/1.1", version);
+            "HTTP/1.1"
+        }
+    }
+}
+
+async fn spawn_socket_manager(
+    service_mgr: Sender<ServiceManagerMessage>,
+    listener: TcpListener,
+    access: AccessList,
+    proxy_protocol: bool,
+    max_connections: usize,
+    tls_acceptor: Option<TlsAcceptor>,
+    shutdown: watch::Receiver<bool>,
+) {
+    debug!("Spawning socket manager");
+    let access = Arc::new(access);
+    // Held by each connection until its handshake is done, so a flood of
+    // connections that never send one backs up in the listen queue instead
+    let connections = Arc::new(Semaphore::new(max_connections));
+    task::spawn(async move {
+        debug!("Socket manager started");
+        let mut saturated = false;
+        loop {
+            let permit = match connections.clone().try_acquire_owned() {
+                Ok(permit) => {
+                    saturated = false;
+                    permit
+                }
+                Err(_) => {
+                    if !saturated {
+                        warn!(
+                            "Socket manager reached {} connections, waiting for one to finish",
+                            max_connections
+                        );
+                        saturated = true;
+                    }
+                    tokio::select! {
+                        permit = connections.clone().acquire_owned() => permit.unwrap(),
+                        _ = shutting_down(shutdown.clone()) => break,
+                    }
+                }
+            };
+            let (socket, remote_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Socket manager failed to accept connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutting_down(shutdown.clone()) => break,
+            };
+            let service_mgr = service_mgr.clone();
+            let access = access.clone();
+            let tls_acceptor = tls_acceptor.clone();
+            task::spawn(async move {
+                let _permit = permit;
+                let result = match tune_primary_stream(&socket) {
+                    Ok(()) => {
+                        socket_manager_read(
+                            socket,
+                            remote_addr,
+                            proxy_protocol,
+                            tls_acceptor,
+                            &access,
+                            service_mgr,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Socket manager dropped connection: {}", e);
+                }
+            });
+        }
+        debug!("Socket manager stopped accepting connections");
+    });
+}
+
+// Generic over the connection so tests can hand it one end of an in-memory pipe
+async fn socket_manager_read<S>(
+    mut socket: S,
+    remote_addr: SocketAddr,
+    proxy_protocol: bool,
+    tls_acceptor: Option<TlsAcceptor>,
+    access: &AccessList,
+    service_mgr: Sender<ServiceManagerMessage>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    trace!("Socket manager received new connection");
+    // Behind a load balancer the client's address comes ahead of the handshake,
+    // connections the load balancer made itself keep its own
+    let remote_addr = if proxy_protocol {
+        timeout(HANDSHAKE_TIMEOUT, proxy_protocol::read_header(&mut socket))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timed out"))??
+            .unwrap_or(remote_addr)
+    } else {
+        remote_addr
+    };
+    if !access.permits(remote_addr.ip()) {
+        debug!("Socket manager dropped connection from {}", remote_addr);
+        return Ok(());
+    }
+    // The PROXY header comes from the load balancer in the clear, everything
+    // from the client is inside TLS
+    let mut stream = match tls_acceptor {
+        Some(tls_acceptor) => {
+            let stream = timeout(HANDSHAKE_TIMEOUT, tls_acceptor.accept(socket))
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+                })??;
+            PrimaryStream::new(stream)
+        }
+        None => PrimaryStream::new(socket),
+    };
+    // The service id, then its HMAC keyed with the secret from the client's
+    // start request
+    let (bytes, signature) = timeout(HANDSHAKE_TIMEOUT, async {
+        let bytes = read_frame_max(&mut stream, MAX_HANDSHAKE_LEN).await?;
+        let signature = read_frame_max(&mut stream, MAX_HANDSHAKE_LEN).await?;
+        io::Result::Ok((bytes, signature))
+    })
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+    let service_id =
+        String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    trace!(
+        "Socket manager forwarding connection from {} to service manager: {}",
+        remote_addr,
+        service_id
+    );
+    let (verified_sender, verified) = oneshot::channel();
+    service_mgr
+        .send(ServiceManagerMessage::ForwardPrimaryStream {
+            service_id: service_id.clone(),
+            signature,
+            stream,
+            verified: verified_sender,
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "service manager has shut down"))?;
+    if !verified.await.unwrap_or(false) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "bad handshake signature from {} for service: {}",
+                remote_addr, service_id
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn verify_handshake(secret: &str, service_id: &str, signature: &[u8]) -> bool {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    hmac::verify(&key, service_id.as_bytes(), signature).is_ok()
+}
+
+// Messages are small and already buffered, so Nagle only adds latency, and
+// keepalive drops connections whose client vanished without closing them
+fn tune_primary_stream(socket: &TcpStream) -> io::Result<()> {
+    socket.set_nodelay(true)?;
+    SockRef::from(socket).set_tcp_keepalive(&TcpKeepalive::new().with_time(TCP_KEEPALIVE))
+}
+
+fn generate_service_id(key: &KeyConfig) -> String {
+    match key.scheme {
+        IdScheme::Phonetic => phonetic_key_generator(key),
+        IdScheme::Uuid => uuid_v4(),
+        IdScheme::Base32 => {
+            let mut rng = rand::thread_rng();
+            (0..key.length)
+                .map(|_| *BASE32_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect()
+        }
+    }
+}
+
+// Lowercase so ids read the same however the browser cased the host
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn uuid_v4() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+// Sorry this code is so weird, I ported it from some old JS code
+fn phonetic_key_generator(key: &KeyConfig) -> String {
+    let mut text = vec![];
+    let mut rng = rand::thread_rng();
+    let start = usize::from(rng.gen::<bool>());
+    for i in 0..key.length {
+        text.push(if i % 2 == start {
+            key.consonants.choose(&mut rng).unwrap()
+        } else {
+            key.vowels.choose(&mut rng).unwrap()
+        });
+    }
+    text.into_iter().collect::<String>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::read_message;
+    use tokio::io::{duplex, DuplexStream};
+
+    const SESSION: SessionConfig = SessionConfig {
+        request_timeout: Duration::from_secs(30),
+        heartbeat_interval: Duration::from_secs(15),
+        heartbeat_timeout: Duration::from_secs(30),
+        compress: false,
+        request_id_header: false,
+        buffer_size: 8 * 1024,
+        max_message_bytes: DEFAULT_MAX_MESSAGE_LEN,
+        max_body_bytes: None,
+        max_in_flight: None,
+        queue_timeout: Duration::from_secs(5),
+        access_log: None,
+        channel_capacity: 1024,
+        basic_auth: None,
+        cors: None,
+        bandwidth_limit: None,
+        dump_messages: None,
+        idle_timeout: None,
+        handshake_secret: String::new(),
+        fallback_url: None,
+        join_token: None,
+    };
+
+    fn test_config(auth_token: Option<&str>) -> Arc<Config> {
+        Arc::new(Config {
+            domain: "tunnel.test".to_string(),
+            http_port: 443,
+            proxy_port: 8080,
+            session: SESSION,
+            auth_token: auth_token.map(str::to_string),
+            key: KeyConfig {
+                scheme: IdScheme::Phonetic,
+                length: 6,
+                vowels: vec!['a'],
+                consonants: vec!['b'],
+            },
+            start_limiter: None,
+            start_retry_after: Duration::from_secs(30),
+            response_headers: vec![],
+            force_response_headers: false,
+            error_pages: HashMap::new(),
+            landing_page: DEFAULT_LANDING_PAGE.to_string(),
+            offline_page: DEFAULT_OFFLINE_PAGE.to_string(),
+            reserved_subdomains: HashSet::from(["admin".to_string()]),
+            reclaim_ttl: None,
+            wildcard_subdomains: false,
+            load_balance: false,
+            fair_share: Arc::new(FairShare::new(1024)),
+        })
+    }
+
+    // Where the socket manager tests' clients connect from
+    const CLIENT_ADDR: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 51234);
+
+    // Hands the session registered for `service_id` one end of an in-memory
+    // pipe as its primary stream, returning the client's end. Signed with the
+    // test sessions' empty secret
+    async fn connect_client(
+        service_mgr: &Sender<ServiceManagerMessage>,
+        service_id: &str,
+    ) -> DuplexStream {
+        let (client, stream) = duplex(64 * 1024);
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"");
+        let (verified, _) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardPrimaryStream {
+                service_id: service_id.to_string(),
+                signature: hmac::sign(&key, service_id.as_bytes()).as_ref().to_vec(),
+                stream: PrimaryStream::new(stream),
+                verified,
+            })
+            .await
+            .unwrap();
+        client
+    }
+
+    async fn forward_request(
+        service_mgr: &Sender<ServiceManagerMessage>,
+        host: &str,
+    ) -> Response<Body> {
+        let (sender, mut receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .header(hyper::http::header::HOST, host)
+                    .body(Body::empty())
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+        receiver.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn dropped_primary_stream_unregisters_service() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let client = connect_client(&service_mgr, "foo").await;
+        drop(client);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while forward_request(&service_mgr, "foo.tunnel.test")
+                .await
+                .status()
+                != StatusCode::NOT_FOUND
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("service was never unregistered");
+    }
+
+    #[tokio::test]
+    async fn rejects_second_primary_stream() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut clients = vec![];
+        for _ in 0..2 {
+            clients.push(connect_client(&service_mgr, "foo").await);
+        }
+        let mut second = clients.pop().unwrap();
+        let mut first = clients.pop().unwrap();
+
+        // The second is closed rather than left hanging
+        let read = tokio::time::timeout(Duration::from_secs(5), second.read(&mut [0; 1]))
+            .await
+            .expect("second primary stream was left open");
+        assert_eq!(read.unwrap(), 0);
+
+        // while the first carries on serving the tunnel
+        let service_mgr_clone = service_mgr.clone();
+        task::spawn(async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await });
+        assert!(matches!(
+            read_message(&mut first).await.unwrap(),
+            Message::Request { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn closes_idle_sessions() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    idle_timeout: Some(Duration::from_millis(100)),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        // The primary stream is closed and the tunnel unregistered
+        let read = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if client.read(&mut [0; 64]).await.unwrap() == 0 {
+                    break;
+                }
+            }
+        });
+        read.await.expect("idle session was never closed");
+        assert_eq!(
+            forward_request(&service_mgr, "foo.tunnel.test")
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn unresponsive_client_times_out() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    request_timeout: Duration::from_millis(100),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        // The client end stays open but never answers
+        let _client = connect_client(&service_mgr, "foo").await;
+
+        let response = tokio::time::timeout(
+            Duration::from_secs(5),
+            forward_request(&service_mgr, "foo.tunnel.test"),
+        )
+        .await
+        .expect("request never timed out");
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn streams_event_stream_responses() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        let service_mgr_clone = service_mgr.clone();
+        let response =
+            task::spawn(
+                async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await },
+            );
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        let head = b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        let event = b"data: hello\n\n".to_vec();
+        write_message(&mut client, &Message::Body { id, data: event })
+            .await
+            .unwrap();
+
+        // The event arrives while the client is still holding the stream open
+        let mut body = response.await.unwrap().into_body();
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+            .await
+            .expect("event was never delivered")
+            .unwrap()
+            .unwrap();
+        assert_eq!(&chunk[..], b"data: hello\n\n");
+
+        write_message(&mut client, &Message::End { id })
+            .await
+            .unwrap();
+        assert!(body.data().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn aborts_body_longer_than_content_length() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        let service_mgr_clone = service_mgr.clone();
+        let response =
+            task::spawn(
+                async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await },
+            );
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        let head = b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        let data = b"hello world".to_vec();
+        write_message(&mut client, &Message::Body { id, data })
+            .await
+            .unwrap();
+
+        let mut body = response.await.unwrap().into_body();
+        assert!(body.data().await.unwrap().is_err());
+        loop {
+            match read_message(&mut client).await.unwrap() {
+                Message::Abort { id: aborted } => break assert_eq!(aborted, id),
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_bad_gateway_for_invalid_status_codes() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        let service_mgr_clone = service_mgr.clone();
+        let response =
+            task::spawn(
+                async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await },
+            );
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        let head = b"HTTP/1.1 099 X\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+
+        assert_eq!(response.await.unwrap().status(), StatusCode::BAD_GATEWAY);
+        loop {
+            match read_message(&mut client).await.unwrap() {
+                Message::Abort { id: aborted } => break assert_eq!(aborted, id),
+                _ => continue,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn session_survives_disconnected_browser() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        // The browser is gone by the time the client responds
+        let (sender, receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .body(Body::empty())
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+        drop(receiver);
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        let head = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        loop {
+            match read_message(&mut client).await.unwrap() {
+                Message::Abort { id: aborted } => break assert_eq!(aborted, id),
+                _ => continue,
+            }
+        }
+
+        let service_mgr_clone = service_mgr.clone();
+        let response =
+            task::spawn(
+                async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await },
+            );
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        let head = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        let response = tokio::time::timeout(Duration::from_secs(5), response)
+            .await
+            .expect("session stopped answering requests")
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_request_bodies_over_limit() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    max_body_bytes: Some(4),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        let send = |body: Body| {
+            let (sender, mut receiver) = unbounded_channel();
+            service_mgr
+                .try_send(ServiceManagerMessage::ForwardRequest {
+                    request: Request::builder()
+                        .header(hyper::http::header::HOST, "foo.tunnel.test")
+                        .body(body)
+                        .unwrap(),
+                    response_sender: sender,
+                })
+                .unwrap();
+            async move { receiver.recv().await.unwrap() }
+        };
+
+        // A declared length over the limit never reaches the client
+        let response = send(Body::from("hello world")).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let (mut body_sender, body) = Body::channel();
+        let response = task::spawn(send(body));
+        body_sender.send_data("hel".into()).await.unwrap();
+        body_sender.send_data("lo".into()).await.unwrap();
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        loop {
+            match read_message(&mut client).await.unwrap() {
+                Message::Abort { id: aborted } => break assert_eq!(aborted, id),
+                _ => continue,
+            }
+        }
+        let response = tokio::time::timeout(Duration::from_secs(5), response)
+            .await
+            .expect("oversized body was never rejected")
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn streams_request_bodies_before_they_end() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        // A chunked upload, with no length known up front
+        let (mut body_sender, body) = Body::channel();
+        let (sender, _receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .method(Method::POST)
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .header(hyper::http::header::TRANSFER_ENCODING, "chunked")
+                    .body(body)
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+
+        let Message::Request { id, data } = read_message(&mut client).await.unwrap() else {
+            panic!("expected the request head first");
+        };
+        let head = String::from_utf8(data).unwrap().to_lowercase();
+        assert!(!head.contains("content-length"));
+        // Each chunk is passed on as it arrives rather than once the body is done
+        for chunk in ["first", "second"] {
+            body_sender.send_data(chunk.into()).await.unwrap();
+            assert!(matches!(
+                read_message(&mut client).await.unwrap(),
+                Message::Body { id: body_id, data } if body_id == id && data == chunk.as_bytes()
+            ));
+        }
+        drop(body_sender);
+        assert!(matches!(
+            read_message(&mut client).await.unwrap(),
+            Message::End { id: end_id } if end_id == id
+        ));
+    }
+
+    #[tokio::test]
+    async fn forwards_bodies_full_of_null_bytes() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        let (sender, mut receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .method(Method::POST)
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .body(Body::from(vec![0; 64]))
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+
+        // Frames carry their length, so nothing in a body is mistaken for the
+        // end of one
+        let mut body = vec![];
+        let id = loop {
+            match read_message(&mut client).await.unwrap() {
+                Message::Body { data, .. } => body.extend(data),
+                Message::End { id } => break id,
+                _ => {}
+            }
+        };
+        assert_eq!(body, [0; 64]);
+        let head = b"HTTP/1.1 200 OK\r\ncontent-length: 64\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        write_message(
+            &mut client,
+            &Message::Body {
+                id,
+                data: vec![0; 64],
+            },
+        )
+        .await
+        .unwrap();
+        write_message(&mut client, &Message::End { id })
+            .await
+            .unwrap();
+        let response = receiver.recv().await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, vec![0; 64]);
+    }
+
+    #[tokio::test]
+    async fn throttles_request_bodies_over_bandwidth_limit() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    bandwidth_limit: Some(1000),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        let (mut body_sender, body) = Body::channel();
+        let (sender, _receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .body(body)
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+        let started = Instant::now();
+        body_sender.send_data(vec![0; 1000].into()).await.unwrap();
+        body_sender.send_data(vec![0; 500].into()).await.unwrap();
+        let mut received = 0;
+        while received < 1500 {
+            if let Message::Body { data, .. } = read_message(&mut client).await.unwrap() {
+                received += data.len();
+            }
+        }
+        // The first second's worth goes straight through, the rest waits for it
+        assert!(started.elapsed() >= Duration::from_millis(400));
+
+        let (reply, services) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::ListServices { services: reply })
+            .await
+            .unwrap();
+        let services = services.await.unwrap();
+        assert!(services[0].bytes_sent >= 1500);
+        assert_eq!(services[0].bytes_received, 0);
+    }
+
+    #[tokio::test]
+    async fn queues_requests_over_in_flight_limit() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    max_in_flight: Some(1),
+                    queue_timeout: Duration::from_millis(200),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        let send = || {
+            let service_mgr = service_mgr.clone();
+            task::spawn(async move { forward_request(&service_mgr, "foo.tunnel.test").await })
+        };
+        let first = send();
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        // Waits for the first request to finish rather than going to the client
+        let second = send();
+        let head = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        write_message(&mut client, &Message::End { id })
+            .await
+            .unwrap();
+        assert_eq!(first.await.unwrap().status(), StatusCode::OK);
+        while !matches!(
+            read_message(&mut client).await.unwrap(),
+            Message::Request { .. }
+        ) {}
+
+        // Nothing finishes this time, so the queued request gives up
+        let third = send();
+        assert_eq!(
+            third.await.unwrap().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        drop(second);
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_clients() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        service_mgr
+            .send(ServiceManagerMessage::Shutdown)
+            .await
+            .unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), read_message(&mut client))
+            .await
+            .expect("client was never told about the shutdown")
+            .unwrap();
+        assert!(matches!(message, Message::Shutdown));
+    }
+
+    #[tokio::test]
+    async fn continues_expect_100_uploads() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        // Served the way the HTTP listener serves browsers
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut browser = TcpStream::connect(http.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, remote_addr) = http.accept().await.unwrap();
+        let config = test_config(None);
+        task::spawn(Http::new().serve_connection(
+            stream,
+            service_fn(move |req| {
+                handle_incoming_request(
+                    req,
+                    service_mgr.clone(),
+                    config.clone(),
+                    remote_addr,
+                    "http",
+                )
+            }),
+        ));
+
+        // What curl sends before a large upload, holding the body back
+        browser
+            .write_all(
+                b"PUT /upload HTTP/1.1\r\nHost: foo.tunnel.test\r\n\
+                  Content-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let (id, head) = loop {
+            if let Message::Request { id, data } = read_message(&mut client).await.unwrap() {
+                break (id, data);
+            }
+        };
+        assert!(!String::from_utf8_lossy(&head)
+            .to_lowercase()
+            .contains("expect"));
+        let mut interim = [0; 25];
+        timeout(Duration::from_secs(5), browser.read_exact(&mut interim))
+            .await
+            .expect("never told to continue")
+            .unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+        browser.write_all(b"hello").await.unwrap();
+        let mut body = vec![];
+        loop {
+            match read_message(&mut client).await.unwrap() {
+                Message::Body { data, .. } => body.extend(data),
+                Message::End { .. } => break,
+                _ => {}
+            }
+        }
+        assert_eq!(body, b"hello");
+        let head = b"HTTP/1.1 204 No Content\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        write_message(&mut client, &Message::End { id })
+            .await
+            .unwrap();
+        let mut status = [0; 12];
+        browser.read_exact(&mut status).await.unwrap();
+        assert_eq!(&status, b"HTTP/1.1 204");
+    }
+
+    #[tokio::test]
+    async fn answers_head_requests_without_a_body() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut browser = TcpStream::connect(http.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, remote_addr) = http.accept().await.unwrap();
+        let config = test_config(None);
+        task::spawn(Http::new().serve_connection(
+            stream,
+            service_fn(move |req| {
+                handle_incoming_request(
+                    req,
+                    service_mgr.clone(),
+                    config.clone(),
+                    remote_addr,
+                    "http",
+                )
+            }),
+        ));
+
+        browser
+            .write_all(b"HEAD /file HTTP/1.1\r\nHost: foo.tunnel.test\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let id = loop {
+            if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                break id;
+            }
+        };
+        // An upstream that answers HEAD like GET
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\nContent-Type: text/plain\r\n\r\n";
+        write_message(
+            &mut client,
+            &Message::Response {
+                id,
+                data: head.to_vec(),
+            },
+        )
+        .await
+        .unwrap();
+        let data = b"hello world".to_vec();
+        write_message(&mut client, &Message::Body { id, data })
+            .await
+            .unwrap();
+        write_message(&mut client, &Message::End { id })
+            .await
+            .unwrap();
+
+        let mut response = vec![];
+        timeout(Duration::from_secs(5), browser.read_to_end(&mut response))
+            .await
+            .expect("connection was left open")
+            .unwrap();
+        let response = String::from_utf8(response).unwrap().to_lowercase();
+        assert!(response.starts_with("http/1.1 200 ok\r\n"));
+        assert!(response.contains("content-length: 11\r\n"));
+        assert!(response.contains("content-type: text/plain\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn streams_whole_responses_to_slow_browsers_while_others_go_on() {
+        const LEN: usize = 1024 * 1024;
+        const CHUNK: usize = 16 * 1024;
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    channel_capacity: 2,
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+        let (mut reader, mut writer) = tokio::io::split(connect_client(&service_mgr, "foo").await);
+        let (message_sender, mut messages) = unbounded_channel();
+        task::spawn(async move {
+            while let Ok(message) = read_message(&mut reader).await {
+                if message_sender.send(message).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let slow = task::spawn({
+            let service_mgr = service_mgr.clone();
+            async move { forward_request(&service_mgr, "foo.tunnel.test").await }
+        });
+        let slow_id = loop {
+            if let Some(Message::Request { id, .. }) = messages.recv().await {
+                break id;
+            }
+        };
+        let data = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", LEN).into_bytes();
+        write_message(&mut writer, &Message::Response { id: slow_id, data })
+            .await
+            .unwrap();
+        // Not read until the other request is done
+        let slow_body = slow.await.unwrap().into_body();
+
+        // Sends the slow response as far as its window allows
+        let mut sent = 0;
+        let mut window = INITIAL_WINDOW as usize;
+        loop {
+            while sent < LEN && window >= CHUNK {
+                let data = vec![b'x'; CHUNK];
+                write_message(&mut writer, &Message::Body { id: slow_id, data })
+                    .await
+                    .unwrap();
+                sent += CHUNK;
+                window -= CHUNK;
+            }
+            match timeout(Duration::from_millis(100), messages.recv()).await {
+                Ok(Some(Message::Window { id, bytes })) if id == slow_id => {
+                    window += bytes as usize
+                }
+                Ok(Some(Message::Abort { .. })) => panic!("slow response was aborted"),
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        assert!(sent < LEN, "slow response wasn't held up");
+
+        let fast = task::spawn({
+            let service_mgr = service_mgr.clone();
+            async move { forward_request(&service_mgr, "foo.tunnel.test").await }
+        });
+        let fast_id = loop {
+            if let Some(Message::Request { id, .. }) = messages.recv().await {
+                break id;
+            }
+        };
+        let data = b"HTTP/1.1 200 OK\r\ncontent-length: 4\r\n\r\n".to_vec();
+        write_message(&mut writer, &Message::Response { id: fast_id, data })
+            .await
+            .unwrap();
+        let data = b"fast".to_vec();
+        write_message(&mut writer, &Message::Body { id: fast_id, data })
+            .await
+            .unwrap();
+        write_message(&mut writer, &Message::End { id: fast_id })
+            .await
+            .unwrap();
+        let fast = timeout(Duration::from_secs(5), fast)
+            .await
+            .expect("other request wasn't answered")
+            .unwrap();
+        let body = hyper::body::to_bytes(fast.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"fast");
+
+        // The rest goes out as the browser reads it
+        let slow_body = task::spawn(hyper::body::to_bytes(slow_body));
+        while sent < LEN {
+            while sent < LEN && window >= CHUNK {
+                let data = vec![b'x'; CHUNK];
+                write_message(&mut writer, &Message::Body { id: slow_id, data })
+                    .await
+                    .unwrap();
+                sent += CHUNK;
+                window -= CHUNK;
+            }
+            match timeout(Duration::from_secs(5), messages.recv())
+                .await
+                .expect("slow response's window wasn't granted")
+            {
+                Some(Message::Window { id, bytes }) if id == slow_id => window += bytes as usize,
+                Some(Message::Abort { .. }) => panic!("slow response was aborted"),
+                _ => {}
+            }
+        }
+        write_message(&mut writer, &Message::End { id: slow_id })
+            .await
+            .unwrap();
+        let body = timeout(Duration::from_secs(5), slow_body)
+            .await
+            .expect("slow response didn't end")
+            .unwrap()
+            .unwrap();
+        assert_eq!(body.len(), LEN);
+    }
+
+    #[tokio::test]
+    async fn serves_other_requests_during_uploads_longer_than_the_heartbeat_timeout() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    heartbeat_interval: Duration::from_millis(50),
+                    heartbeat_timeout: Duration::from_millis(200),
+                    channel_capacity: 4,
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+        let client = connect_client(&service_mgr, "foo").await;
+        // Reads the upload slower than the browser sends it, so the writer queue
+        // stays full while it goes on
+        task::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(client);
+            let ok = |id| {
+                let data = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+                vec![Message::Response { id, data }, Message::End { id }]
+            };
+            while let Ok(message) = read_message(&mut reader).await {
+                let replies = match message {
+                    Message::Ping { id } => vec![Message::Pong { id }],
+                    Message::Body { id, data } => {
+                        sleep(Duration::from_millis(5)).await;
+                        let bytes = data.len() as u32;
+                        vec![Message::Window { id, bytes }]
+                    }
+                    Message::Request { id, data } if data.starts_with(b"GET") => ok(id),
+                    Message::End { id } => ok(id),
+                    _ => vec![],
+                };
+                for reply in replies {
+                    write_message(&mut writer, &reply).await.unwrap();
+                }
+            }
+        });
+
+        let (mut body_sender, body) = Body::channel();
+        task::spawn(async move {
+            for _ in 0..128 {
+                let chunk = vec![b'x'; 16 * 1024];
+                if body_sender.send_data(chunk.into()).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let (sender, mut upload) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .method(Method::POST)
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .body(body)
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(300)).await;
+
+        let response = timeout(
+            Duration::from_secs(5),
+            forward_request(&service_mgr, "foo.tunnel.test"),
+        )
+        .await
+        .expect("request wasn't answered");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(upload.try_recv().is_err(), "upload finished too soon");
+        let response = timeout(Duration::from_secs(10), upload.recv())
+            .await
+            .expect("upload wasn't answered")
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn passes_on_header_values_that_arent_ascii() {
+        let (parts, _) = Request::builder()
+            .uri("/caf%C3%A9")
+            .header("x-name", HeaderValue::from_bytes(b"caf\xe9").unwrap())
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert_eq!(
+            create_http_head(&parts),
+            b"GET /caf%C3%A9 HTTP/1.1\r\nx-name: caf\xe9\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_messages_over_the_size_limit() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    max_message_bytes: 64,
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+        let mut client = connect_client(&service_mgr, "foo").await;
+
+        for filler in [1024, 0] {
+            let service_mgr = service_mgr.clone();
+            let response =
+                task::spawn(async move { forward_request(&service_mgr, "foo.tunnel.test").await });
+            let id = loop {
+                if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                    break id;
+                }
+            };
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nx-filler: {}\r\ncontent-length: 0\r\n\r\n",
+                "a".repeat(filler)
+            );
+            write_message(
+                &mut client,
+                &Message::Response {
+                    id,
+                    data: head.into_bytes(),
+                },
+            )
+            .await
+            .unwrap();
+            // The stream stays usable after a message is skipped
+            let expected = if filler > 0 {
+                StatusCode::BAD_GATEWAY
+            } else {
+                StatusCode::OK
+            };
+            assert_eq!(response.await.unwrap().status(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn missed_pongs_unregister_service() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    heartbeat_interval: Duration::from_millis(50),
+                    heartbeat_timeout: Duration::from_millis(100),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        // The client end stays open but never answers pings
+        let mut client = connect_client(&service_mgr, "foo").await;
+        assert!(matches!(
+            read_message(&mut client).await.unwrap(),
+            Message::Ping { .. }
+        ));
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while forward_request(&service_mgr, "foo.tunnel.test")
+                .await
+                .status()
+                != StatusCode::NOT_FOUND
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("service was never unregistered");
+    }
+
+    #[tokio::test]
+    async fn missing_host_is_bad_request() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let response = handle_incoming_request(
+            Request::builder().body(Body::empty()).unwrap(),
+            service_mgr,
+            test_config(None),
+            "192.0.2.7:4321".parse().unwrap(),
+            "http",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn admin_lists_and_kills_tunnels() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        let config = test_config(Some("secret"));
+        let admin = |method: Method, path: &str, token: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .header(
+                    hyper::http::header::AUTHORIZATION,
+                    format!("Bearer {}", token),
+                )
+                .body(Body::empty())
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+
+        let response = admin(Method::GET, "/admin/tunnels", "wrong").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = admin(Method::GET, "/admin/tunnels", "secret")
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let tunnels: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tunnels[0]["service_id"], "foo");
+        assert_eq!(tunnels[0]["requests"], 0);
+        assert_eq!(tunnels[0]["bytes_sent"], 0);
+
+        let response = admin(Method::DELETE, "/admin/tunnels/foo", "secret")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            forward_request(&service_mgr, "foo.tunnel.test")
+                .await
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+        let response = admin(Method::DELETE, "/admin/tunnels/foo", "secret")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_start_requests() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let config = Arc::new(Config {
+            start_limiter: Some(RateLimiter::new(2)),
+            ..Arc::into_inner(test_config(None)).unwrap()
+        });
+        let start = |remote_addr: &str, subdomain: &str| {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/start")
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .body(Body::from(subdomain.to_string()))
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                remote_addr.parse().unwrap(),
+                "http",
+            )
+        };
+
+        for subdomain in ["foo", "bar"] {
+            let response = start("192.0.2.7:4321", subdomain).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = start("192.0.2.7:4322", "baz").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let response = start("192.0.2.8:4321", "baz").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn describes_started_tunnels_in_json_when_asked() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let config = test_config(None);
+        let start = |config: &Arc<Config>, subdomain: &str, accept: &str| {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/start")
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .header(hyper::http::header::ACCEPT, accept)
+                .body(Body::from(subdomain.to_string()))
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "https",
+            )
+        };
+
+        let response = start(&config, "foo", "*/*").await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "foo");
+        let response = start(&config, "bar", "application/json").await.unwrap();
+        assert_eq!(
+            response.headers()[hyper::http::header::CONTENT_TYPE],
+            "application/json"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let started: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(started["service_id"], "bar");
+        assert_eq!(started["url"], "https://bar.tunnel.test");
+        assert_eq!(started["proxy"], "tunnel.test:8080");
+
+        let other_port = Arc::new(Config {
+            http_port: 8443,
+            ..Arc::into_inner(test_config(None)).unwrap()
+        });
+        let response = start(&other_port, "baz", "application/json").await.unwrap();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let started: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(started["url"], "https://baz.tunnel.test:8443");
+    }
+
+    #[tokio::test]
+    async fn turns_away_new_tunnels_while_draining() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let config = test_config(Some("secret"));
+        let send = |method: Method, path: &str, body: &str| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .header(hyper::http::header::AUTHORIZATION, "Bearer secret")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+        let json = |response: Response<Body>| async move {
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let response = send(Method::POST, "/start", "foo").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = send(Method::POST, "/admin/drain", "").await.unwrap();
+        let drained = json(response).await;
+        assert_eq!(drained["draining"], true);
+        assert_eq!(drained["tunnels"], 1);
+
+        let response = send(Method::POST, "/start", "bar").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[hyper::http::header::RETRY_AFTER], "30");
+        let response = send(Method::GET, "/admin/tunnels", "").await.unwrap();
+        let tunnels = json(response).await;
+        assert_eq!(tunnels.as_array().unwrap().len(), 1);
+        assert_eq!(tunnels[0]["service_id"], "foo");
+        let response = send(Method::GET, "/health", "").await.unwrap();
+        assert_eq!(json(response).await["draining"], true);
+
+        let response = send(Method::POST, "/admin/undrain", "").await.unwrap();
+        assert_eq!(json(response).await["draining"], false);
+        let response = send(Method::POST, "/start", "bar").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn serves_configured_error_pages() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let config = Arc::new(Config {
+            error_pages: HashMap::from([(
+                StatusCode::NOT_FOUND,
+                "<h1>No tunnel at {{host}}</h1>".to_string(),
+            )]),
+            ..Arc::into_inner(test_config(None)).unwrap()
+        });
+        let response = handle_incoming_request(
+            Request::builder()
+                .header(hyper::http::header::HOST, "<foo>.tunnel.test")
+                .body(Body::empty())
+                .unwrap(),
+            service_mgr,
+            config,
+            "192.0.2.7:4321".parse().unwrap(),
+            "http",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            response.headers()[hyper::http::header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"<h1>No tunnel at &lt;foo&gt;.tunnel.test</h1>");
+    }
+
+    #[tokio::test]
+    async fn serves_landing_page_on_root_domain() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let config = test_config(None);
+        let get = |path: &str| {
+            let req = Request::builder()
+                .uri(path)
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .body(Body::empty())
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+
+        let response = get("/").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("<code>*.tunnel.test</code>"));
+        assert_eq!(get("/nope").await.unwrap().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn serves_offline_page_for_recently_closed_tunnels() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            None,
+            false,
+            None,
+            Some(Duration::from_secs(60)),
+            false,
+            receiver,
+        );
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        service_mgr
+            .send(ServiceManagerMessage::UnregisterService {
+                service_id: "foo".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let config = test_config(None);
+        let get = |host: &str| {
+            let req = Request::builder()
+                .header(hyper::http::header::HOST, host)
+                .body(Body::empty())
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+        let response = get("foo.tunnel.test").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[hyper::http::header::RETRY_AFTER],
+            OFFLINE_RETRY_AFTER
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("<code>foo.tunnel.test</code>"));
+
+        // Tunnels that never existed are still missing
+        let response = get("bar.tunnel.test").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn redirects_offline_tunnels_to_their_fallback_url() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            None,
+            false,
+            None,
+            Some(Duration::from_secs(60)),
+            false,
+            receiver,
+        );
+        let config = test_config(None);
+        let send = |method: Method, host: &str, uri: &str, fallback: Option<&str>| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(hyper::http::header::HOST, host);
+            if let Some(fallback) = fallback {
+                req = req.header("X-Fallback-Url", fallback);
+            }
+            handle_incoming_request(
+                req.body(Body::from("foo")).unwrap(),
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+
+        let response = send(Method::POST, "tunnel.test", "/start", Some("/maintenance"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = send(
+            Method::POST,
+            "tunnel.test",
+            "/start",
+            Some("https://status.example/foo/"),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        service_mgr
+            .send(ServiceManagerMessage::UnregisterService {
+                service_id: "foo".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let response = send(Method::GET, "foo.tunnel.test", "/docs?page=2", None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            response.headers()[hyper::http::header::LOCATION],
+            "https://status.example/foo/docs?page=2"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_reserved_and_invalid_subdomains() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let config = test_config(None);
+        let start = |subdomain: &str| {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/start")
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .header("X-Requested-Subdomain", subdomain)
+                .body(Body::empty())
+                .unwrap();
+            handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+
+        let response = start("Admin").await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        for invalid in ["-foo", "foo-", "foo.bar", "foo_bar", &"a".repeat(64)] {
+            let response = start(invalid).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", invalid);
+        }
+        let response = start("my-app2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn detects_upgrade_requests() {
+        let upgrade = Request::builder()
+            .header(hyper::http::header::CONNECTION, "keep-alive, Upgrade")
+            .header(hyper::http::header::UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert!(is_upgrade_request(&upgrade));
+
+        let plain = Request::builder()
+            .header(hyper::http::header::CONNECTION, "keep-alive")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_upgrade_request(&plain));
+    }
+
+    #[test]
+    fn appends_to_existing_forwarded_for() {
+        let mut req = Request::builder()
+            .header(hyper::http::header::HOST, "foo.tunnel.test")
+            .header(X_FORWARDED_FOR, "10.0.0.1")
+            .body(Body::empty())
+            .unwrap();
+        add_forwarded_headers(&mut req, "192.0.2.7:4321".parse().unwrap(), "https");
+        assert_eq!(req.headers()[X_FORWARDED_FOR], "10.0.0.1, 192.0.2.7");
+        assert_eq!(req.headers()[X_FORWARDED_HOST], "foo.tunnel.test");
+        assert_eq!(req.headers()[X_FORWARDED_PROTO], "https");
+    }
+
+    #[test]
+    fn strips_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::CONNECTION,
+            HeaderValue::from_static("close, x-trace"),
+        );
+        headers.insert(
+            hyper::header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-trace", HeaderValue::from_static("1"));
+        headers.insert(
+            hyper::header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain"),
+        );
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[hyper::header::CONTENT_TYPE], "text/plain");
+    }
+
+    #[test]
+    fn adds_response_headers() {
+        let extra = [
+            (hyper::header::VIA, HeaderValue::from_static("tunnel-ly")),
+            (
+                HeaderName::from_static("x-correlation-id"),
+                HeaderValue::from_static("abc"),
+            ),
+        ];
+        let mut headers = HeaderMap::new();
+        headers.insert(hyper::header::VIA, HeaderValue::from_static("1.1 cdn"));
+        add_response_headers(&mut headers, &extra, false);
+        assert_eq!(headers[hyper::header::VIA], "1.1 cdn");
+        assert_eq!(headers["x-correlation-id"], "abc");
+
+        add_response_headers(&mut headers, &extra, true);
+        assert_eq!(headers[hyper::header::VIA], "tunnel-ly");
+        assert_eq!(headers.get_all("x-correlation-id").iter().count(), 1);
+    }
+
+    #[test]
+    fn routes_hosts_to_service_ids() {
+        assert_eq!(
+            service_id_for_host("foo.tunnel.test", "tunnel.test", false),
+            "foo"
+        );
+        assert_eq!(
+            service_id_for_host("api.foo.tunnel.test", "tunnel.test", false),
+            "api.foo"
+        );
+        assert_eq!(
+            service_id_for_host("a.api.foo.tunnel.test", "tunnel.test", true),
+            "foo"
+        );
+        assert_eq!(
+            service_id_for_host("foo.tunnel.test", "tunnel.test", true),
+            "foo"
+        );
+        assert_eq!(service_id_for_host("foo", "tunnel.test", true), "foo");
+    }
+
+    #[test]
+    fn reuses_inbound_request_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_REQUEST_ID, HeaderValue::from_static("abc-123"));
+        assert_eq!(ensure_request_id(&mut headers), "abc-123");
+
+        let mut headers = HeaderMap::new();
+        let request_id = ensure_request_id(&mut headers);
+        assert_eq!(request_id.len(), 16);
+        assert_eq!(headers[X_REQUEST_ID], request_id.as_str());
+    }
+
+    #[tokio::test]
+    async fn binds_ipv6_listeners() {
+        let listener = bind("[::1]:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.is_ipv6());
+        let (_client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        assert!(accepted.unwrap().1.is_ipv6());
+
+        // Unspecified IPv6 listens on both stacks
+        let listener = bind("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (client, accepted) =
+            tokio::join!(TcpStream::connect(("127.0.0.1", port)), listener.accept());
+        client.unwrap();
+        let (_, remote_addr) = accepted.unwrap();
+        assert!(remote_addr.ip().to_canonical().is_ipv4());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_handshake() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let (mut client, socket) = duplex(1024);
+        client.write_u32(u32::MAX).await.unwrap();
+        let e = socket_manager_read(
+            socket,
+            CLIENT_ADDR,
+            false,
+            None,
+            &AccessList::default(),
+            service_mgr,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn checks_address_from_proxy_protocol_header() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        // Only the client behind the load balancer is allowed, not the
+        // load balancer itself
+        let access = AccessList {
+            allow: vec!["198.51.100.0/24".parse().unwrap()],
+            deny: vec![],
+        };
+        let (mut client, socket) = duplex(1024);
+        client
+            .write_all(b"PROXY TCP4 198.51.100.7 203.0.113.1 51234 8080\r\n")
+            .await
+            .unwrap();
+        write_handshake(&mut client, "", "foo").await;
+        socket_manager_read(
+            socket,
+            CLIENT_ADDR,
+            true,
+            None,
+            &access,
+            service_mgr.clone(),
+        )
+        .await
+        .unwrap();
+
+        let service_mgr_clone = service_mgr.clone();
+        task::spawn(async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await });
+        assert!(matches!(
+            read_message(&mut client).await.unwrap(),
+            Message::Request { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn hands_primary_streams_to_the_session_that_signed_them() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            None,
+            false,
+            None,
+            None,
+            true,
+            receiver,
+        );
+        for secret in ["a", "b"] {
+            let session = SessionConfig {
+                handshake_secret: secret.to_string(),
+                join_token: Some("j0in".to_string()),
+                ..SESSION
+            };
+            assert_eq!(
+                spawn_service_session("foo".to_string(), service_mgr.clone(), session, None).await,
+                Registration::Registered
+            );
+        }
+        let handshake = |secret: &'static str| {
+            let service_mgr = service_mgr.clone();
+            async move {
+                let (mut client, socket) = duplex(1024);
+                write_handshake(&mut client, secret, "foo").await;
+                let result = socket_manager_read(
+                    socket,
+                    CLIENT_ADDR,
+                    false,
+                    None,
+                    &AccessList::default(),
+                    service_mgr,
+                )
+                .await;
+                (client, result)
+            }
+        };
+
+        let (_b, result) = handshake("b").await;
+        result.unwrap();
+        // Its session already has a stream, and it isn't given to the other one
+        let (mut again, result) = handshake("b").await;
+        result.unwrap();
+        assert!(read_message(&mut again).await.is_err());
+        let (_a, result) = handshake("a").await;
+        result.unwrap();
+    }
+
+    async fn write_handshake(
+        client: &mut (impl AsyncWrite + Unpin),
+        secret: &str,
+        service_id: &str,
+    ) {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        let signature = hmac::sign(&key, service_id.as_bytes());
+        protocol::write_frame(client, service_id.as_bytes())
+            .await
+            .unwrap();
+        protocol::write_frame(client, signature.as_ref())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_handshakes_not_signed_with_session_secret() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let session = SessionConfig {
+            handshake_secret: "s3cret".to_string(),
+            ..SESSION
+        };
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), session, None).await,
+            Registration::Registered
+        );
+        let handshake = |secret: &'static str| {
+            let service_mgr = service_mgr.clone();
+            async move {
+                let (mut client, socket) = duplex(1024);
+                write_handshake(&mut client, secret, "foo").await;
+                let result = socket_manager_read(
+                    socket,
+                    CLIENT_ADDR,
+                    false,
+                    None,
+                    &AccessList::default(),
+                    service_mgr,
+                )
+                .await;
+                (client, result)
+            }
+        };
+
+        let (_, result) = handshake("guessed").await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        let (mut client, result) = handshake("s3cret").await;
+        result.unwrap();
+        let service_mgr_clone = service_mgr.clone();
+        task::spawn(async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await });
+        assert!(matches!(
+            read_message(&mut client).await.unwrap(),
+            Message::Request { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn waits_to_accept_proxy_connections_over_the_limit() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown, shutdown_receiver) = watch::channel(false);
+        spawn_socket_manager(
+            service_mgr.clone(),
+            listener,
+            AccessList::default(),
+            false,
+            1,
+            None,
+            shutdown_receiver,
+        )
+        .await;
+
+        // Takes the only slot without ever sending a handshake
+        let silent = TcpStream::connect(addr).await.unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        write_handshake(&mut client, "", "foo").await;
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // Not accepted yet, so the session has no stream and drops requests
+        let (sender, mut receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .body(Body::empty())
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+        assert!(receiver.recv().await.is_none());
+
+        drop(silent);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let service_mgr_clone = service_mgr.clone();
+        task::spawn(async move { forward_request(&service_mgr_clone, "foo.tunnel.test").await });
+        assert!(matches!(
+            read_message(&mut client).await.unwrap(),
+            Message::Request { .. }
+        ));
+    }
+
+    #[test]
+    fn generates_keys_from_configured_alphabet() {
+        let key = KeyConfig {
+            scheme: IdScheme::Phonetic,
+            length: 6,
+            vowels: vec!['a'],
+            consonants: vec!['b'],
+        };
+        let generated = phonetic_key_generator(&key);
+        assert!(generated == "bababa" || generated == "ababab");
+    }
+
+    #[test]
+    fn generates_uuid_and_base32_keys() {
+        let mut key = KeyConfig {
+            scheme: IdScheme::Uuid,
+            length: 26,
+            vowels: vec!['a'],
+            consonants: vec!['b'],
+        };
+        let uuid = generate_service_id(&key);
+        assert!(is_dns_label(&uuid));
+        let groups: Vec<_> = uuid.split('-').map(str::len).collect();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(&uuid[14..15], "4");
+        assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+
+        key.scheme = IdScheme::Base32;
+        let base32 = generate_service_id(&key);
+        assert_eq!(base32.len(), 26);
+        assert!(base32.bytes().all(|c| BASE32_ALPHABET.contains(&c)));
+        assert_ne!(base32, generate_service_id(&key));
+    }
+
+    #[test]
+    fn checks_bearer_token() {
+        let request = |authorization: &str| {
+            Request::builder()
+                .header(hyper::http::header::AUTHORIZATION, authorization)
+                .body(Body::empty())
+                .unwrap()
+        };
+        assert!(is_authorized(&request("Bearer hunter2"), "hunter2"));
+        assert!(!is_authorized(&request("Bearer hunter3"), "hunter2"));
+        assert!(!is_authorized(&request("hunter2"), "hunter2"));
+        assert!(!is_authorized(
+            &Request::builder().body(Body::empty()).unwrap(),
+            "hunter2"
+        ));
+    }
+
+    #[tokio::test]
+    async fn stats_counts_registered_services() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        assert_eq!(
+            spawn_service_session("bar".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::Stats { stats: sender })
+            .await
+            .unwrap();
+        assert_eq!(receiver.await.unwrap().services, 2);
+    }
+
+    #[tokio::test]
+    async fn rejects_services_over_tunnel_limit() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            Some(1),
+            false,
+            None,
+            None,
+            false,
+            receiver,
+        );
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        assert_eq!(
+            spawn_service_session("bar".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Full
+        );
+
+        service_mgr
+            .send(ServiceManagerMessage::UnregisterService {
+                service_id: "foo".to_string(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            spawn_service_session("bar".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+    }
+
+    #[tokio::test]
+    async fn holds_disconnected_services_for_their_reclaim_token() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            None,
+            false,
+            Some(Duration::from_millis(200)),
+            None,
+            false,
+            receiver,
+        );
+        let token = || Some("secret".to_string());
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, token()).await,
+            Registration::Registered
+        );
+        service_mgr
+            .send(ServiceManagerMessage::UnregisterService {
+                service_id: "foo".to_string(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Taken
+        );
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SESSION,
+                Some("guess".to_string())
+            )
+            .await,
+            Registration::Taken
+        );
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, token()).await,
+            Registration::Registered
+        );
+
+        // Once the hold expires anyone can have the subdomain
+        service_mgr
+            .send(ServiceManagerMessage::UnregisterService {
+                service_id: "foo".to_string(),
+            })
+            .await
+            .unwrap();
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_busy_when_queues_are_full() {
+        // A session that never reads its messages, so its queue stays full
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let (session_sender, _session_receiver) = channel(1);
+        let (registered, _) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::RegisterService {
+                service_id: "foo".to_string(),
+                session: 0,
+                sender: session_sender,
+                traffic: Arc::new(Traffic::new(None)),
+                secret: String::new(),
+                fallback: None,
+                reclaim_token: None,
+                join_token: None,
+                registered,
+            })
+            .await
+            .unwrap();
+        let (sender, _receiver) = unbounded_channel();
+        service_mgr
+            .send(ServiceManagerMessage::ForwardRequest {
+                request: Request::builder()
+                    .header(hyper::http::header::HOST, "foo.tunnel.test")
+                    .body(Body::empty())
+                    .unwrap(),
+                response_sender: sender,
+            })
+            .await
+            .unwrap();
+        let response = forward_request(&service_mgr, "foo.tunnel.test").await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Nothing drains this service manager's queue
+        let (service_mgr, _receiver) = channel(1);
+        service_mgr
+            .send(ServiceManagerMessage::Shutdown)
+            .await
+            .unwrap();
+        let response = handle_incoming_request(
+            Request::builder()
+                .header(hyper::http::header::HOST, "foo.tunnel.test")
+                .body(Body::empty())
+                .unwrap(),
+            service_mgr,
+            test_config(None),
+            "192.0.2.7:4321".parse().unwrap(),
+            "http",
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn check_reports_listeners_that_cannot_bind() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = TunnelServer::builder()
+            .http_addr(taken.local_addr().unwrap())
+            .proxy_addr("127.0.0.1:0".parse().unwrap())
+            .build();
+        let checks = server.check();
+        let failed: Vec<&str> = checks
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(checks.len(), 3);
+        assert_eq!(
+            failed,
+            [format!("HTTP listener on {}", taken.local_addr().unwrap())]
+        );
+    }
+
+    #[tokio::test]
+    async fn check_tells_bad_settings_from_io_failures() {
+        let server = TunnelServer::builder()
+            .http_addr("127.0.0.1:0".parse().unwrap())
+            .proxy_addr("127.0.0.1:0".parse().unwrap())
+            .key_alphabet("", "b")
+            .tls("/nonexistent/cert.pem", "/nonexistent/key.pem")
+            .build();
+        let checks = server.check();
+        assert!(matches!(checks[0].1, Err(TunnelError::Parse(_))));
+        assert!(matches!(
+            &checks[1].1,
+            Err(TunnelError::Io(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn check_reports_missing_proxy_tls_certificate() {
+        let server = TunnelServer::builder()
+            .http_addr("127.0.0.1:0".parse().unwrap())
+            .proxy_addr("127.0.0.1:0".parse().unwrap())
+            .proxy_tls("/nonexistent/cert.pem", "/nonexistent/key.pem")
+            .build();
+        let checks = server.check();
+        let failed: Vec<&str> = checks
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(failed, ["proxy TLS certificate"]);
+    }
+
+    #[tokio::test]
+    async fn flooded_tunnel_leaves_queue_room_for_others() {
+        // Nothing drains this service manager's queue, so requests stay in it
+        let (service_mgr, _receiver) = channel(4);
+        let config = Arc::new(Config {
+            fair_share: Arc::new(FairShare::new(4)),
+            ..Arc::try_unwrap(test_config(None)).unwrap()
+        });
+        let send = |host: &str| {
+            let req = Request::builder()
+                .header(hyper::http::header::HOST, host)
+                .body(Body::empty())
+                .unwrap();
+            task::spawn(handle_incoming_request(
+                req,
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            ))
+        };
+
+        let _queued = [send("foo.tunnel.test"), send("foo.tunnel.test")];
+        let flooded = timeout(Duration::from_secs(5), send("foo.tunnel.test"))
+            .await
+            .expect("request over the tunnel's share was never answered")
+            .unwrap()
+            .unwrap();
+        assert_eq!(flooded.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Another tunnel's request gets queued rather than turned away
+        let other = timeout(Duration::from_millis(200), send("bar.tunnel.test")).await;
+        assert!(other.is_err());
+    }
+
+    #[tokio::test]
+    async fn challenges_requests_without_basic_auth() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    basic_auth: BasicAuth::parse("alice:open:sesame"),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        let send = |authorization: Option<&str>| {
+            let mut request =
+                Request::builder().header(hyper::http::header::HOST, "foo.tunnel.test");
+            if let Some(authorization) = authorization {
+                request = request.header(hyper::http::header::AUTHORIZATION, authorization);
+            }
+            let (sender, mut receiver) = unbounded_channel();
+            service_mgr
+                .try_send(ServiceManagerMessage::ForwardRequest {
+                    request: request.body(Body::empty()).unwrap(),
+                    response_sender: sender,
+                })
+                .unwrap();
+            async move { receiver.recv().await.unwrap() }
+        };
+
+        let response = send(None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            response.headers()[hyper::http::header::WWW_AUTHENTICATE],
+            "Basic realm=\"foo\""
+        );
+        let wrong = format!("Basic {}", STANDARD.encode("alice:guess"));
+        assert_eq!(send(Some(&wrong)).await.status(), StatusCode::UNAUTHORIZED);
+
+        let right = format!("Basic {}", STANDARD.encode("alice:open:sesame"));
+        let response = task::spawn(send(Some(&right)));
+        let (id, head) = loop {
+            if let Message::Request { id, data } = read_message(&mut client).await.unwrap() {
+                break (id, data);
+            }
+        };
+        // Only the allowed request reached the client, without the credentials
+        assert!(!String::from_utf8_lossy(&head)
+            .to_lowercase()
+            .contains("authorization"));
+        let head = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+        write_message(&mut client, &Message::Response { id, data: head })
+            .await
+            .unwrap();
+        assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn answers_cors_preflights_for_allowed_origins() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        assert_eq!(
+            spawn_service_session(
+                "foo".to_string(),
+                service_mgr.clone(),
+                SessionConfig {
+                    cors: Some(Cors {
+                        origins: vec!["https://app.test".to_string()],
+                        ..Cors::default()
+                    }),
+                    ..SESSION
+                },
+                None
+            )
+            .await,
+            Registration::Registered
+        );
+
+        let mut client = connect_client(&service_mgr, "foo").await;
+        let send = |method: Method, origin: &str| {
+            let request = Request::builder()
+                .method(method)
+                .header(hyper::http::header::HOST, "foo.tunnel.test")
+                .header(hyper::http::header::ORIGIN, origin)
+                .header(hyper::http::header::ACCESS_CONTROL_REQUEST_METHOD, "PUT");
+            let (sender, mut receiver) = unbounded_channel();
+            service_mgr
+                .try_send(ServiceManagerMessage::ForwardRequest {
+                    request: request.body(Body::empty()).unwrap(),
+                    response_sender: sender,
+                })
+                .unwrap();
+            async move { receiver.recv().await.unwrap() }
+        };
+
+        // Answered without reaching the client
+        let response = send(Method::OPTIONS, "https://app.test").await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[hyper::http::header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.test"
+        );
+        assert_eq!(
+            headers[hyper::http::header::ACCESS_CONTROL_ALLOW_METHODS],
+            cors::DEFAULT_METHODS
+        );
+        assert_eq!(headers[hyper::http::header::VARY], "origin");
+
+        // Other origins are left to the upstream, allowed ones' responses get the
+        // headers added
+        for (method, origin, allowed) in [
+            (Method::OPTIONS, "https://other.test", false),
+            (Method::GET, "https://app.test", true),
+        ] {
+            let response = task::spawn(send(method, origin));
+            let id = loop {
+                if let Message::Request { id, .. } = read_message(&mut client).await.unwrap() {
+                    break id;
+                }
+            };
+            let head = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+            write_message(&mut client, &Message::Response { id, data: head })
+                .await
+                .unwrap();
+            let response = response.await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response
+                    .headers()
+                    .contains_key(hyper::http::header::ACCESS_CONTROL_ALLOW_ORIGIN),
+                allowed
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn round_robins_requests_over_load_balanced_clients() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            None,
+            false,
+            None,
+            None,
+            true,
+            receiver,
+        );
+        let session = SessionConfig {
+            join_token: Some("j0in".to_string()),
+            ..SESSION
+        };
+        let mut clients = vec![];
+        for _ in 0..2 {
+            assert_eq!(
+                spawn_service_session(
+                    "foo".to_string(),
+                    service_mgr.clone(),
+                    session.clone(),
+                    None
+                )
+                .await,
+                Registration::Registered
+            );
+            clients.push(connect_client(&service_mgr, "foo").await);
+        }
+        let (sender, receiver) = oneshot::channel();
+        service_mgr
+            .send(ServiceManagerMessage::ListServices { services: sender })
+            .await
+            .unwrap();
+        assert_eq!(receiver.await.unwrap()[0].clients, 2);
+
+        // Each client gets one of two requests
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let service_mgr = service_mgr.clone();
+            responses.push(task::spawn(async move {
+                forward_request(&service_mgr, "foo.tunnel.test").await
+            }));
+        }
+        for client in &mut clients {
+            let id = timeout(Duration::from_secs(5), async {
+                loop {
+                    if let Message::Request { id, .. } = read_message(client).await.unwrap() {
+                        break id;
+                    }
+                }
+            })
+            .await
+            .expect("client was never sent a request");
+            let head = b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_vec();
+            write_message(client, &Message::Response { id, data: head })
+                .await
+                .unwrap();
+        }
+        for response in responses {
+            assert_eq!(response.await.unwrap().status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_lets_clients_with_the_join_token_into_load_balanced_tunnels() {
+        let (service_mgr, receiver) = channel(1024);
+        start_service_manager(
+            "tunnel.test".to_string(),
+            None,
+            false,
+            None,
+            None,
+            true,
+            receiver,
+        );
+        let mut config = test_config(None);
+        Arc::get_mut(&mut config).unwrap().load_balance = true;
+        let start = |join_token: Option<&str>| {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/start")
+                .header(hyper::http::header::HOST, "tunnel.test")
+                .header("X-Requested-Subdomain", "foo");
+            if let Some(token) = join_token {
+                req = req.header(X_JOIN_TOKEN, token);
+            }
+            handle_incoming_request(
+                req.body(Body::empty()).unwrap(),
+                service_mgr.clone(),
+                config.clone(),
+                "192.0.2.7:4321".parse().unwrap(),
+                "http",
+            )
+        };
+
+        let response = start(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let token = response.headers()[X_JOIN_TOKEN]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = start(None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = start(Some("guessed")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response = start(Some(&token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[X_JOIN_TOKEN], token.as_str());
+    }
+
+    #[tokio::test]
+    async fn streams_service_events_to_admins() {
+        let service_mgr = spawn_service_manager("tunnel.test".to_string()).await;
+        let req = Request::builder()
+            .uri("/admin/events")
+            .header(hyper::http::header::HOST, "tunnel.test")
+            .header(hyper::http::header::AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let response = handle_incoming_request(
+            req,
+            service_mgr.clone(),
+            test_config(Some("secret")),
+            "192.0.2.7:4321".parse().unwrap(),
+            "http",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            response.headers()[hyper::http::header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut body = response.into_body();
+
+        assert_eq!(
+            spawn_service_session("foo".to_string(), service_mgr.clone(), SESSION, None).await,
+            Registration::Registered
+        );
+        service_mgr
+            .send(ServiceManagerMessage::UnregisterService {
+                service_id: "foo".to_string(),
+            })
+            .await
+            .unwrap();
+        for kind in ["registered", "unregistered"] {
+            let chunk = timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("event was never sent")
+                .unwrap()
+                .unwrap();
+            let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+            let data = chunk
+                .lines()
+                .find_map(|line| line.strip_prefix("data: "))
+                .unwrap();
+            let event: serde_json::Value = serde_json::from_str(data).unwrap();
+            assert!(chunk.starts_with(&format!("event: {}\n", kind)));
+            assert_eq!(event["type"], kind);
+            assert_eq!(event["service_id"], "foo");
+            assert!(event["timestamp"].as_u64().unwrap() > 0);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Logger setup for the server
+//!
+//! Log lines may carry structured key-values such as `service_id`. The JSON
+//! format emits them as fields of their own, the pretty format appends them to
+//! the message.
+
+use clap::ValueEnum;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Log, Metadata, Record};
+use serde_json::{Map, Value as Json};
+use std::fmt::Write as _;
+use std::io::Write as _;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// Colored, human readable lines
+    Pretty,
+    /// One JSON object per line
+    Json,
+}
+
+/// Installs the global logger, filtered by `RUST_LOG` like before
+pub fn init(format: LogFormat) {
+    let mut builder = pretty_env_logger::formatted_builder();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    match format {
+        LogFormat::Pretty => {
+            let logger = builder.build();
+            log::set_max_level(logger.filter());
+            log::set_boxed_logger(Box::new(KvLogger(logger))).unwrap();
+        }
+        LogFormat::Json => {
+            builder.format(|buf, record| {
+                let mut line = Map::new();
+                line.insert("level".into(), record.level().as_str().into());
+                line.insert("target".into(), record.target().into());
+                line.insert("msg".into(), record.args().to_string().into());
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                writeln!(buf, "{}", Json::Object(line))
+            });
+            builder.init();
+        }
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Json>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = match value.to_u64() {
+            Some(number) => number.into(),
+            None => value.to_string().into(),
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+// The pretty format only prints the message, so key-values are folded into it
+struct KvLogger<L>(L);
+
+impl<L: Log> Log for KvLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let mut fields = String::new();
+        let _ = record.key_values().visit(&mut PrettyFields(&mut fields));
+        if fields.is_empty() {
+            return self.0.log(record);
+        }
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("{}{}", record.args(), fields))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+struct PrettyFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for PrettyFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
@@ -1,432 +1,430 @@
-use hyper::http::status;
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Method, Request, Response, Server, StatusCode, Version};
-use log::{debug, error, trace, warn};
-use rand::prelude::*;
-use std::cell::RefCell;
-use std::convert::Infallible;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use config_file::Config;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::StatusCode;
+use log::{error, warn};
+use logging::LogFormat;
+use server::{AccessLogFormat, Cidr, IdScheme, TunnelError, TunnelServer};
+use std::fs::{self, OpenOptions};
+use std::io;
 use std::net::SocketAddr;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::{collections::HashMap, io};
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
-use tokio::sync::Mutex;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    task,
-};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    pretty_env_logger::init();
-
-    let proxy_ip = "127.0.0.1:8080";
-    let http_ip = "127.0.0.1:80";
-    let domain = "rachel.test";
-    let service_mgr = spawn_service_manager(domain.to_string()).await;
-    spawn_socket_manager(service_mgr.clone(), proxy_ip.to_string()).await;
-    let thread =
-        spawn_request_manager(http_ip.to_string(), service_mgr.clone(), domain.to_string()).await;
-    thread.await.unwrap();
-    Ok(())
-}
-
-#[derive(Debug)]
-enum ServiceManagerMessage {
-    ForwardRequest {
-        request: Request<Body>,
-        response_sender: UnboundedSender<Response<Body>>,
-    },
-    RegisterService {
-        service_id: String,
-        sender: UnboundedSender<ServiceSessionMessage>,
-    },
-    ForwardPrimaryStream {
-        service_id: String,
-        stream: TcpStream,
-    },
-    UnregisterService {
-        service_id: String,
-    },
-}
+mod config_file;
+mod logging;
 
-#[derive(Debug)]
-enum ServiceSessionMessage {
-    RecvPrimaryStream(TcpStream),
-    RecvRequest(Request<Body>, UnboundedSender<Response<Body>>),
+#[derive(Parser, Debug)]
+#[command(about = "tunnel-ly server", args_override_self = true)]
+struct Args {
+    /// TOML file of settings keyed by flag name, e.g. `domain = "example.com"`,
+    /// flags given on the command line override it and add to its lists
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Address the public HTTP listener binds to, `[::]:80` listens on IPv4 and IPv6
+    #[arg(long, default_value = "127.0.0.1:80")]
+    http_addr: SocketAddr,
+    /// Address the client proxy listener binds to, `[::]:8080` listens on IPv4 and IPv6
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    proxy_addr: SocketAddr,
+    /// Root domain that tunnels are served under
+    #[arg(long, default_value = "rachel.test")]
+    domain: String,
+    /// Seconds to wait for a tunnel's client to start responding before returning 504
+    #[arg(long, default_value_t = 30)]
+    request_timeout: u64,
+    /// Seconds between pings sent to each client over its primary stream
+    #[arg(long, default_value_t = 15)]
+    heartbeat_interval: u64,
+    /// Seconds a client has to answer a ping before its tunnel is closed
+    #[arg(long, default_value_t = 30)]
+    heartbeat_timeout: u64,
+    /// Compress responses with gzip or deflate for clients that accept it
+    #[arg(long)]
+    compress: bool,
+    /// Echo each request's X-Request-Id, generated if it didn't have one, on its response
+    #[arg(long)]
+    request_id_header: bool,
+    /// Bytes buffered on each side of a client's primary stream
+    #[arg(long, default_value_t = 8 * 1024)]
+    buffer_size: usize,
+    /// Skip response heads and body chunks from clients longer than this many bytes
+    /// instead of reading them, failing their request with 502
+    #[arg(long, default_value_t = protocol::DEFAULT_MAX_MESSAGE_LEN)]
+    max_message_bytes: usize,
+    /// Log every message on clients' primary streams at trace level, showing up
+    /// to this many bytes of each head or body chunk. Puts request and response
+    /// contents in the logs, so only use it for debugging
+    #[arg(long, value_name = "MAX_BYTES")]
+    dump_messages: Option<usize>,
+    /// Answer requests with a body larger than this many bytes with 413
+    #[arg(long)]
+    max_body_bytes: Option<u64>,
+    /// Forward at most this many requests at once to each tunnel
+    #[arg(long)]
+    max_in_flight: Option<usize>,
+    /// Seconds a request over --max-in-flight waits for a slot before returning 503
+    #[arg(long, default_value_t = 5)]
+    queue_timeout: u64,
+    /// Bytes per second each tunnel client's request and response bodies are
+    /// held to in each direction
+    #[arg(long)]
+    bandwidth_limit: Option<u64>,
+    /// Seconds a client can go without a request before its tunnel is closed
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+    /// Messages the service manager and each tunnel queue before requests are
+    /// answered with 503, and chunks each response queues before it's aborted
+    #[arg(long, default_value_t = 1024)]
+    channel_capacity: usize,
+    /// How subdomains are generated for clients that don't request one:
+    /// `phonetic`, `uuid`, or `base32` for harder to guess ones
+    #[arg(long, default_value = "phonetic")]
+    id_scheme: IdScheme,
+    /// Length of phonetic and base32 generated subdomains
+    #[arg(long, default_value_t = 10)]
+    key_length: usize,
+    /// Vowels generated subdomains alternate with consonants from
+    #[arg(long, default_value = "aeiou")]
+    key_vowels: String,
+    /// Consonants generated subdomains alternate with vowels from
+    #[arg(long, default_value = "bcdfghjklmnpqrstvwxyz")]
+    key_consonants: String,
+    /// Require clients to send `Authorization: Bearer <token>` to open a tunnel
+    #[arg(long)]
+    auth_token: Option<String>,
+    /// Subdomains clients may not request, comma separated
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "admin,health,metrics,start,status,www"
+    )]
+    reserved_subdomains: Vec<String>,
+    /// Refuse new tunnels with 503 while this many are open
+    #[arg(long)]
+    max_tunnels: Option<usize>,
+    /// Seconds clients are told to wait, in Retry-After, before trying again when
+    /// a new tunnel is refused because the server is full or draining
+    #[arg(long, default_value_t = 30)]
+    start_retry_after: u64,
+    /// Route any subdomain of a tunnel's host, e.g. api.foo.{domain}, to that tunnel
+    #[arg(long)]
+    wildcard_subdomains: bool,
+    /// Hand clients a reclaim token and hold a disconnected tunnel's subdomain
+    /// for it this many seconds, so a reconnecting client gets the same one back
+    #[arg(long)]
+    reclaim_ttl: Option<u64>,
+    /// Seconds after a tunnel closes that requests for it get 503 and the offline
+    /// page, or a redirect to the client's --fallback-url, instead of 404. 0
+    /// answers 404 straight away
+    #[arg(long, default_value_t = 60)]
+    offline_grace: u64,
+    /// Let clients that request a subdomain in use join its tunnel and share its
+    /// requests round-robin, rather than refusing them with 409, as long as they
+    /// send the join token its first client was given
+    #[arg(long)]
+    load_balance: bool,
+    /// Refuse new tunnels with 429 once an IP opens this many in a minute
+    #[arg(long)]
+    start_rate_limit: Option<u32>,
+    /// Read a PROXY protocol header from the load balancer in front of the proxy
+    /// listener at the start of each connection, for the client's real address
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+    /// Connections to the proxy listener still handshaking at once, further ones
+    /// wait to be accepted until one finishes
+    #[arg(long, default_value_t = 1024)]
+    max_proxy_connections: usize,
+    /// Only accept client connections from this network, e.g. 10.0.0.0/8, can be repeated
+    #[arg(long, value_name = "CIDR")]
+    allow_cidr: Vec<Cidr>,
+    /// Drop client connections from this network even if allowed, can be repeated
+    #[arg(long, value_name = "CIDR")]
+    deny_cidr: Vec<Cidr>,
+    /// Header added to every tunneled response, e.g. "Via: tunnel-ly", can be repeated
+    #[arg(long, value_name = "NAME: VALUE", value_parser = parse_header)]
+    response_header: Vec<(HeaderName, HeaderValue)>,
+    /// Replace headers the upstream set with the ones given by --response-header
+    #[arg(long)]
+    force_response_headers: bool,
+    /// Answer CORS preflights from this origin and add Access-Control-Allow-* headers
+    /// to responses for it, `*` allows any origin, can be repeated
+    #[arg(long, value_name = "ORIGIN")]
+    cors: Vec<String>,
+    /// Access-Control-Allow-Methods sent to origins allowed by --cors
+    #[arg(long, default_value = server::DEFAULT_CORS_METHODS, value_parser = parse_header_value)]
+    cors_methods: HeaderValue,
+    /// Access-Control-Allow-Headers sent to origins allowed by --cors
+    #[arg(long, default_value = "*", value_parser = parse_header_value)]
+    cors_headers: HeaderValue,
+    /// Directory of HTML pages named by status, e.g. 404.html, served instead of
+    /// the server's plain text errors, `{{host}}` is replaced with the requested host
+    #[arg(long, value_name = "DIR")]
+    error_pages: Option<PathBuf>,
+    /// HTML file served for `GET /` on the root domain instead of the default info
+    /// page, `{{domain}}` is replaced with the root domain
+    #[arg(long, value_name = "PATH")]
+    landing_page: Option<PathBuf>,
+    /// HTML file served for tunnels that closed within --offline-grace instead of
+    /// the default page, `{{host}}` is replaced with the requested host
+    #[arg(long, value_name = "PATH")]
+    offline_page: Option<PathBuf>,
+    /// File to append an access log line to for every tunneled request, `-` for stdout
+    #[arg(long, value_name = "PATH")]
+    access_log: Option<PathBuf>,
+    /// Format of access log lines, `common` or `combined`
+    #[arg(long, default_value = "combined")]
+    access_log_format: AccessLogFormat,
+    /// Format of log lines written to stderr
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// Seconds to wait for in-flight requests after SIGINT or SIGTERM
+    #[arg(long, default_value_t = 30)]
+    shutdown_grace_period: u64,
+    /// PEM certificate chain to serve HTTPS with, should cover `*.{domain}`
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for the certificate given by --tls-cert
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// PEM certificate chain to accept clients' primary streams over TLS with,
+    /// should cover `{domain}`
+    #[arg(long)]
+    proxy_tls_cert: Option<PathBuf>,
+    /// PEM private key for the certificate given by --proxy-tls-cert
+    #[arg(long)]
+    proxy_tls_key: Option<PathBuf>,
+    /// Check the settings and that both listeners can bind, then exit instead of serving
+    #[arg(long)]
+    check: bool,
 }
 
-async fn spawn_service_manager(domain: String) -> UnboundedSender<ServiceManagerMessage> {
-    debug!("Spawning service manager");
-
-    let (sender, mut receiver) = unbounded_channel();
-    task::spawn(async move {
-        debug!("Service manager started");
-        let mut services: HashMap<String, UnboundedSender<ServiceSessionMessage>> = HashMap::new();
-        loop {
-            let msg = match receiver.recv().await {
-                Some(msg) => msg,
-                None => {
-                    error!("Service manager failed to recv message");
-                    continue;
-                }
-            };
-            trace!("Service manager received message: {:?}", msg);
-            match msg {
-                ServiceManagerMessage::RegisterService { service_id, sender } => {
-                    debug!("Service manager registered service: {}", service_id);
-                    services.insert(service_id, sender);
-                }
-                ServiceManagerMessage::ForwardPrimaryStream { service_id, stream } => {
-                    if let Some(sender) = services.get(&service_id) {
-                        match sender.send(ServiceSessionMessage::RecvPrimaryStream(stream)) {
-                            Ok(_) => {
-                                debug!(
-                                    "Service manager forwarded primary stream to service: {}",
-                                    service_id
-                                );
-                            }
-                            Err(e) => {
-                                warn!("Service manager failed to forward primary stream: {}", e);
-                            }
-                        };
-                    } else {
-                        warn!("Service manager could not find service: {}", service_id);
-                    }
-                }
-                ServiceManagerMessage::ForwardRequest {
-                    request,
-                    response_sender,
-                } => {
-                    let host = match request.headers().get(hyper::http::header::HOST) {
-                        Some(host) => host.to_str(),
-                        None => {
-                            warn!("Service manager could not find host header");
-                            let _ = response_sender.send(
-                                Response::builder()
-                                    .status(StatusCode::BAD_REQUEST)
-                                    .body(Body::from("400 Bad Request"))
-                                    .unwrap(),
-                            );
-                            continue;
-                        }
-                    };
-                    let str_host = match host {
-                        Ok(host) => host,
-                        Err(e) => {
-                            warn!("Service manager could not parse host header: {}", e);
-                            let _ = response_sender.send(
-                                Response::builder()
-                                    .status(StatusCode::BAD_REQUEST)
-                                    .body(Body::from("400 Bad Request"))
-                                    .unwrap(),
-                            );
-                            continue;
-                        }
-                    };
-                    let service_id = match str_host.strip_suffix(&format!(".{}", domain)) {
-                        Some(service_id) => service_id,
-                        None => str_host,
-                    };
+#[tokio::main]
+async fn main() {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = args.config.clone() {
+        if let Err(e) =
+            Config::load(&path).and_then(|config| config.merge_into(&mut args, &matches))
+        {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    }
+    // Checked once the file is merged, either half can come from it
+    for (cert, key, pair) in [
+        (&args.tls_cert, &args.tls_key, "--tls-cert and --tls-key"),
+        (
+            &args.proxy_tls_cert,
+            &args.proxy_tls_key,
+            "--proxy-tls-cert and --proxy-tls-key",
+        ),
+    ] {
+        if cert.is_some() != key.is_some() {
+            Args::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    format!("{} must be given together", pair),
+                )
+                .exit();
+        }
+    }
+    logging::init(args.log_format);
 
-                    if let Some(sender) = services.get(service_id) {
-                        let service_id = service_id.to_string();
-                        match sender.send(ServiceSessionMessage::RecvRequest(
-                            request,
-                            response_sender.clone(),
-                        )) {
-                            Ok(_) => {
-                                debug!(
-                                    "Service manager forwarded request to service: {}",
-                                    service_id
-                                );
-                            }
-                            Err(e) => {
-                                warn!("Service manager failed to forward request: {}", e);
-                                let _ = response_sender.send(
-                                    Response::builder()
-                                        .status(StatusCode::BAD_GATEWAY)
-                                        .body(Body::from("502 Bad Gateway"))
-                                        .unwrap(),
-                                );
-                            }
-                        }
-                    } else {
-                        warn!("Service manager could not find service: {}", service_id);
-                        let _ = response_sender.send(
-                            Response::builder()
-                                .status(StatusCode::NOT_FOUND)
-                                .body(Body::from("404 Service Not Found"))
-                                .unwrap(),
-                        );
-                    }
-                }
+    let mut builder = TunnelServer::builder()
+        .http_addr(args.http_addr)
+        .proxy_addr(args.proxy_addr)
+        .domain(args.domain)
+        .request_timeout(Duration::from_secs(args.request_timeout))
+        .heartbeat_interval(Duration::from_secs(args.heartbeat_interval))
+        .heartbeat_timeout(Duration::from_secs(args.heartbeat_timeout))
+        .compress(args.compress)
+        .request_id_header(args.request_id_header)
+        .buffer_size(args.buffer_size)
+        .max_message_bytes(args.max_message_bytes)
+        .queue_timeout(Duration::from_secs(args.queue_timeout))
+        .channel_capacity(args.channel_capacity)
+        .id_scheme(args.id_scheme)
+        .key_length(args.key_length)
+        .key_alphabet(&args.key_vowels, &args.key_consonants)
+        .wildcard_subdomains(args.wildcard_subdomains)
+        .offline_grace(Duration::from_secs(args.offline_grace))
+        .load_balance(args.load_balance)
+        .start_retry_after(Duration::from_secs(args.start_retry_after))
+        .accept_proxy_protocol(args.accept_proxy_protocol)
+        .max_proxy_connections(args.max_proxy_connections)
+        .reserved_subdomains(args.reserved_subdomains)
+        .force_response_headers(args.force_response_headers)
+        .shutdown_grace_period(Duration::from_secs(args.shutdown_grace_period));
+    if let Some(token) = args.auth_token {
+        builder = builder.auth_token(token);
+    }
+    if let Some(max) = args.max_body_bytes {
+        builder = builder.max_body_bytes(max);
+    }
+    if let Some(max) = args.max_in_flight {
+        builder = builder.max_in_flight(max);
+    }
+    if let Some(max_bytes) = args.dump_messages {
+        builder = builder.dump_messages(max_bytes);
+    }
+    if let Some(secs) = args.idle_timeout {
+        builder = builder.idle_timeout(Duration::from_secs(secs));
+    }
+    if let Some(bytes_per_sec) = args.bandwidth_limit {
+        builder = builder.bandwidth_limit(bytes_per_sec);
+    }
+    if let Some(max_tunnels) = args.max_tunnels {
+        builder = builder.max_tunnels(max_tunnels);
+    }
+    if let Some(ttl) = args.reclaim_ttl {
+        builder = builder.reclaim_ttl(Duration::from_secs(ttl));
+    }
+    if let Some(per_minute) = args.start_rate_limit {
+        builder = builder.start_rate_limit(per_minute);
+    }
+    for cidr in args.allow_cidr {
+        builder = builder.allow_cidr(cidr);
+    }
+    for cidr in args.deny_cidr {
+        builder = builder.deny_cidr(cidr);
+    }
+    for (name, value) in args.response_header {
+        builder = builder.response_header(name, value);
+    }
+    if !args.cors.is_empty() {
+        builder = builder
+            .cors_methods(args.cors_methods)
+            .cors_headers(args.cors_headers);
+        for origin in args.cors {
+            builder = builder.cors_origin(origin);
+        }
+    }
+    if let Some(dir) = args.error_pages {
+        let pages = match load_error_pages(&dir) {
+            Ok(pages) => pages,
+            Err(e) => {
+                error!("Failed to load error pages from {}: {}", dir.display(), e);
+                std::process::exit(1);
             }
+        };
+        for (status, html) in pages {
+            builder = builder.error_page(status, html);
         }
-    });
-    sender
-}
-
-async fn spawn_request_manager(
-    http_ip: String,
-    service_mgr: UnboundedSender<ServiceManagerMessage>,
-    domain: String,
-) -> task::JoinHandle<()> {
-    debug!("Spawning request manager");
-    task::spawn(async move {
-        debug!("Request manager started");
-        let make_service = make_service_fn(move |_conn| {
-            let service_mgr = service_mgr.clone();
-            let domain = domain.clone();
-            async {
-                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                    handle_incoming_request(req, service_mgr.clone(), domain.clone())
-                }))
+    }
+    if let Some(path) = args.landing_page {
+        match fs::read_to_string(&path) {
+            Ok(html) => builder = builder.landing_page(html),
+            Err(e) => {
+                error!("Failed to read landing page {}: {}", path.display(), e);
+                std::process::exit(1);
             }
-        });
-
-        // Then bind and serve...
-        let server = Server::bind(&SocketAddr::from_str(&http_ip).unwrap()).serve(make_service);
-        // And run forever...
-        if let Err(e) = server.await {
-            eprintln!("server error: {}", e);
-            panic!("server error: {}", e);
         }
-    })
-}
-
-async fn handle_incoming_request(
-    req: Request<Body>,
-    service_mgr: UnboundedSender<ServiceManagerMessage>,
-    domain: String,
-) -> Result<Response<Body>, Infallible> {
-    trace!("Request manager received request: {:?}", req);
-    if req.headers().get(hyper::http::header::HOST).unwrap() == &domain {
-        handle_root_request(req, service_mgr, domain).await
-    } else {
-        let (sender, mut receiver) = unbounded_channel();
-        service_mgr
-            .send(ServiceManagerMessage::ForwardRequest {
-                request: req,
-                response_sender: sender,
-            })
-            .unwrap();
-        let response = receiver.recv().await.unwrap();
-        Ok(response)
     }
-}
-
-async fn handle_root_request(
-    req: Request<Body>,
-    service_mgr: UnboundedSender<ServiceManagerMessage>,
-    domain: String,
-) -> Result<Response<Body>, Infallible> {
-    if req.method() == Method::POST && req.uri().path() == "/start" {
-        trace!("Request manager received start request: {:?}", req);
-        let service_id = phonetic_key_generator();
-        spawn_service_session(service_id.clone(), service_mgr).await;
-        trace!("Request manager spawned service session: {}", service_id);
-        Ok(Response::builder().body(Body::from(service_id)).unwrap())
-    } else {
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("Hello World"))
-            .unwrap())
+    if let Some(path) = args.offline_page {
+        match fs::read_to_string(&path) {
+            Ok(html) => builder = builder.offline_page(html),
+            Err(e) => {
+                error!("Failed to read offline page {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        }
     }
-}
-
-async fn spawn_service_session(
-    service_id: String,
-    service_mgr: UnboundedSender<ServiceManagerMessage>,
-) {
-    debug!("Spawning service session: {}", service_id);
-    task::spawn(async move {
-        debug!("Service session started: {}", service_id);
-        let (sender, mut receiver) = unbounded_channel();
-        service_mgr
-            .send(ServiceManagerMessage::RegisterService {
-                service_id: service_id.clone(),
-                sender,
-            })
-            .unwrap();
-        trace!(
-            "Service session registered with service manager: {}",
-            service_id
-        );
-        let mut stream = loop {
-            let msg = receiver.recv().await.unwrap();
-            match msg {
-                ServiceSessionMessage::RecvPrimaryStream(stream) => {
-                    trace!("Service session received primary stream: {}", service_id);
-                    break stream;
+    if let Some(path) = args.access_log {
+        builder = if path.as_os_str() == "-" {
+            builder.access_log(io::stdout(), args.access_log_format)
+        } else {
+            match OpenOptions::new().create(true).append(true).open(&path) {
+                Ok(file) => builder.access_log(file, args.access_log_format),
+                Err(e) => {
+                    error!("Failed to open access log {}: {}", path.display(), e);
+                    std::process::exit(1);
                 }
-                ServiceSessionMessage::RecvRequest(req, response_sender) => {}
             }
         };
-        loop {
-            let msg = match receiver.recv().await {
-                Some(msg) => msg,
-                None => {
-                    continue;
-                }
-            };
-            match msg {
-                ServiceSessionMessage::RecvPrimaryStream(stream) => {}
-                ServiceSessionMessage::RecvRequest(req, response_sender) => 'block: {
-                    trace!(
-                        "Service session received request from socket connection manager: {}",
-                        service_id
-                    );
-                    let http_text = create_http_text(req).await;
-                    stream.write_all(&http_text).await.unwrap();
-                    stream.write_all(&[0x00]).await.unwrap();
-
-                    trace!(
-                        "Service session forwarded request to client: {}",
-                        service_id
-                    );
-
-                    let mut bytes = vec![];
-                    loop {
-                        let mut buf: [u8; 1] = [0; 1];
-                        let bytes_read = stream.read(&mut buf).await.unwrap();
-                        if bytes_read == 0 {
-                            continue;
-                        }
-                        if buf[0] == 0x00 {
-                            // End of message signalled
-                            break;
-                        }
-                        bytes.push(buf[0]);
-                    }
-                    let content_length: usize = String::from_utf8_lossy(&bytes).parse().unwrap();
-                    let buf = &mut vec![0; content_length];
-                    stream.read_exact(buf).await.unwrap();
-                    let mut headers = vec![httparse::EMPTY_HEADER; 64];
-                    let mut resp = httparse::Response::new(&mut headers);
-                    let p = resp.parse(buf).unwrap();
-                    let pre_len = match p {
-                        httparse::Status::Complete(len) => len,
-                        httparse::Status::Partial => {
-                            response_sender
-                                .send(
-                                    Response::builder()
-                                        .status(StatusCode::BAD_REQUEST)
-                                        .body(Body::from("400 Bad Request"))
-                                        .unwrap(),
-                                )
-                                .unwrap();
-                            break 'block;
-                        }
-                    };
-                    let headers = resp
-                        .headers
-                        .iter()
-                        .filter(|h| **h != httparse::EMPTY_HEADER);
-                    let version = match resp.version.unwrap() {
-                        1 => Version::HTTP_11,
-                        _ => Version::HTTP_10,
-                    };
-                    let status = StatusCode::from_u16(resp.code.unwrap()).unwrap();
-                    let body = &buf[pre_len..];
-                    let mut r = Response::builder().version(version).status(status);
-                    for header in headers {
-                        r = r.header(header.name, header.value);
-                    }
-                    trace!(
-                        "Service session received and parsed response from client: {}",
-                        service_id
-                    );
-                    response_sender
-                        .send(r.body(Body::from(body.to_vec())).unwrap())
-                        .unwrap();
+    }
+    if let (Some(cert), Some(key)) = (args.tls_cert, args.tls_key) {
+        builder = builder.tls(cert, key);
+    }
+    if let (Some(cert), Some(key)) = (args.proxy_tls_cert, args.proxy_tls_key) {
+        builder = builder.proxy_tls(cert, key);
+    }
+    let server = builder.build();
+    if args.check {
+        let mut ok = true;
+        for (name, result) in server.check() {
+            match result {
+                Ok(()) => println!("ok: {}", name),
+                Err(e) => {
+                    println!("FAILED: {}: {}", name, e);
+                    ok = false;
                 }
             }
         }
-    });
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    match server.run_until(shutdown_signal()).await {
+        Ok(()) => {}
+        Err(TunnelError::Timeout(_)) => {
+            warn!("Shutdown grace period ended with requests still in flight")
+        }
+        Err(e) => {
+            error!("Server failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
-async fn create_http_text(req: Request<Body>) -> Vec<u8> {
-    let mut text = vec![];
-    text.extend_from_slice(format!("{} {} HTTP/1.1\r\n", req.method(), req.uri()).as_bytes());
-    for (key, value) in req.headers() {
-        text.extend_from_slice(format!("{}: {}\r\n", key, value.to_str().unwrap()).as_bytes());
-    }
-    text.extend_from_slice(&b"\r\n"[..]);
-    let body = req.into_body();
-    let body_bytes = hyper::body::to_bytes(body).await.unwrap();
-    text.extend_from_slice(&body_bytes[..]);
-    text
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| "expected `NAME: VALUE`".to_string())?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| e.to_string())?;
+    Ok((name, parse_header_value(value.trim())?))
 }
 
-async fn spawn_socket_manager(
-    service_mgr: UnboundedSender<ServiceManagerMessage>,
-    proxy_ip: String,
-) {
-    debug!("Spawning socket manager");
-    task::spawn(async move {
-        debug!("Socket manager started");
-        let listener = TcpListener::bind(proxy_ip).await.unwrap();
-        loop {
-            let (socket, _) = match listener.accept().await {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("Socket manager failed to accept connection: {}", e);
-                    continue;
-                }
-            };
-            socket_manager_read(socket, service_mgr.clone()).await;
-        }
-    });
+fn parse_header_value(s: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(s).map_err(|e| e.to_string())
 }
 
-async fn socket_manager_read(
-    mut socket: TcpStream,
-    service_mgr: UnboundedSender<ServiceManagerMessage>,
-) {
-    trace!("Socket manager received new connection");
-    let mut bytes = vec![];
-    loop {
-        let mut buf: [u8; 1] = [0; 1];
-        let bytes_read = socket.read(&mut buf).await.unwrap();
-        if bytes_read == 0 {
-            // Stream ended early
-            break;
+// Reads every `<status>.html` in `dir`, other files are ignored
+fn load_error_pages(dir: &Path) -> io::Result<Vec<(StatusCode, String)>> {
+    let mut pages = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "html") {
+            continue;
         }
-        if buf[0] == 0x00 {
-            // End of message signalled
-            break;
+        let status = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u16>().ok())
+            .and_then(|code| StatusCode::from_u16(code).ok());
+        if let Some(status) = status {
+            pages.push((status, fs::read_to_string(&path)?));
         }
-        bytes.push(buf[0]);
     }
-    let service_id = String::from_utf8_lossy(&bytes).to_string();
-    trace!(
-        "Socket manager forwarding connection to service manager: {}",
-        service_id
-    );
-    service_mgr
-        .send(ServiceManagerMessage::ForwardPrimaryStream {
-            service_id,
-            stream: socket,
-        })
-        .unwrap();
+    Ok(pages)
 }
 
-// Sorry this code is so weird, I ported it from some old JS code
-fn phonetic_key_generator() -> String {
-    let vowels = "aeiou".chars().collect::<Vec<char>>();
-    let cons = "bcdfghjklmnpqrstvwxyz".chars().collect::<Vec<char>>();
-    let mut text = vec![];
-    let mut rng = rand::thread_rng();
-    let start = i32::from(rng.gen::<bool>());
-    let length = 10;
-    for i in 0..length {
-        text.push(if i % 2 == start {
-            cons.choose(&mut rng).unwrap()
-        } else {
-            vowels.choose(&mut rng).unwrap()
-        });
+// Resolves on Ctrl-C, or SIGTERM where there is one
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::process::exit(1);
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
     }
-    text.into_iter().collect::<String>()
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
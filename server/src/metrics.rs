@@ -0,0 +1,141 @@
+//! Counters exposed at `GET /metrics` in the Prometheus text format
+
+use hyper::StatusCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    requests_forwarded: AtomicU64,
+    active_tunnels: AtomicI64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    responses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            requests_forwarded: AtomicU64::new(0),
+            active_tunnels: AtomicI64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            responses: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn request_forwarded(&self) {
+        self.requests_forwarded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_opened(&self) {
+        self.active_tunnels.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_closed(&self) {
+        self.active_tunnels.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Bytes written to a client over its primary stream
+    pub fn bytes_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes read from a client over its primary stream
+    pub fn bytes_received(&self, len: usize) {
+        self.bytes_received.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn response(&self, status: StatusCode) {
+        *self
+            .responses
+            .lock()
+            .unwrap()
+            .entry(status.as_u16())
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            text.push_str(&value);
+        };
+        metric(
+            "tunnelly_requests_forwarded_total",
+            "counter",
+            "Requests forwarded to tunnel clients",
+            sample(
+                "tunnelly_requests_forwarded_total",
+                &self.requests_forwarded,
+            ),
+        );
+        metric(
+            "tunnelly_active_tunnels",
+            "gauge",
+            "Currently registered tunnels",
+            format!(
+                "tunnelly_active_tunnels {}\n",
+                self.active_tunnels.load(Ordering::Relaxed)
+            ),
+        );
+        metric(
+            "tunnelly_bytes_sent_total",
+            "counter",
+            "Bytes sent to tunnel clients",
+            sample("tunnelly_bytes_sent_total", &self.bytes_sent),
+        );
+        metric(
+            "tunnelly_bytes_received_total",
+            "counter",
+            "Bytes received from tunnel clients",
+            sample("tunnelly_bytes_received_total", &self.bytes_received),
+        );
+        let mut responses = String::new();
+        for (code, count) in self.responses.lock().unwrap().iter() {
+            let _ = writeln!(
+                responses,
+                "tunnelly_responses_total{{code=\"{}\"}} {}",
+                code, count
+            );
+        }
+        metric(
+            "tunnelly_responses_total",
+            "counter",
+            "Responses returned for tunneled requests by status code",
+            responses,
+        );
+        text
+    }
+}
+
+fn sample(name: &str, value: &AtomicU64) -> String {
+    format!("{} {}\n", name, value.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_prometheus_text() {
+        let metrics = Metrics::new();
+        metrics.tunnel_opened();
+        metrics.request_forwarded();
+        metrics.bytes_sent(10);
+        metrics.response(StatusCode::OK);
+        metrics.response(StatusCode::OK);
+        metrics.response(StatusCode::BAD_GATEWAY);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE tunnelly_active_tunnels gauge\ntunnelly_active_tunnels 1\n"));
+        assert!(text.contains("tunnelly_requests_forwarded_total 1\n"));
+        assert!(text.contains("tunnelly_bytes_sent_total 10\n"));
+        assert!(text.contains("tunnelly_responses_total{code=\"200\"} 2\n"));
+        assert!(text.contains("tunnelly_responses_total{code=\"502\"} 1\n"));
+    }
+}
@@ -0,0 +1,153 @@
+//! PROXY protocol headers from a load balancer in front of the proxy listener
+//!
+//! A TCP load balancer hides the client's address behind its own, so it can
+//! send a header ahead of the client's bytes saying where the connection really
+//! came from. Version 1 is a line of text and version 2 is binary, both are
+//! read here before anything else on the connection.
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// Longest a version 1 line can be, including its CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the header, returning the client's address or None if the load
+/// balancer opened the connection itself, e.g. for a health check
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least this long
+    let mut start = [0; 12];
+    reader.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(reader, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header isn't text"))?;
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4") | Some("TCP6") => {}
+        _ => return Err(invalid("unsupported PROXY protocol family")),
+    }
+    let (Some(ip), Some(_), Some(port), Some(_), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        return Err(invalid("malformed PROXY protocol header"));
+    };
+    let ip: IpAddr = ip
+        .parse()
+        .map_err(|_| invalid("invalid PROXY protocol address"))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| invalid("invalid PROXY protocol port"))?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let version_command = reader.read_u8().await?;
+    let family = reader.read_u8().await?;
+    let len = reader.read_u16().await? as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    // Addresses are followed by optional extensions, which are skipped
+    let mut addresses = vec![0; len];
+    reader.read_exact(&mut addresses).await?;
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    let source = match family >> 4 {
+        // AF_INET
+        1 if len >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            SocketAddr::new(Ipv4Addr::from(ip).into(), port)
+        }
+        // AF_INET6
+        2 if len >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        }
+        // AF_UNSPEC and AF_UNIX don't name a TCP client
+        0 | 3 => return Ok(None),
+        _ => return Err(invalid("malformed PROXY protocol header")),
+    };
+    Ok(Some(source))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let mut stream: &[u8] = b"PROXY TCP4 198.51.100.7 203.0.113.1 51234 8080\r\nfoo";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("198.51.100.7:51234".parse().unwrap())
+        );
+        // Only the header is consumed
+        assert_eq!(stream, b"foo");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 8080\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("[2001:db8::7]:51234".parse().unwrap())
+        );
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([198, 51, 100, 7, 203, 0, 113, 1]);
+        header.extend(51234u16.to_be_bytes());
+        header.extend(8080u16.to_be_bytes());
+        header.extend(b"foo");
+        let mut stream = &header[..];
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("198.51.100.7:51234".parse().unwrap())
+        );
+        assert_eq!(stream, b"foo");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,82 @@
+//! Per-IP token buckets limiting how often tunnels can be opened
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Idle buckets are swept once the map grows past this many IPs
+const SWEEP_THRESHOLD: usize = 1024;
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_minute: u32,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allows bursts of `per_minute` requests, refilling at that rate
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from `ip`'s bucket, false if it's empty
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let capacity = self.per_minute as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= SWEEP_THRESHOLD {
+            // A bucket that has refilled is the same as no bucket at all
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < capacity);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        let refill = elapsed.as_secs_f64() * self.per_minute as f64 / 60.0;
+        (bucket.tokens + refill).min(self.per_minute as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::new(2);
+        let ip: IpAddr = "192.0.2.7".parse().unwrap();
+        let other: IpAddr = "192.0.2.8".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(ip, now));
+        assert!(!limiter.allow_at(ip, now));
+        assert!(limiter.allow_at(other, now));
+        assert!(!limiter.allow_at(ip, now + Duration::from_secs(29)));
+        assert!(limiter.allow_at(ip, now + Duration::from_secs(31)));
+    }
+}
@@ -0,0 +1,100 @@
+//! Bytes each session has moved, and the optional cap on how fast it moves them
+//!
+//! The cap is a token bucket holding up to a second's worth of bytes, and body
+//! chunks wait for enough tokens before they're passed on. A load balanced
+//! tunnel's sessions each have their own, so its totals are their sum.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+#[derive(Debug)]
+pub struct Traffic {
+    received: AtomicU64,
+    sent: AtomicU64,
+    bucket: Option<Mutex<Bucket>>,
+}
+
+impl Traffic {
+    pub fn new(max_bytes_per_sec: Option<u64>) -> Self {
+        Traffic {
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            bucket: max_bytes_per_sec.map(|rate| Mutex::new(Bucket::new(rate))),
+        }
+    }
+
+    /// Counts bytes read from the tunnel's client
+    pub fn received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes written to the tunnel's client
+    pub fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Bytes received from and sent to the client so far
+    pub fn totals(&self) -> (u64, u64) {
+        (
+            self.received.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Waits until `bytes` more fit under the cap, if there is one
+    pub async fn throttle(&self, bytes: usize) {
+        let Some(bucket) = &self.bucket else {
+            return;
+        };
+        let wait = bucket.lock().unwrap().take(bytes);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    // Takes `bytes` tokens, going into debt if there aren't enough, and returns
+    // how long until the debt is paid off
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refilled).min(self.rate) - bytes as f64;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_holds_a_second_of_bytes() {
+        let mut bucket = Bucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        let wait = bucket.take(500);
+        assert!(wait > Duration::from_millis(450) && wait <= Duration::from_millis(500));
+    }
+}